@@ -11,6 +11,20 @@ fn custom_validator(fn_name: &str) -> String {
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     build_tonic()?;
+    build_method_list()?;
+    Ok(())
+}
+
+/// Write the names of the rpc methods of the service into `methods.rs` for validating method names in the server config
+fn build_method_list() -> Result<(), Box<dyn std::error::Error>> {
+    let proto = std::fs::read_to_string("proto/tapo.proto")?;
+    let methods = proto.lines()
+        .filter_map(|line| line.trim().strip_prefix("rpc "))
+        .filter_map(|rpc| rpc.split('(').next())
+        .map(|method| format!("\"{}\"", method.trim()))
+        .collect::<Vec<String>>();
+    let path = std::path::PathBuf::from(std::env::var("OUT_DIR")?).join("methods.rs");
+    std::fs::write(path, format!("&[{}]", methods.join(", ")))?;
     Ok(())
 }
 
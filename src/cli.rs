@@ -1,7 +1,15 @@
-use clap::{Args, Parser, Subcommand};
+use std::time::Duration;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::path::PathBuf;
+use clap::{Arg, Args, Command, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap::builder::{PossibleValue, TypedValueParser};
+use clap::error::ErrorKind;
+use colorsys::{Hsl, Rgb};
 use spinoff::Spinner;
 use spinoff::spinners::SpinnerFrames;
-use crate::config::Config;
+use crate::config::{ColorFallback, Config, CONFIG_ENV};
+use crate::palette::Region;
 use crate::tapo::server::rpc::{Color, EventType, IntegerValueChange};
 
 #[derive(Parser, Debug)]
@@ -11,10 +19,12 @@ pub struct Cli {
     pub command: Commands,
 
     /// Path to the configuration file which should be used
-    #[arg(long, short, value_parser = parse_config, default_value_t = Config::None, global = true)]
-    pub config: Config,
+    #[arg(long, short, env = CONFIG_ENV, default_value_t = Config::default_path(), global = true)]
+    pub config: String,
 
     /// Address for client to connect to gRPC server [default: config or 127.0.0.1]
+    ///
+    /// A full url (e.g. https://host:1234) overrides the port and secure options
     #[arg(long, short, global = true)]
     pub address: Option<String>,
 
@@ -26,6 +36,22 @@ pub struct Cli {
     #[arg(long, short = 'i', global = true)]
     pub secure: Option<bool>,
 
+    /// Boolean whether to request gzip compressed responses from the gRPC server [default: config or true]
+    #[arg(long, global = true)]
+    pub compression: Option<bool>,
+
+    /// Prefix of the environment variables which override the server connection (e.g. `TAPO_HOST`)
+    #[arg(long, global = true, default_value = "TAPO")]
+    pub env_prefix: String,
+
+    /// Number of times connecting to the gRPC server is retried with an increasing delay
+    #[arg(long, global = true, default_value_t = 0)]
+    pub connect_retries: u32,
+
+    /// How the color of lights whose color can't be derived is printed [default: config or omit]
+    #[arg(long, value_enum, global = true)]
+    pub color_fallback: Option<ColorFallback>,
+
     /// Print result (if any) as json
     #[arg(long, short, default_value_t = false, global = true)]
     pub json: bool
@@ -47,7 +73,16 @@ pub enum UtilCommand {
     /// Create shell completions
     Completions {
         directory: String
-    }
+    },
+    /// Inspect the configuration
+    #[command(subcommand)]
+    Config(ConfigCommand)
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommand {
+    /// Print the resolved configuration path and whether it could be loaded
+    Path
 }
 
 #[derive(Subcommand, Debug)]
@@ -55,88 +90,379 @@ pub enum ServerCommand {
     /// Start the grpc server
     Serve {
         #[arg(value_parser = clap::value_parser!(u16).range(1..=65535))]
-        port: Option<u16>
+        port: Option<u16>,
+
+        /// Exit with an error instead of warning about problems in the config (e.g. duplicate device addresses)
+        #[arg(long, default_value_t = false)]
+        strict: bool
+    },
+    /// Start a read-only grpc server which mirrors the devices and states of another server
+    Mirror {
+        /// Url of the server which is mirrored (e.g. http://10.10.10.10:19191)
+        upstream: String,
+
+        #[arg(value_parser = clap::value_parser!(u16).range(1..=65535))]
+        port: Option<u16>,
+
+        /// Number of the last events which are kept for replaying them to new subscribers
+        #[arg(long, default_value_t = 0)]
+        event_history: usize
     },
 }
 
 #[derive(Subcommand, Debug)]
 pub enum ClientCommand {
     /// List all registered devices
-    Devices,
+    #[command(alias = "ls")]
+    Devices {
+        /// Print every device with all its details instead of a compact table
+        #[arg(long, short, default_value_t = false)]
+        long: bool
+    },
     /// Subscribe to device events
     Events {
         /// Event types to subscribe to
         /// When nothing specified all events are subscribed
-        types: Vec<EventType>
+        types: Vec<EventType>,
+
+        /// Exit with an error instead of skipping events which can't be decoded
+        #[arg(long, default_value_t = false)]
+        strict_json: bool,
+
+        /// Exit after receiving the given number of events. Zero keeps the subscription open forever
+        #[arg(long, short = 'C', default_value_t = 0)]
+        count: u32,
+
+        /// Receive up to the given number of the last events recorded by the server before the live events
+        #[arg(long, default_value_t = 0)]
+        replay: u32
+    },
+    /// Apply the device state changes of an event log recorded with `events --json` to the devices
+    Replay {
+        /// Event log with one event per line as printed by `events --json`
+        file: PathBuf,
+
+        /// Factor by which the replay is faster than the recording (e.g. 2 for double speed)
+        #[arg(long, default_value_t = 1.0, value_parser = parse_speed)]
+        speed: f64
+    },
+    /// Write the current state of the devices together with a timestamp to a file
+    ExportState {
+        /// File to which the device states are written as JSON
+        file: PathBuf,
+
+        /// Only export the given devices (comma-separated). Defaults to all registered devices
+        #[arg(long, short, value_delimiter = ',')]
+        devices: Vec<String>,
+
+        /// Read the states from the devices instead of the cache of the server
+        #[arg(long, short = 'f', default_value_t = false)]
+        fresh: bool,
+    },
+    /// Apply the device states of a file written by `export-state` to the devices
+    ImportState {
+        /// File with the device states as written by `export-state`
+        file: PathBuf,
+
+        /// Only import the given devices (comma-separated). Defaults to all devices of the file
+        #[arg(long, short, value_delimiter = ',')]
+        devices: Vec<String>,
     },
     /// Update properties of a device
+    #[command(alias = "s")]
     Set {
-        /// Device which should be updated
-        device: String,
+        /// Devices which should be updated. Defaults to the `default_device` of the client config
+        devices: Vec<String>,
 
-        /// Brightness value between 1 and 100
-        #[arg(value_parser = parse_100_value, allow_negative_numbers = true, long, short)]
+        /// File with additional devices, one name per line. Lines starting with `#` are comments
+        #[arg(long, value_name = "FILE")]
+        device_file: Option<PathBuf>,
+
+        /// Brightness value between 1 and 100 or one of the named levels dim (10), medium (40), bright (70) and max (100)
+        #[arg(value_parser = parse_brightness_value, allow_negative_numbers = true, long, short)]
         brightness: Option<IntegerValueChange>,
 
         #[command(flatten)]
         hue_saturation: HueSaturation,
 
-        /// Color temperature in kelvin between 2500 and 6500
-        #[arg(value_parser = parse_kelvin_value, allow_negative_numbers = true, long, short)]
+        /// Color temperature in kelvin between 2500 and 6500 (or in mireds between 154 and 400 with `--mireds`)
+        #[arg(value_parser = parse_temperature_value, allow_negative_numbers = true, long, short)]
         temperature: Option<IntegerValueChange>,
 
         /// Use predefined google home color
-        #[arg(long, short = 'o', value_enum)]
+        #[arg(long, short = 'o', value_parser = ColorValueParser)]
         color: Option<Color>,
 
+        /// Use the dominant color of an image (png, jpeg, gif, webp or bmp)
+        #[arg(long, conflicts_with_all = ["color", "temperature", "hue", "saturation"])]
+        from_image: Option<PathBuf>,
+
+        /// Only use the region of the image given as `x,y,width,height` in pixels
+        #[arg(long, value_parser = parse_region, requires = "from_image")]
+        region: Option<Region>,
+
         /// Turn device on or off
         #[arg(long, short)]
         power: Option<bool>,
+
+        /// Restore the previous state should any of the updates fail
+        #[arg(long, default_value_t = false)]
+        atomic: bool,
+
+        /// Only print the computed state without applying it to the device
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+
+        /// Print an explanation of how the new state was computed
+        #[arg(long, default_value_t = false)]
+        explain: bool,
+
+        /// Only change the properties when the device is currently turned on
+        #[arg(long, default_value_t = false)]
+        if_on: bool,
+
+        /// Read the state from the device after the update instead of printing the optimistically computed state
+        #[arg(long, alias = "no-optimistic", default_value_t = false, conflicts_with = "dry_run")]
+        confirm: bool,
+
+        /// Use mireds instead of kelvin for the color temperature
+        #[arg(long, default_value_t = false)]
+        mireds: bool,
     },
     /// Print information about a device
+    #[command(alias = "i")]
     Info {
-        /// Device for which the info should be fetched
+        /// Device for which the info should be fetched. Defaults to the `default_device` of the client config
+        device: Option<String>,
+
+        /// Print the color temperature in mireds instead of kelvin
+        #[arg(long, default_value_t = false)]
+        mireds: bool,
+
+        /// Read the info from the device and update the cached state of the server
+        #[arg(long, short = 'f', default_value_t = false)]
+        fresh: bool,
+
+        /// Format in which the info is printed. `env` prints `TAPO_<KEY>=<value>` lines for `eval`
+        #[arg(long, value_enum, default_value_t = InfoFormat::Text)]
+        format: InfoFormat,
+    },
+    /// Apply a color gradient across multiple devices in the given order
+    Gradient {
+        /// Devices which should be set from the start to the end of the gradient
+        #[arg(required = true)]
+        devices: Vec<String>,
+
+        /// Hex code of the color at the start of the gradient (e.g. '#ff0000')
+        #[arg(long, value_parser = parse_hex_color)]
+        from: Hsl,
+
+        /// Hex code of the color at the end of the gradient (e.g. '#0000ff')
+        #[arg(long, value_parser = parse_hex_color)]
+        to: Hsl,
+
+        /// Time after which the update of a single device fails (e.g. 5s). Plain numbers are minutes
+        #[arg(long, value_parser = parse_duration_value)]
+        timeout_per_device: Option<Duration>,
+
+        /// Maximum number of devices which are updated at once
+        #[arg(long, short = 'P', default_value_t = 8, value_parser = clap::value_parser!(u16).range(1..))]
+        parallel: u16
+    },
+    /// Print information about multiple devices at once
+    InfoBatch {
+        /// Devices for which the info should be fetched
+        #[arg(required = true)]
+        devices: Vec<String>,
+
+        /// Read the infos from the devices and update the cached states of the server
+        #[arg(long, short = 'f', default_value_t = false)]
+        fresh: bool,
+    },
+    /// Print session, reachability, state and today's usage of a device at once
+    Status {
+        /// Device for which the status should be fetched
         device: String,
     },
+    /// Check the session and reachability of all devices concurrently and exit non-zero if any check fails
+    Doctor {
+        /// Time after which the check of a single device fails (e.g. 5s). Plain numbers are minutes
+        #[arg(long, value_parser = parse_duration_value)]
+        timeout_per_device: Option<Duration>,
+
+        /// Maximum number of devices which are checked at once
+        #[arg(long, short = 'P', default_value_t = 8, value_parser = clap::value_parser!(u16).range(1..))]
+        parallel: u16
+    },
+    /// Print the latest temperature and humidity reading of a sensor which is paired with a hub
+    Sensor {
+        /// Hub with which the sensor is paired
+        device: String,
+
+        /// Device id or nickname of the sensor
+        child: String,
+    },
+    /// Change the target temperature and frost protection of a radiator valve
+    SetTemperature {
+        /// Radiator valve which should be updated
+        device: String,
+
+        /// Target temperature in degrees celsius within the control range of the valve
+        #[arg(required_unless_present = "frost_protection")]
+        celsius: Option<u32>,
+
+        /// Enable or disable the frost protection
+        #[arg(long)]
+        frost_protection: Option<bool>,
+    },
     /// Print usage information about a device
     Usage {
         /// Device to get the usage for
         device: String,
+
+        /// Print a summary of today's usage with the estimated cost if a price is configured
+        #[arg(long, short, default_value_t = false)]
+        summary: bool,
     },
-    /// Turn device on
+    /// Turn devices on
     On {
-        /// Device which should be turned on
-        device: String,
+        /// Devices which should be turned on. Defaults to the `default_device` of the client config
+        devices: Vec<String>,
+
+        /// File with additional devices, one name per line. Lines starting with `#` are comments
+        #[arg(long, value_name = "FILE")]
+        device_file: Option<PathBuf>
+    },
+    /// Turn device off when it's on and on otherwise
+    Toggle {
+        /// Device which should be toggled. Defaults to the `default_device` of the client config
+        device: Option<String>,
     },
-    /// Turn device off
+    /// Turn devices off
     Off {
+        /// Devices which should be turned off. Defaults to the `default_device` of the client config
+        devices: Vec<String>,
+
+        /// File with additional devices, one name per line. Lines starting with `#` are comments
+        #[arg(long, value_name = "FILE")]
+        device_file: Option<PathBuf>,
+
+        /// Turn the device off immediately even if it has a configured fade
+        #[arg(long, default_value_t = false)]
+        instant: bool,
+    },
+    /// Turn device off after a delay
+    Sleep {
         /// Device which should be turned off
         device: String,
+
+        /// Delay after which the device is turned off (e.g. 90s, 30m, 1h). Plain numbers are minutes
+        #[arg(value_parser = parse_duration_value)]
+        duration: Duration
     },
     /// Reset a device to factory defaults
     Reset {
         /// Device which should be reset
+        device: String,
+
+        /// Let the device blink and ask for a confirmation before resetting it
+        #[arg(long, short = 'b')]
+        blink: bool
+    },
+    /// Reboot a device without changing its settings
+    Reboot {
+        /// Device which should be rebooted
+        device: String
+    },
+    /// Cancel the long-running operation of a device
+    Cancel {
+        /// Device on which the operation should be cancelled
+        device: String
+    },
+    /// Measure the response latency of a device by sending multiple read-only info requests
+    Bench {
+        /// Device which should be benchmarked
+        device: String,
+
+        /// Number of sequential info requests
+        #[arg(long, short = 'C', default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+        count: u32
+    },
+    /// Print the most recent commands which changed a device
+    Audit {
+        /// Device for which the audit log should be printed
+        device: String
+    },
+    /// Print the request counts, errors and latencies of a device
+    Stats {
+        /// Device for which the request metrics should be printed
+        device: String
+    },
+    /// Tail the log records of the server
+    Logs,
+    /// Print the version and the uptime of the server
+    Version,
+    /// Print the firmware and hardware version of a device
+    Firmware {
+        /// Device for which the firmware should be checked
         device: String
     }
 }
 
+impl ClientCommand {
+    /// Get the device of the commands which fall back to the default device when none is given
+    pub fn optional_device_mut(&mut self) -> Option<&mut Option<String>> {
+        match self {
+            ClientCommand::Info { device, .. } | ClientCommand::Toggle { device } => Some(device),
+            _ => None
+        }
+    }
+
+    /// Get the devices of the commands which fall back to the default device when none are given
+    pub fn optional_devices_mut(&mut self) -> Option<&mut Vec<String>> {
+        match self {
+            ClientCommand::Set { devices, .. } | ClientCommand::On { devices, .. } | ClientCommand::Off { devices, .. } => Some(devices),
+            _ => None
+        }
+    }
+
+    /// Get the device file and the devices to which its names are added for the commands with a `--device-file`
+    pub fn device_file_mut(&mut self) -> Option<(&mut Option<PathBuf>, &mut Vec<String>)> {
+        match self {
+            ClientCommand::Set { devices, device_file, .. }
+            | ClientCommand::On { devices, device_file }
+            | ClientCommand::Off { devices, device_file, .. } => Some((device_file, devices)),
+            _ => None
+        }
+    }
+}
+
+/// Output format of the device info
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InfoFormat {
+    /// Human-readable text
+    Text,
+    /// Shell variable assignments
+    Env
+}
+
 #[derive(Args, Clone, Debug)]
 #[group(multiple = true, requires_all = ["hue", "saturation"])]
 pub struct HueSaturation {
-    /// Hue value between 1 and 360
+    /// Hue value between 0 and 360
     #[arg(value_parser = parse_360_value, long, short = 'u', allow_negative_numbers = true)]
     pub hue: Option<IntegerValueChange>,
 
-    /// Saturation value between 1 and 100
-    #[arg(value_parser = parse_100_value, long, short, allow_negative_numbers = true)]
+    /// Saturation value between 0 and 100
+    #[arg(value_parser = parse_saturation_value, long, short, allow_negative_numbers = true)]
     pub saturation: Option<IntegerValueChange>,
 }
 
 fn parse_360_value(s: &str) -> Result<IntegerValueChange, String> {
     let int = s.parse().map_err(|_| format!("'{s}' is not a valid integer"))?;
     let relative = s.starts_with('+') || s.starts_with('-');
-    if !relative && !(1..=360).contains(&int) {
-        Err(format!("'{int}' is not in range 1 to 360"))?;
+    if !relative && !(0..=360).contains(&int) {
+        Err(format!("'{int}' is not in range 0 to 360"))?;
     }
     Ok(IntegerValueChange {
         absolute: !relative,
@@ -156,20 +482,263 @@ fn parse_100_value(s: &str) -> Result<IntegerValueChange, String> {
     })
 }
 
-fn parse_kelvin_value(s: &str) -> Result<IntegerValueChange, String> {
+/// Parse an image region given as `x,y,width,height`
+fn parse_region(s: &str) -> Result<Region, String> {
+    let values = s.split(',')
+        .map(|value| value.trim().parse::<u32>().map_err(|_| format!("'{value}' is not a valid integer")))
+        .collect::<Result<Vec<_>, _>>()?;
+    match values[..] {
+        [_, _, 0, _] | [_, _, _, 0] => Err(String::from("The width and height of the region have to be greater than 0")),
+        [x, y, width, height] => Ok(Region { x, y, width, height }),
+        _ => Err(format!("'{s}' is not a region in the format x,y,width,height"))
+    }
+}
+
+/// Named brightness levels which can be used instead of a percentage
+const BRIGHTNESS_LEVELS: [(&str, i32); 4] = [("dim", 10), ("medium", 40), ("bright", 70), ("max", 100)];
+
+/// Parse a brightness value which is either a named level or a percentage
+fn parse_brightness_value(s: &str) -> Result<IntegerValueChange, String> {
+    match BRIGHTNESS_LEVELS.iter().find(|(name, _)| name.eq_ignore_ascii_case(s.trim())) {
+        Some((_, value)) => Ok(IntegerValueChange { absolute: true, value: *value }),
+        None => parse_100_value(s)
+    }
+}
+
+/// Parse a saturation value which unlike other percentages can be zero
+fn parse_saturation_value(s: &str) -> Result<IntegerValueChange, String> {
     let int = s.parse().map_err(|_| format!("'{s}' is not a valid integer"))?;
     let relative = s.starts_with('+') || s.starts_with('-');
-    if !relative && !(2500..=6500).contains(&int) {
-        Err(format!("'{int}' is not in range 2500 to 6500"))?;
+    if !relative && !(0..=100).contains(&int) {
+        Err(format!("'{int}' is not in range 0 to 100"))?;
+    }
+    Ok(IntegerValueChange {
+        absolute: !relative,
+        value: int
+    })
+}
+
+fn parse_speed(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(speed) if speed.is_finite() && speed > 0f64 => Ok(speed),
+        Ok(_) => Err(format!("'{s}' is not a positive number")),
+        Err(_) => Err(format!("'{s}' is not a valid number"))
+    }
+}
+
+/// Parse a color given as hex code with or without a leading '#'
+fn parse_hex_color(s: &str) -> Result<Hsl, String> {
+    Rgb::from_hex_str(s.trim()).map(Hsl::from).map_err(|_| format!("'{s}' is not a valid hex color"))
+}
+
+/// Value parser for colors which ignores the case, hyphens and underscores of the input
+///
+/// Unlike the default value enum parser it suggests the closest colors on a miss
+#[derive(Clone)]
+struct ColorValueParser;
+
+impl TypedValueParser for ColorValueParser {
+    type Value = Color;
+
+    fn parse_ref(&self, cmd: &Command, _arg: Option<&Arg>, value: &OsStr) -> Result<Self::Value, clap::Error> {
+        let Some(value) = value.to_str() else {
+            return Err(clap::Error::new(ErrorKind::InvalidUtf8).with_cmd(cmd));
+        };
+        parse_color_value(value).map_err(|err| cmd.clone().error(ErrorKind::InvalidValue, err))
+    }
+
+    fn possible_values(&self) -> Option<Box<dyn Iterator<Item = PossibleValue> + '_>> {
+        Some(Box::new(Color::value_variants().iter().filter_map(ValueEnum::to_possible_value)))
+    }
+}
+
+fn parse_color_value(s: &str) -> Result<Color, String> {
+    let normalize = |s: &str| s.chars().filter(|c| !matches!(c, '-' | '_' | ' ')).collect::<String>().to_lowercase();
+    let input = normalize(s);
+
+    let colors = Color::value_variants().iter()
+        .filter_map(|color| color.to_possible_value().map(|value| (*color, value.get_name().to_string())))
+        .collect::<Vec<_>>();
+
+    if let Some((color, _)) = colors.iter().find(|(_, name)| normalize(name) == input) {
+        return Ok(*color);
+    }
+
+    // colors containing the input are suggested before colors which are only spelled similarly
+    let mut suggestions = colors.iter()
+        .map(|(_, name)| (!normalize(name).contains(&input), edit_distance(&normalize(name), &input), name))
+        .filter(|(not_contained, distance, _)| !not_contained || *distance <= 3)
+        .collect::<Vec<_>>();
+    suggestions.sort();
+
+    if suggestions.is_empty() {
+        Err(format!("'{s}' is not a valid color"))
+    } else {
+        let names = suggestions.iter().take(3).map(|(_, _, name)| name.as_str()).collect::<Vec<_>>().join(", ");
+        Err(format!("'{s}' is not a valid color. Did you mean: {names}?"))
+    }
+}
+
+/// Get the levenshtein distance between two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
     }
+    previous[b.len()]
+}
+
+/// Parse a temperature value without checking its range since the unit depends on the `--mireds` flag
+fn parse_temperature_value(s: &str) -> Result<IntegerValueChange, String> {
+    let int = s.parse().map_err(|_| format!("'{s}' is not a valid integer"))?;
+    let relative = s.starts_with('+') || s.starts_with('-');
     Ok(IntegerValueChange {
         absolute: !relative,
         value: int
     })
 }
 
-fn parse_config(s: &str) -> Result<Config, String> {
-    Ok(Config::new(Some(s.to_string())))
+/// Convert a temperature change to kelvin and check whether it's in the supported range
+///
+/// Relative changes are only supported in kelvin since a relative change in mireds depends on the current temperature
+pub fn temperature_in_kelvin(change: IntegerValueChange, mireds: bool) -> Result<IntegerValueChange, String> {
+    if !mireds {
+        if change.absolute && !(2500..=6500).contains(&change.value) {
+            Err(format!("'{}' is not in range 2500 to 6500 kelvin (154 to 400 mireds)", change.value))?;
+        }
+        return Ok(change);
+    }
+
+    if !change.absolute {
+        Err(String::from("Relative temperature changes are only supported in kelvin"))?;
+    }
+    if !(154..=400).contains(&change.value) {
+        Err(format!("'{}' is not in range 154 to 400 mireds (2500 to 6500 kelvin)", change.value))?;
+    }
+    // the conversion can slightly overshoot the kelvin range at the range bounds
+    let kelvin = mireds_to_kelvin(change.value as u32).clamp(2500, 6500);
+    Ok(IntegerValueChange { absolute: true, value: kelvin as i32 })
+}
+
+/// Get the device names of a device file with one name per line
+///
+/// Blank lines and lines starting with `#` are skipped
+pub fn parse_device_list(content: &str) -> Vec<String> {
+    content.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
+/// Convert a color temperature in mireds to kelvin
+pub fn mireds_to_kelvin(mireds: u32) -> u32 {
+    (1_000_000 + mireds / 2) / mireds
+}
+
+/// Convert a color temperature in kelvin to mireds
+pub fn kelvin_to_mireds(kelvin: u32) -> u32 {
+    (1_000_000 + kelvin / 2) / kelvin
+}
+
+fn parse_duration_value(s: &str) -> Result<Duration, String> {
+    let (value, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => s.split_at(index),
+        None => (s, "m")
+    };
+    let invalid = || format!("'{s}' is not a valid duration");
+    let value: u64 = value.parse().map_err(|_| invalid())?;
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        _ => Err(format!("'{unit}' is not a valid duration unit. Use s, m or h"))?
+    };
+    let seconds = value.checked_mul(multiplier).ok_or_else(invalid)?;
+    if seconds == 0 {
+        Err(String::from("Duration has to be greater than zero"))?;
+    }
+    Ok(Duration::from_secs(seconds))
+}
+
+/// Expand a user-defined alias in the command-line arguments
+///
+/// Only the first argument which isn't a global option (or the value of one) is considered. It's
+/// replaced with the whitespace-separated words of its expansion unless it's already a known command
+pub fn expand_aliases(args: Vec<String>, aliases: &HashMap<String, String>) -> Vec<String> {
+    if aliases.is_empty() {
+        return args;
+    }
+
+    let command = Cli::command();
+    let Some(index) = command_index(&command, &args) else { return args };
+    let name = &args[index];
+    if command.find_subcommand(name).is_some() {
+        return args;
+    }
+
+    match aliases.get(name) {
+        Some(expansion) => {
+            let mut expanded = args[..index].to_vec();
+            expanded.extend(expansion.split_whitespace().map(String::from));
+            expanded.extend_from_slice(&args[index + 1..]);
+            expanded
+        }
+        None => args
+    }
+}
+
+/// Get the path of the configuration file from the arguments before they're parsed by clap
+///
+/// Falls back to the environment variable and the default path like the `--config` argument does
+pub fn config_path_from_args(args: &[String]) -> String {
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        if arg == "--" { break; }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return path.to_string();
+        }
+        if arg == "--config" || arg == "-c" {
+            if let Some(path) = iter.next() {
+                return path.clone();
+            }
+        }
+    }
+    std::env::var(CONFIG_ENV).unwrap_or(Config::default_path())
+}
+
+/// Find the index of the command in the arguments by skipping all options and their values
+fn command_index(command: &Command, args: &[String]) -> Option<usize> {
+    let mut index = 1;
+    while index < args.len() {
+        let arg = &args[index];
+        let option = if let Some(long) = arg.strip_prefix("--") {
+            // the value of an option in the form of `--option=value` is part of the same argument
+            if long.is_empty() || long.contains('=') { None }
+            else { command.get_arguments().find(|a| a.get_long() == Some(long)) }
+        } else if let Some(short) = arg.strip_prefix('-') {
+            let mut chars = short.chars();
+            match (chars.next(), chars.next()) {
+                (Some(short), None) => command.get_arguments().find(|a| a.get_short() == Some(short)),
+                // combined short flags or a short option with an attached value
+                (Some(_), Some(_)) => None,
+                (None, _) => return Some(index)
+            }
+        } else {
+            return Some(index);
+        };
+
+        if arg == "--" { return None; }
+        let takes_value = option.is_some_and(|option| option.get_action().takes_values());
+        index += if takes_value { 2 } else { 1 };
+    }
+    None
 }
 
 pub trait SpinnerOpt<'a> {
@@ -178,6 +747,8 @@ pub trait SpinnerOpt<'a> {
     fn fail(&mut self, message: impl Into<&'a str>);
 
     fn update(&mut self, spinner_type: SpinnerFrames, message: impl Into<&'a str>);
+
+    fn clear(&mut self);
 }
 
 impl<'a> SpinnerOpt<'a> for Option<Spinner> {
@@ -198,4 +769,10 @@ impl<'a> SpinnerOpt<'a> for Option<Spinner> {
             spinner.update(spinner_type, message.into().to_string(), None)
         }
     }
+
+    fn clear(&mut self) {
+        if let Some(spinner) = self {
+            spinner.clear()
+        }
+    }
 }
\ No newline at end of file
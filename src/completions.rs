@@ -5,13 +5,26 @@ use clap_complete::{generate, Shell};
 
 use crate::{cli::Cli, tapo::server::rpc::Device};
 
-const DEVICE_COMPLETION_COMMANDS: [&str; 6] = [
+const DEVICE_COMPLETION_COMMANDS: [&str; 19] = [
     "set",
+    "gradient",
     "info",
+    "info-batch",
     "usage",
+    "sensor",
+    "set-temperature",
+    "status",
     "on",
+    "toggle",
     "off",
-    "reset"
+    "sleep",
+    "reset",
+    "reboot",
+    "cancel",
+    "audit",
+    "stats",
+    "bench",
+    "firmware"
 ];
 
 /// Save device names in cache so that shell completions can use them
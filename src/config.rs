@@ -1,31 +1,73 @@
 use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::time::{SystemTime, UNIX_EPOCH};
 use anyhow::Context;
+use clap::ValueEnum;
 use enum_stringify::EnumStringify;
 use log::{debug, error};
 use serde::Deserialize;
 
 const CONFIG_PATH: &str = "tapoctl/config.toml";
+pub const CONFIG_ENV: &str = "TAPO_CONFIG";
 
 #[derive(Deserialize, Clone, Debug)]
 #[serde(untagged)]
 pub enum Config {
-    Server(ServerConfig),
+    Server(Box<ServerConfig>),
     Client(ClientConfig),
     None
 }
 
-impl ToString for Config {
-    fn to_string(&self) -> String {
-        // we return the CONFIG_PATH here since the `ToString` trait is used by
-        // clap for the default preview and we parse the config path directly
-        // to a config
-        dirs::config_dir().unwrap_or_default().join(CONFIG_PATH).to_str().unwrap_or_default().to_string()
+/// Format in which the configuration file is written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Toml,
+    Json
+}
+
+impl ConfigFormat {
+    /// Detect the format by the file extension. Files without a `.json` extension are parsed as toml
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some(extension) if extension.eq_ignore_ascii_case("json") => ConfigFormat::Json,
+            _ => ConfigFormat::Toml
+        }
+    }
+
+    /// Get a human-readable name of the format
+    pub fn name(&self) -> &'static str {
+        match self {
+            ConfigFormat::Toml => "TOML",
+            ConfigFormat::Json => "JSON"
+        }
+    }
+
+    fn parse(&self, content: &str) -> anyhow::Result<Config> {
+        match self {
+            ConfigFormat::Toml => Ok(toml::from_str(content)?),
+            ConfigFormat::Json => Ok(serde_json::from_str(content)?)
+        }
     }
 }
 
+/// Resolved location of the configuration file together with the outcome of loading it
+pub struct ConfigResolution {
+    pub path: PathBuf,
+    pub result: Result<Config, ConfigError>
+}
+
+#[derive(Debug, Clone)]
+pub enum ConfigError {
+    /// The file at the resolved path couldn't be read
+    Missing(String),
+    /// The file isn't valid UTF-8
+    InvalidUtf8,
+    /// The file doesn't match the server or client config definition
+    Invalid(String)
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct ClientConfig {
     #[serde(default = "default_address")]
@@ -33,7 +75,32 @@ pub struct ClientConfig {
     #[serde(default = "default_port")]
     pub port: u16,
     #[serde(default)]
-    pub secure: bool
+    pub secure: bool,
+    #[serde(default = "default_compression")]
+    pub compression: bool,
+    /// User-defined command aliases which are expanded before the arguments are parsed
+    #[serde(default)]
+    pub aliases: HashMap<String, String>,
+    /// Price per kWh which is used for estimating the cost of the power usage
+    #[serde(default)]
+    pub price_per_kwh: Option<f64>,
+    /// Device which is used by the commands which are called without a device
+    #[serde(default)]
+    pub default_device: Option<String>,
+    /// How the color of lights whose color can't be derived is printed
+    #[serde(default)]
+    pub color_fallback: ColorFallback
+}
+
+/// Rendering of the color line for lights whose color can't be derived (e.g. an unknown color temperature)
+#[derive(Deserialize, ValueEnum, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorFallback {
+    /// Omit the color line
+    #[default]
+    Omit,
+    /// Print a neutral placeholder swatch with a note
+    Placeholder
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -43,7 +110,65 @@ pub struct ServerConfig {
     #[serde(default = "default_port")]
     pub port: u16,
     #[serde(default = "default_timeout")]
-    pub timeout: u32
+    pub timeout: u32,
+    #[serde(default = "default_compression")]
+    pub compression: bool,
+    /// Interval in seconds in which the current power draw of energy-capable devices is broadcast
+    #[serde(default)]
+    pub energy_interval: Option<u64>,
+    /// Path to a file in which the cached device states are persisted across restarts
+    #[serde(default)]
+    pub state_file: Option<PathBuf>,
+    /// Maximum level of the log records which are streamed to the clients. Log streaming is disabled when unset
+    #[serde(default)]
+    pub log_level: Option<String>,
+    /// Whether device names in requests are matched case and separator insensitive
+    #[serde(default)]
+    pub loose_names: bool,
+    /// Weight of the newest reading (0..1] in the moving average of the current power draw. Smoothing is disabled when unset
+    #[serde(default)]
+    pub power_smoothing: Option<f64>,
+    /// Names of the methods which can be called by clients. All methods are enabled when unset
+    #[serde(default)]
+    pub enabled_methods: Option<Vec<String>>,
+    /// Whether devices which aren't logged in attempt a new login on every request instead of waiting for the retry delay.
+    /// Enabled by default which lets every request to an unreachable device wait for a login attempt
+    #[serde(default = "default_auto_relogin")]
+    pub auto_relogin: bool,
+    /// Webhooks to which the events are posted
+    #[serde(default, rename = "webhook")]
+    pub webhooks: Vec<WebhookDefinition>,
+    /// Devices which blink when an event of a given type is broadcast
+    #[serde(default, rename = "notifier")]
+    pub notifiers: Vec<NotifierDefinition>,
+    /// Top-level keys of the raw device info which are returned by `info_json`. All keys are returned when unset
+    #[serde(default)]
+    pub info_json_allow: Option<Vec<String>>,
+    /// Top-level keys of the raw device info which are never returned by `info_json`
+    #[serde(default)]
+    pub info_json_deny: Vec<String>,
+    /// Number of the last events which are kept for replaying them to new subscribers
+    #[serde(default)]
+    pub event_history: usize,
+    /// Brightness limit during the night for all devices without their own limit
+    #[serde(default)]
+    pub night: Option<NightLimit>
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct NotifierDefinition {
+    /// Name of the event type on which the device blinks
+    pub event: String,
+    /// Device which blinks
+    pub device: String
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct WebhookDefinition {
+    pub url: String,
+    /// Names of the event types which are posted to the webhook. All events are posted when empty
+    #[serde(default)]
+    pub events: Vec<String>
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -56,23 +181,297 @@ pub struct Authentication {
 pub struct DeviceDefinition {
     #[serde(rename = "type")]
     pub device_type: SupportedDevice,
-    pub address: String
+    pub address: String,
+    /// Time in seconds after which the device is turned off automatically should it still be turned on
+    #[serde(default)]
+    pub max_on_time: Option<u64>,
+    /// Devices with a higher priority are logged into first on startup
+    #[serde(default)]
+    pub priority: i32,
+    /// Time in milliseconds over which the brightness is lowered before the device is turned off
+    #[serde(default)]
+    pub off_fade: Option<u64>,
+    /// Color temperature in kelvin which is set when the device is turned on
+    #[serde(default)]
+    pub on_temperature: Option<u16>,
+    /// Brightness which is set when the device is turned on
+    #[serde(default)]
+    pub on_brightness: Option<u8>,
+    /// Correction of the colors which are set to match the rendering of other devices
+    #[serde(default)]
+    pub calibration: Calibration,
+    /// Brightness limit during the night which overrides the global limit
+    #[serde(default)]
+    pub night: Option<NightLimit>,
+    /// Nickname of the child device behind the hub at the address. Required for the KE100 type
+    #[serde(default)]
+    pub child: Option<String>
+}
+
+/// Maximum brightness which is enforced between the start and the end of the night
+///
+/// The times are in the local time given by the offset to UTC. Since the offset is fixed it
+/// has to be adjusted manually for daylight saving time
+#[derive(Deserialize, Debug, Clone)]
+pub struct NightLimit {
+    /// Start of the night as `HH:MM` (e.g. 21:00)
+    pub start: String,
+    /// End of the night as `HH:MM` (e.g. 06:00). Nights ending before they start end on the next day
+    pub end: String,
+    /// Highest brightness which can be set during the night
+    pub max_brightness: u8,
+    /// Offset of the local time to UTC in minutes (e.g. 120 for UTC+2)
+    #[serde(default)]
+    pub utc_offset: i32
+}
+
+/// Correction of the hue, saturation and brightness which are sent to a device
+///
+/// The default calibration doesn't change any values
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct Calibration {
+    /// Offset in degrees which is added to the hue
+    #[serde(default)]
+    pub hue_offset: i32,
+    /// Factor with which the saturation is scaled
+    #[serde(default = "default_scale")]
+    pub saturation_scale: f64,
+    /// Factor with which the brightness is scaled
+    #[serde(default = "default_scale")]
+    pub brightness_scale: f64
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Self { hue_offset: 0, saturation_scale: default_scale(), brightness_scale: default_scale() }
+    }
+}
+
+impl Calibration {
+    /// Apply the correction to the brightness and the hue and saturation which are sent to the device
+    ///
+    /// The corrected values stay in the ranges accepted by the devices (hue 1 to 360, saturation and brightness 1 to 100)
+    pub fn apply(&self, brightness: Option<u8>, hue_saturation: Option<(u16, u8)>) -> (Option<u8>, Option<(u16, u8)>) {
+        let brightness = brightness.map(|brightness| scale(brightness, self.brightness_scale));
+        let hue_saturation = hue_saturation.map(|(hue, saturation)| {
+            // the devices only accept hues from 1 to 360 where 360 is the same red as 0
+            let hue = (hue as i32 + self.hue_offset).rem_euclid(360) as u16;
+            (if hue == 0 { 360 } else { hue }, scale(saturation, self.saturation_scale))
+        });
+        (brightness, hue_saturation)
+    }
+}
+
+fn scale(value: u8, factor: f64) -> u8 {
+    (value as f64 * factor).round().clamp(1f64, 100f64) as u8
+}
+
+impl NightLimit {
+    /// Check the times, the brightness and the offset for valid ranges
+    pub fn validate(&self) -> Result<(), String> {
+        for time in [&self.start, &self.end] {
+            if minute_of_day(time).is_none() {
+                Err(format!("'{time}' is not a time in the format HH:MM"))?;
+            }
+        }
+        if !(1..=100).contains(&self.max_brightness) {
+            Err(format!("'{}' is not in range 1 to 100", self.max_brightness))?;
+        }
+        if !(-720..=840).contains(&self.utc_offset) {
+            Err(format!("'{}' is not a utc offset in range -720 to 840 minutes", self.utc_offset))?;
+        }
+        Ok(())
+    }
+
+    /// Get the maximum brightness should it currently be night
+    pub fn current_limit(&self) -> Option<u8> {
+        let seconds = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+        let minute = (seconds / 60 + self.utc_offset as i64).rem_euclid(24 * 60) as u16;
+        self.contains(minute).then_some(self.max_brightness)
+    }
+
+    fn contains(&self, minute: u16) -> bool {
+        let (Some(start), Some(end)) = (minute_of_day(&self.start), minute_of_day(&self.end)) else { return false };
+        if start <= end { (start..end).contains(&minute) }
+        else { minute >= start || minute < end }
+    }
+}
+
+/// Parse a time given as `HH:MM` into the minutes since midnight
+fn minute_of_day(time: &str) -> Option<u16> {
+    let (hours, minutes) = time.trim().split_once(':')?;
+    let (hours, minutes) = (hours.parse::<u16>().ok()?, minutes.parse::<u16>().ok()?);
+    (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
 }
 
 #[derive(Deserialize, Debug, Clone, EnumStringify)]
 pub enum SupportedDevice {
     L530,
     L630,
+    /// Color light strip
+    L900,
     L510,
     L520,
     L610,
-    Generic
+    /// Plug without energy monitoring
+    P100,
+    /// Plug without energy monitoring
+    P105,
+    /// Energy monitoring plug
+    P110,
+    /// Energy monitoring plug
+    P115,
+    /// Hub for child devices like the T310 and T315 temperature and humidity sensors
+    H100,
+    /// Thermostatic radiator valve paired with a hub
+    KE100,
+    Generic,
+    /// In-memory device without any network access for testing and demos
+    Virtual,
+    /// Device whose type is detected from the model it reports after logging in
+    Auto
+}
+
+/// Features supported by a device type
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Capabilities {
+    pub brightness: bool,
+    pub color: bool,
+    pub temperature: bool,
+    pub energy_monitoring: bool
+}
+
+impl Capabilities {
+    /// Get the names of all supported features
+    pub fn names(&self) -> Vec<&'static str> {
+        [
+            (self.brightness, "brightness"),
+            (self.color, "color"),
+            (self.temperature, "temperature"),
+            (self.energy_monitoring, "energy monitoring")
+        ].into_iter().filter(|(supported, _)| *supported).map(|(_, name)| name).collect()
+    }
+}
+
+impl SupportedDevice {
+    /// Get the features supported by the device type
+    pub fn capabilities(&self) -> Capabilities {
+        match self {
+            SupportedDevice::L530 | SupportedDevice::L630 | SupportedDevice::L900 => Capabilities {
+                brightness: true,
+                color: true,
+                temperature: true,
+                ..Capabilities::default()
+            },
+            SupportedDevice::L510 | SupportedDevice::L520 | SupportedDevice::L610 => Capabilities {
+                brightness: true,
+                ..Capabilities::default()
+            },
+            SupportedDevice::P110 | SupportedDevice::P115 => Capabilities {
+                energy_monitoring: true,
+                ..Capabilities::default()
+            },
+            SupportedDevice::P100 | SupportedDevice::P105 | SupportedDevice::H100 | SupportedDevice::KE100 | SupportedDevice::Generic => Capabilities::default(),
+            SupportedDevice::Virtual => Capabilities {
+                brightness: true,
+                color: true,
+                temperature: true,
+                ..Capabilities::default()
+            },
+            // the capabilities are unknown until the type is detected
+            SupportedDevice::Auto => Capabilities::default()
+        }
+    }
+
+    /// Get the device type for the model reported by a device (e.g. `L530E` or `L510 Series`)
+    ///
+    /// Returns `None` should the model not match any of the supported types
+    pub fn from_model(model: &str) -> Option<Self> {
+        let model = model.trim().to_uppercase();
+        [
+            SupportedDevice::L530,
+            SupportedDevice::L630,
+            SupportedDevice::L900,
+            SupportedDevice::L510,
+            SupportedDevice::L520,
+            SupportedDevice::L610,
+            SupportedDevice::P100,
+            SupportedDevice::P105,
+            SupportedDevice::P110,
+            SupportedDevice::P115,
+            SupportedDevice::H100
+        ].into_iter().find(|device_type| model.starts_with(device_type.to_string().as_str()))
+    }
+}
+
+impl ServerConfig {
+    /// Cross-check the options which depend on the capabilities of the configured devices
+    ///
+    /// Returns a warning for every option which can't have any effect with the configured devices
+    pub fn capability_warnings(&self) -> Vec<String> {
+        let mut warnings = vec![];
+
+        // devices with a detected type could support energy monitoring
+        let energy_monitoring = self.devices.values()
+            .any(|definition| definition.device_type.capabilities().energy_monitoring || matches!(definition.device_type, SupportedDevice::Auto));
+        if self.energy_interval.is_some() && !energy_monitoring {
+            warnings.push(String::from("'energy_interval' is set but none of the configured devices supports energy monitoring"));
+        }
+
+        let mut devices = self.devices.iter().collect::<Vec<_>>();
+        devices.sort_by_key(|(name, _)| *name);
+        for (name, definition) in devices {
+            // the capabilities of devices with a detected type are only known after logging in
+            if matches!(definition.device_type, SupportedDevice::Auto) { continue }
+            let capabilities = definition.device_type.capabilities();
+            if definition.on_temperature.is_some() && !capabilities.temperature {
+                warnings.push(format!("'on_temperature' of device '{name}' is set but {} devices don't support color temperatures", definition.device_type));
+            }
+            if definition.on_brightness.is_some() && !capabilities.brightness {
+                warnings.push(format!("'on_brightness' of device '{name}' is set but {} devices don't support brightness", definition.device_type));
+            }
+        }
+
+        warnings
+    }
+
+    /// Find the devices which share the same address
+    ///
+    /// Returns a warning for every address which is used by more than one device
+    pub fn duplicate_address_warnings(&self) -> Vec<String> {
+        let mut addresses = HashMap::<String, Vec<&String>>::new();
+        // the address of virtual devices isn't used
+        let devices = self.devices.iter().filter(|(_, definition)| !matches!(definition.device_type, SupportedDevice::Virtual));
+        for (name, definition) in devices {
+            addresses.entry(definition.address.trim().to_lowercase()).or_default().push(name);
+        }
+
+        let mut warnings = addresses.into_iter()
+            .filter(|(_, names)| names.len() > 1)
+            .map(|(address, mut names)| {
+                names.sort();
+                let names = names.iter().map(|name| format!("'{name}'")).collect::<Vec<_>>().join(", ");
+                format!("Devices {names} share the same address '{address}'")
+            })
+            .collect::<Vec<_>>();
+        warnings.sort();
+        warnings
+    }
 }
 
 impl ClientConfig {
-    pub fn from(address: Option<String>, port: Option<u16>, secure: Option<bool>) -> Option<Self> {
-        if address.is_some() || port.is_some() || secure.is_some() {
-            Some(Self { port: port.unwrap_or(default_port()), address: address.unwrap_or(default_address()), secure: secure.unwrap_or_default() })
+    pub fn from(address: Option<String>, port: Option<u16>, secure: Option<bool>, compression: Option<bool>) -> Option<Self> {
+        if address.is_some() || port.is_some() || secure.is_some() || compression.is_some() {
+            Some(Self {
+                port: port.unwrap_or(default_port()),
+                address: address.unwrap_or(default_address()),
+                secure: secure.unwrap_or_default(),
+                compression: compression.unwrap_or(default_compression()),
+                aliases: HashMap::new(),
+                price_per_kwh: None,
+                default_device: None,
+                color_fallback: ColorFallback::default()
+            })
         } else {
             None
         }
@@ -81,31 +480,60 @@ impl ClientConfig {
 
 impl Config {
     pub fn new(alternative_path: Option<String>) -> Self {
-        let path = match &alternative_path {
-            Some(path) => PathBuf::from(path),
-            None => dirs::config_dir().unwrap_or_default().join(CONFIG_PATH)
-        };
+        let ConfigResolution { path, result } = Self::resolve(alternative_path);
 
-        let content = match fs::read(&path).context(format!("Missing configuration file at '{}'", path.to_string_lossy())) {
-            Ok(content) => content,
-            Err(err) => {
+        match result {
+            Ok(config) => config,
+            Err(ConfigError::Missing(err)) => {
                 debug!("Unable to read config file at {path:?}: {err}");
-                return Config::None
-            }
-        };
-
-        let utf8 = match String::from_utf8(content) {
-            Ok(utf8) => utf8,
-            Err(_) => {
+                Config::None
+            },
+            Err(ConfigError::InvalidUtf8) => {
                 error!("Invalid UTF-8 config file at {path:?}");
                 exit(1)
+            },
+            Err(ConfigError::Invalid(err)) => {
+                error!("Error whilst reading config file: {err}");
+                Config::None
             }
+        }
+    }
+
+    /// Resolve the path of the configuration file and try to load it
+    ///
+    /// The `alternative_path` takes precedence over the default path in the user's config directory
+    pub fn resolve(alternative_path: Option<String>) -> ConfigResolution {
+        let path = match &alternative_path {
+            Some(path) => PathBuf::from(path),
+            None => PathBuf::from(Self::default_path())
         };
 
-        toml::from_str(utf8.as_str()).context("Config file doesn't match config definition").unwrap_or_else(|err| {
-            error!("Error whilst reading config file: {err}");
-            Config::None
-        })
+        let result = fs::read(&path)
+            .context(format!("Missing configuration file at '{}'", path.to_string_lossy()))
+            .map_err(|err| ConfigError::Missing(err.to_string()))
+            .and_then(|content| String::from_utf8(content).map_err(|_| ConfigError::InvalidUtf8))
+            .and_then(|utf8| {
+                let format = ConfigFormat::from_path(&path);
+                format.parse(utf8.as_str())
+                    .context(format!("{} config file doesn't match config definition", format.name()))
+                    .map_err(|err| ConfigError::Invalid(format!("{err:#}")))
+            });
+
+        ConfigResolution { path, result }
+    }
+
+    /// Get the default path of the configuration file inside the user's config directory
+    pub fn default_path() -> String {
+        dirs::config_dir().unwrap_or_default().join(CONFIG_PATH).to_string_lossy().to_string()
+    }
+
+    /// Get a human-readable name of the configuration kind
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Config::Server(_) => "server",
+            Config::Client(_) => "client",
+            Config::None => "none"
+        }
     }
 }
 
@@ -113,8 +541,65 @@ fn default_address() -> String {
     String::from("127.0.0.1")
 }
 
-fn default_port() -> u16 {
+pub fn default_port() -> u16 {
     19191
 }
 
-fn default_timeout() -> u32 { 10000 }
\ No newline at end of file
+fn default_timeout() -> u32 { 10000 }
+
+fn default_compression() -> bool { true }
+
+fn default_auto_relogin() -> bool { true }
+
+fn default_scale() -> f64 { 1f64 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identity_calibration_keeps_values() {
+        let calibration = Calibration::default();
+        assert_eq!(calibration.apply(Some(42), Some((200, 70))), (Some(42), Some((200, 70))));
+        assert_eq!(calibration.apply(None, None), (None, None));
+    }
+
+    #[test]
+    fn hue_offset_wraps_around() {
+        let calibration = Calibration { hue_offset: 20, ..Calibration::default() };
+        assert_eq!(calibration.apply(None, Some((350, 50))).1, Some((10, 50)));
+        // the devices expect 360 instead of 0 for red
+        assert_eq!(calibration.apply(None, Some((340, 50))).1, Some((360, 50)));
+
+        let calibration = Calibration { hue_offset: -30, ..Calibration::default() };
+        assert_eq!(calibration.apply(None, Some((10, 50))).1, Some((340, 50)));
+    }
+
+    #[test]
+    fn scaling_is_clamped_to_device_range() {
+        let calibration = Calibration { saturation_scale: 1.5, brightness_scale: 1.5, ..Calibration::default() };
+        assert_eq!(calibration.apply(Some(40), Some((100, 40))), (Some(60), Some((100, 60))));
+        assert_eq!(calibration.apply(Some(80), Some((100, 90))), (Some(100), Some((100, 100))));
+
+        let calibration = Calibration { saturation_scale: 0.1, brightness_scale: 0.0, ..Calibration::default() };
+        assert_eq!(calibration.apply(Some(50), Some((100, 4))), (Some(1), Some((100, 1))));
+    }
+
+    #[test]
+    fn light_strip_type_is_parsed() {
+        let config: ServerConfig = toml::from_str("[auth]\nusername = \"\"\npassword = \"\"\n[devices.strip]\ntype = \"L900\"\naddress = \"192.168.1.20\"").unwrap();
+        let definition = &config.devices["strip"];
+        assert!(matches!(definition.device_type, SupportedDevice::L900));
+        assert_eq!(definition.address, "192.168.1.20");
+
+        let capabilities = definition.device_type.capabilities();
+        assert!(capabilities.brightness && capabilities.color && capabilities.temperature);
+        assert!(!capabilities.energy_monitoring);
+    }
+
+    #[test]
+    fn light_strip_model_is_detected() {
+        assert!(matches!(SupportedDevice::from_model("L900-5"), Some(SupportedDevice::L900)));
+        assert!(matches!(SupportedDevice::from_model("l900 Series"), Some(SupportedDevice::L900)));
+    }
+}
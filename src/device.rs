@@ -1,12 +1,13 @@
 use std::cmp::min;
 use std::time::{Duration, SystemTime};
 use log::{debug, error, info, warn};
-use tapo::{ApiClient, ColorLightHandler, GenericDeviceHandler, LightHandler};
+use tapo::{ApiClient, ColorLightHandler, GenericDeviceHandler, LightHandler, HubHandler, HubDevice, KE100Handler, PlugEnergyMonitoringHandler, PlugHandler, RgbLightStripHandler};
 use tonic::Status;
-use crate::config::{DeviceDefinition, SupportedDevice};
+use crate::config::{Calibration, DeviceDefinition, NightLimit, SupportedDevice};
 use crate::tapo::server::{EventSender, rpc};
 use crate::tapo::server::rpc::EventType;
 use crate::tapo::{create_event, TapoSessionStatusExt};
+use crate::tapo::device::virtual_device::VirtualDeviceHandler;
 
 const SESSION_VALIDITY_MILLIS: u64 = 60 * 60 * 1000; // 60 minutes
 const SESSION_REFRESH_RETRIES: u8 = 10; // after 10 failed session refresh attempts the session status can be set to RepeatedFailure
@@ -24,16 +25,32 @@ pub struct Device {
     pub name: String,
     pub device_type: SupportedDevice,
     pub session_status: SessionStatus,
+    /// Duration over which the brightness is lowered before the device is turned off
+    pub off_fade: Option<Duration>,
+    /// Brightness from before the last fade which is restored when the device is turned on
+    pub faded_brightness: Option<u8>,
+    /// Color temperature which is set when the device is turned on
+    pub on_temperature: Option<u16>,
+    /// Brightness which is set when the device is turned on
+    pub on_brightness: Option<u8>,
+    /// Correction of the colors which are set
+    pub calibration: Calibration,
+    /// Brightness limit during the night
+    pub night: Option<NightLimit>,
+    /// Nickname of the child device behind the hub at the address
+    pub child: Option<String>,
     client: ApiClient,
     next_session_action: SystemTime,
     handler: Option<DeviceHandler>,
     refresh_retires: u8,
+    auto_relogin: bool,
     sender: EventSender,
 }
 
 impl Device {
-    pub async fn new(name: String, definition: DeviceDefinition, client: ApiClient, sender: EventSender) -> Option<Self> {
-        let handler = Self::acquire_handler(&definition.device_type, &definition.address, client.clone()).await;
+    pub async fn new(name: String, definition: DeviceDefinition, client: ApiClient, sender: EventSender, auto_relogin: bool) -> Option<Self> {
+        let mut device_type = definition.device_type;
+        let handler = Self::acquire_handler(&name, &mut device_type, &definition.address, definition.child.as_deref(), client.clone()).await;
 
         if let Err(err) = &handler {
             warn!("Unable to log into device '{name}': {err}. Retrying on next access...")
@@ -49,19 +66,35 @@ impl Device {
 
         Some(Self {
             refresh_retires: if handler.is_ok() { 0 } else { 1 },
-            device_type: definition.device_type,
+            device_type,
+            off_fade: definition.off_fade.map(Duration::from_millis),
+            faded_brightness: None,
+            on_temperature: definition.on_temperature,
+            on_brightness: definition.on_brightness,
+            calibration: definition.calibration,
+            night: definition.night,
+            child: definition.child,
             address: definition.address,
             session_status: if handler.is_ok() { SessionStatus::Authenticated } else { SessionStatus::Failure },
             handler: handler.ok(),
             next_session_action,
             name,
             client,
+            auto_relogin,
             sender
         })
     }
 
     /// Try to get the device handler from the tapo api for a specific device
-    async fn acquire_handler(device_type: &SupportedDevice, address: &String, client: ApiClient) -> Result<DeviceHandler, Status> {
+    ///
+    /// An [`SupportedDevice::Auto`] device type is replaced with the type detected from the model reported by the device.
+    /// Devices with an unrecognized model fall back to the generic handler. Child devices are acquired through
+    /// the hub at the address
+    async fn acquire_handler(name: &str, device_type: &mut SupportedDevice, address: &String, child: Option<&str>, client: ApiClient) -> Result<DeviceHandler, Status> {
+        if let SupportedDevice::Auto = device_type {
+            *device_type = Self::detect_device_type(name, address, client.clone()).await?;
+        }
+
         match device_type {
             SupportedDevice::L530 => {
                 client.l530(address).await.map_err(|err| Status::internal(err.to_string())).map(DeviceHandler::ColorLight)
@@ -69,6 +102,9 @@ impl Device {
             SupportedDevice::L630 => {
                 client.l630(address).await.map_err(|err| Status::internal(err.to_string())).map(DeviceHandler::ColorLight)
             },
+            SupportedDevice::L900 => {
+                client.l900(address).await.map_err(|err| Status::internal(err.to_string())).map(DeviceHandler::LightStrip)
+            },
             SupportedDevice::L510 => {
                 client.l510(address).await.map_err(|err| Status::internal(err.to_string())).map(DeviceHandler::Light)
             }
@@ -78,9 +114,47 @@ impl Device {
             SupportedDevice::L610 => {
                 client.l610(address).await.map_err(|err| Status::internal(err.to_string())).map(DeviceHandler::Light)
             }
-            SupportedDevice::Generic => {
+            SupportedDevice::P100 => {
+                client.p100(address).await.map_err(|err| Status::internal(err.to_string())).map(DeviceHandler::Plug)
+            }
+            SupportedDevice::P105 => {
+                client.p105(address).await.map_err(|err| Status::internal(err.to_string())).map(DeviceHandler::Plug)
+            }
+            SupportedDevice::P110 => {
+                client.p110(address).await.map_err(|err| Status::internal(err.to_string())).map(DeviceHandler::EnergyPlug)
+            }
+            SupportedDevice::P115 => {
+                client.p115(address).await.map_err(|err| Status::internal(err.to_string())).map(DeviceHandler::EnergyPlug)
+            }
+            SupportedDevice::H100 => {
+                client.h100(address).await.map_err(|err| Status::internal(err.to_string())).map(DeviceHandler::Hub)
+            }
+            SupportedDevice::KE100 => {
+                let hub = client.h100(address).await.map_err(|err| Status::internal(err.to_string()))?;
+                let child = HubDevice::ByNickname(child.unwrap_or_default().to_string());
+                hub.ke100(child).await.map_err(|err| Status::internal(err.to_string())).map(DeviceHandler::Valve)
+            }
+            // the auto type was already replaced with the detected type
+            SupportedDevice::Generic | SupportedDevice::Auto => {
                 client.generic_device(address).await.map_err(|err| Status::internal(err.to_string())).map(DeviceHandler::Generic)
             }
+            SupportedDevice::Virtual => Ok(DeviceHandler::Virtual(VirtualDeviceHandler::default()))
+        }
+    }
+
+    /// Detect the device type from the model which the device reports to the generic handler
+    async fn detect_device_type(name: &str, address: &String, client: ApiClient) -> Result<SupportedDevice, Status> {
+        let handler = client.generic_device(address).await.map_err(|err| Status::internal(err.to_string()))?;
+        let info = handler.get_device_info().await.map_err(|err| Status::internal(err.to_string()))?;
+        match SupportedDevice::from_model(&info.model) {
+            Some(device_type) => {
+                info!("Detected type {device_type} for device '{name}' with model '{}'", info.model);
+                Ok(device_type)
+            },
+            None => {
+                warn!("Unrecognized model '{}' of device '{name}'. Falling back to the generic type", info.model);
+                Ok(SupportedDevice::Generic)
+            }
         }
     }
 
@@ -93,17 +167,38 @@ impl Device {
         let now = SystemTime::now();
         let current = self.session_status.clone();
 
+        // devices behind a hub share its session which is only renewed by acquiring them again
+        if matches!(self.handler, Some(DeviceHandler::Valve(_))) {
+            self.handler = None;
+        }
+
         let result = if let Some(handler) = &mut self.handler {
             let result = match handler {
                 DeviceHandler::ColorLight(handler) => {
                     handler.refresh_session().await.map_err(|err| Status::internal(err.to_string())).err()
                 },
+                DeviceHandler::LightStrip(handler) => {
+                    handler.refresh_session().await.map_err(|err| Status::internal(err.to_string())).err()
+                },
                 DeviceHandler::Light(handler) => {
                     handler.refresh_session().await.map_err(|err| Status::internal(err.to_string())).err()
                 },
                 DeviceHandler::Generic(handler) => {
                     handler.refresh_session().await.map_err(|err| Status::internal(err.to_string())).err()
                 },
+                DeviceHandler::EnergyPlug(handler) => {
+                    handler.refresh_session().await.map_err(|err| Status::internal(err.to_string())).err()
+                },
+                DeviceHandler::Plug(handler) => {
+                    handler.refresh_session().await.map_err(|err| Status::internal(err.to_string())).err()
+                },
+                DeviceHandler::Hub(handler) => {
+                    handler.refresh_session().await.map_err(|err| Status::internal(err.to_string())).err()
+                },
+                // the handler of devices behind a hub was already dropped to acquire it again
+                DeviceHandler::Valve(_) => None,
+                // virtual devices don't have a session which could expire
+                DeviceHandler::Virtual(_) => None,
             };
             if let Some(error) = result {
                 debug!("Session refresh failed for device '{}' with reason: {}", self.name, error);
@@ -117,7 +212,7 @@ impl Device {
             }
         } else {
             debug!("Attempting initial session acquisition for device '{}'", self.name);
-            match Self::acquire_handler(&self.device_type, &self.address, self.client.clone()).await {
+            match Self::acquire_handler(&self.name, &mut self.device_type, &self.address, self.child.as_deref(), self.client.clone()).await {
                 Ok(handler) => {
                     self.session_status = SessionStatus::Authenticated;
                     self.next_session_action = now + Duration::from_millis(SESSION_VALIDITY_MILLIS);
@@ -140,14 +235,7 @@ impl Device {
 
         if current.ne(&self.session_status) {
             debug!("Session status changed: {:?}", self.session_status);
-            let device = rpc::Device {
-                name: self.name.clone(),
-                status: self.session_status.rpc().into(),
-                address: self.address.clone(),
-                r#type: self.device_type.to_string()
-            };
-
-            if let Err(err) = self.sender.send(create_event(EventType::DeviceAuthChange, device)) {
+            if let Some(Err(err)) = create_event(EventType::DeviceAuthChange, self.rpc()).map(|event| self.sender.send(event)) {
                 error!("Error whilst sending new device auth state: {err}")
             }
         }
@@ -155,23 +243,42 @@ impl Device {
         result
     }
 
+    /// Get the rpc representation of the device
+    pub fn rpc(&self) -> rpc::Device {
+        rpc::Device {
+            name: self.name.clone(),
+            r#type: self.device_type.to_string(),
+            address: self.address.clone(),
+            status: self.session_status.rpc().into()
+        }
+    }
+
     /// Attempt to refresh the auth session for the device
     ///
     /// Should the session be expired or the previous refresh attempt failed a new attempt is started.
     /// After 10 failed refresh attempts the session state changes to [`SessionStatus::RepeatedFailure`] which only allows
-    /// the next refresh attempt after 10 minutes
+    /// the next refresh attempt after 10 minutes. With auto relogin enabled devices which aren't logged in at all
+    /// attempt a login on every call regardless of the previous failures
     pub async fn try_refresh_session(&mut self) -> Result<(), Status> {
         let now = SystemTime::now();
 
         debug!("Try session refresh: {:?} {:?}", now, self.next_session_action);
 
-        if now.ge(&self.next_session_action) {
+        let relogin = self.auto_relogin && self.handler.is_none();
+        if relogin || now.ge(&self.next_session_action) {
             self.refresh_session().await
         } else {
             Ok(())
         }
     }
 
+    /// Get the highest brightness which can currently be set should the night limit of the device apply
+    ///
+    /// The limit is returned regardless of the capabilities of the device. Callers only apply it to devices with brightness support
+    pub fn night_limit(&self) -> Option<u8> {
+        self.night.as_ref().and_then(NightLimit::current_limit)
+    }
+
     /// Access the current device handler
     ///
     /// Returns tonic status code should the handler be unavailable
@@ -185,7 +292,13 @@ impl Device {
 
 pub enum DeviceHandler {
     ColorLight(ColorLightHandler),
+    LightStrip(RgbLightStripHandler),
     Light(LightHandler),
-    Generic(GenericDeviceHandler)
+    Generic(GenericDeviceHandler),
+    EnergyPlug(PlugEnergyMonitoringHandler),
+    Plug(PlugHandler),
+    Hub(HubHandler),
+    Valve(KE100Handler),
+    Virtual(VirtualDeviceHandler)
 }
 
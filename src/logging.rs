@@ -0,0 +1,72 @@
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::{LevelFilter, Log, Metadata, Record};
+use tokio::sync::broadcast;
+use crate::tapo::server::rpc::LogRecord;
+
+pub type LogSender = broadcast::Sender<LogRecord>;
+
+/// Targets whose records are never forwarded since forwarding a record causes
+/// new records in the transport crates
+const IGNORED_TARGETS: [&str; 4] = ["h2", "hyper", "tonic", "tower"];
+
+/// Level and sender of the forwarded log records. Only set when log streaming is enabled
+static FORWARDING: OnceLock<(LevelFilter, LogSender)> = OnceLock::new();
+
+/// Logger which prints the records using `env_logger` and additionally forwards them to
+/// the clients subscribed to the log stream
+struct ForwardingLogger {
+    inner: env_logger::Logger
+}
+
+impl Log for ForwardingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata) || FORWARDING.get().is_some_and(|(level, _)| metadata.level() <= *level)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.inner.matches(record) {
+            self.inner.log(record);
+        }
+
+        let Some((level, sender)) = FORWARDING.get() else { return };
+        let ignored = IGNORED_TARGETS.iter().any(|target| record.target().starts_with(target));
+        if record.level() > *level || ignored || sender.receiver_count() == 0 {
+            return;
+        }
+
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        // sending only fails when there are no subscribers left which isn't worth reporting
+        let _ = sender.send(LogRecord {
+            level: record.level().to_string(),
+            target: record.target().to_string(),
+            message: record.args().to_string(),
+            timestamp
+        });
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+/// Install the logger with the level from the `RUST_LOG` environment variable
+pub fn init() {
+    let inner = env_logger::Builder::from_env(env_logger::Env::new().default_filter_or("info")).build();
+    let filter = inner.filter();
+
+    if let Err(err) = log::set_boxed_logger(Box::new(ForwardingLogger { inner })) {
+        eprintln!("Unable to install logger: {err}");
+        return;
+    }
+    log::set_max_level(filter);
+}
+
+/// Start forwarding the log records up to the given level
+///
+/// Returns the sender to which the clients can subscribe for receiving the records
+pub fn enable_forwarding(level: LevelFilter) -> LogSender {
+    let (_, sender) = FORWARDING.get_or_init(|| (level, broadcast::channel(64).0));
+    log::set_max_level(log::max_level().max(level));
+    sender.clone()
+}
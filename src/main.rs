@@ -2,44 +2,73 @@ use std::collections::HashMap;
 use std::path::Path;
 use std::process::exit;
 use std::str::FromStr;
-use clap::{Parser, ValueEnum};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::io::Write;
+use std::future::Future;
+use clap::{CommandFactory, Parser, ValueEnum};
+use clap::error::ErrorKind;
 use clap_complete::{Generator, Shell};
 use cli::UtilCommand;
 use colored::Colorize;
+use futures::StreamExt;
+use log::{error, warn};
 use serde_json::{json, Value};
 use spinoff::{Spinner, spinners};
-use tonic::transport::Channel;
-use crate::cli::{Cli, ClientCommand, Commands, ServerCommand, SpinnerOpt};
-use crate::config::{ClientConfig, Config};
-use crate::tapo::server::rpc::{DeviceRequest, HueSaturation, Empty, SetRequest, EventRequest, EventType, InfoResponse, Device};
+use tonic::codec::CompressionEncoding;
+use tonic::transport::{Channel, Uri};
+use crate::cli::{config_path_from_args, expand_aliases, kelvin_to_mireds, parse_device_list, temperature_in_kelvin, Cli, ClientCommand, Commands, ConfigCommand, InfoFormat, ServerCommand, SpinnerOpt};
+use crate::config::{default_port, ClientConfig, Config, ConfigError, ConfigResolution};
+use crate::tapo::server::rpc::{DeviceRequest, HueSaturation, InfoBatchRequest, IntegerValueChange, OffRequest, Empty, PowerManyRequest, SetManyRequest, SetRequest, EventRequest, EventResponse, EventType, ResetConfirmRequest, SensorRequest, SleepRequest, TargetTemperatureRequest, BulkResponse, CurrentPowerResponse, InfoResponse, Device, Operation, SafetyCutoffEvent};
 use crate::tapo::server::rpc::tapo_client::TapoClient;
 use crate::tapo::start_server;
+use crate::tapo::mirror::start_mirror;
 use crate::tapo::TonicErrMap;
+use crate::tapo::print::{cost_today, devices_table, doctor_table, gradient_table, DoctorCheck, FormattedInfo, GradientResult, InfoAsEnv, UsageSummary};
+use crate::tapo::color::any_to_hsl;
+use crate::tapo::validation::validate_hue_saturation;
 
 mod device;
 mod config;
 mod tapo;
 mod cli;
 mod completions;
+mod logging;
+mod palette;
+mod replay;
+mod snapshot;
+
+const CONNECT_RETRY_DELAY: Duration = Duration::from_millis(500);
+const MAX_CONNECT_RETRY_DELAY: Duration = Duration::from_secs(8);
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+    logging::init();
+
+    let args = std::env::args().collect::<Vec<_>>();
+    // the aliases are only read silently since the config is loaded again after parsing
+    let args = match Config::resolve(Some(config_path_from_args(&args))).result {
+        Ok(Config::Client(config)) => expand_aliases(args, &config.aliases),
+        _ => args
+    };
 
-    let cli: Cli = Cli::parse();
-    let config = cli.config;
+    let cli: Cli = Cli::parse_from(args);
+    let config_path = cli.config;
+    let config = Config::new(Some(config_path.clone()));
     let json = cli.json;
 
     match cli.command {
 
         Commands::Server(server_command) => {
             let server_config = match config {
-                Config::Server(cfg) => Some(cfg),
+                Config::Server(cfg) => Some(*cfg),
                 _ => None
             };
             match server_command {
-                ServerCommand::Serve { port } => {
-                    start_server(port, server_config).await;
+                ServerCommand::Serve { port, strict } => {
+                    start_server(port, server_config, strict).await;
+                }
+                ServerCommand::Mirror { upstream, port, event_history } => {
+                    start_mirror(upstream, port.unwrap_or_else(default_port), event_history).await;
                 }
             }
         },
@@ -50,41 +79,120 @@ async fn main() -> anyhow::Result<()> {
                     let exists = path.exists();
                     if !exists {
                         if let Err(err) = std::fs::create_dir_all(&directory) {
-                            println!("Failed to create completions directory at {directory}: {err}");
+                            if json { println!("{}", json!({ "code": "Invalid directory", "message": format!("Failed to create completions directory: {err}") })) }
+                            else { println!("Failed to create completions directory at {directory}: {err}") }
                             return Ok(());
                         };
                     } else if !path.is_dir() {
-                        println!("Unable to write completions to {directory}. File exists but is not a directory!");
+                        if json { println!("{}", json!({ "code": "Invalid directory", "message": "File exists but is not a directory" })) }
+                        else { println!("Unable to write completions to {directory}. File exists but is not a directory!") }
                         return Ok(());
                     }
 
+                    let mut results = vec![];
                     for shell in Shell::value_variants() {
                         let completions = completions::generate_completions(*shell, "tapoctl");
-                        match std::fs::write(path.join(shell.file_name("tapoctl")), completions) {
+                        let file = path.join(shell.file_name("tapoctl"));
+                        let result = std::fs::write(&file, completions);
+                        if json {
+                            let error = result.err().map(|err| err.to_string());
+                            results.push(json!({ "shell": shell.to_string(), "path": file, "success": error.is_none(), "error": error }));
+                            continue;
+                        }
+                        match result {
                             Ok(_) => println!("Successfully created completions for {}", shell.to_string()),
                             Err(err) => println!("Error whilst writing completions file for {}: {err}", shell.to_string())
                         }
                     }
+                    if json {
+                        println!("{}", json!({ "directory": directory, "shells": results }))
+                    }
+                }
+                UtilCommand::Config(ConfigCommand::Path) => {
+                    let ConfigResolution { path, result } = Config::resolve(Some(config_path));
+                    let exists = path.exists();
+                    let (kind, error) = match &result {
+                        Ok(config) => (config.kind(), None),
+                        Err(ConfigError::Missing(_)) => (Config::None.kind(), None),
+                        Err(ConfigError::InvalidUtf8) => (Config::None.kind(), Some(String::from("Invalid UTF-8 config file"))),
+                        Err(ConfigError::Invalid(err)) => (Config::None.kind(), Some(err.clone()))
+                    };
+
+                    if json {
+                        println!("{}", json!({ "path": path, "exists": exists, "type": kind, "error": error }))
+                    } else {
+                        println!("{}: {}", "Path".bold(), path.to_string_lossy());
+                        println!("{}: {}", "Exists".bold(), if exists { "Yes" } else { "No" });
+                        println!("{}: {kind}", "Type".bold());
+                        if let Some(error) = error {
+                            println!("{}: {}", "Error".bold(), error.red());
+                        }
+                    }
                 }
             }
         },
-        Commands::Client(client_command) => {
+        Commands::Client(mut client_command) => {
             let client_config = match config {
                 Config::Client(mut cfg) => {
                     cfg.address = cli.address.clone().unwrap_or(cfg.address.clone());
                     cfg.port = cli.port.unwrap_or(cfg.port);
                     cfg.secure = cli.secure.unwrap_or(cfg.secure);
+                    cfg.compression = cli.compression.unwrap_or(cfg.compression);
                     Some(cfg)
                 },
                 _ => None,
-            }.or(ClientConfig::from(cli.address, cli.port, cli.secure));
+            }.or(ClientConfig::from(cli.address, cli.port, cli.secure, cli.compression));
 
-            let mut spinner = (!json).then(|| Spinner::new(spinners::Dots, "Preparing client...", None));
-            let mut client = get_client(client_config, &mut spinner, json).await;
+            let price_per_kwh = client_config.as_ref().and_then(|config| config.price_per_kwh);
+            let color_fallback = cli.color_fallback.or(client_config.as_ref().map(|config| config.color_fallback)).unwrap_or_default();
+            // the default device is resolved before connecting so a missing device is reported like any other missing argument
+            let default_device = || match client_config.as_ref().and_then(|config| config.default_device.clone()) {
+                Some(default) => default,
+                None => Cli::command().error(
+                    ErrorKind::MissingRequiredArgument,
+                    "the following required argument was not provided: <DEVICE>\n\nSpecify a device or set `default_device` in the client config"
+                ).exit()
+            };
+            // the names of a device file are added to the given devices and checked against the server once connected
+            let device_file = client_command.device_file_mut().and_then(|(file, devices)| {
+                let file = file.take()?;
+                let names = match std::fs::read_to_string(&file) {
+                    Ok(content) => parse_device_list(&content),
+                    Err(err) => Cli::command().error(ErrorKind::Io, format!("Unable to read device file {}: {err}", file.display())).exit()
+                };
+                if names.is_empty() {
+                    Cli::command().error(ErrorKind::InvalidValue, format!("Device file {} doesn't contain any devices", file.display())).exit()
+                }
+                devices.extend(names.iter().cloned());
+                Some((file, names))
+            });
+            if let Some(device @ None) = client_command.optional_device_mut() {
+                *device = Some(default_device());
+            }
+            if let Some(devices) = client_command.optional_devices_mut().filter(|devices| devices.is_empty()) {
+                devices.push(default_device());
+            }
+            // the assignments of the env format are evaluated by a shell and mustn't be mixed with the spinner output
+            let env_format = matches!(client_command, ClientCommand::Info { format: InfoFormat::Env, .. });
+            let mut spinner = (!json && !env_format).then(|| Spinner::new(spinners::Dots, "Preparing client...", None));
+            let mut client = get_client(client_config, &cli.env_prefix, cli.connect_retries, &mut spinner, json).await;
             spinner.update(spinners::Dots.into(), "Sending request...");
 
+            // all unknown names are reported together instead of failing the devices one by one
+            if let Some((file, names)) = device_file {
+                let registered = client.devices(Empty {}).await.map_tonic_err(&mut spinner, json).into_inner().devices;
+                let unknown = names.iter()
+                    .filter(|name| !registered.iter().any(|device| &device.name == *name))
+                    .map(|name| format!("'{name}'"))
+                    .collect::<Vec<_>>();
+                if !unknown.is_empty() {
+                    let message = format!("Unknown devices in {}: {}", file.display(), unknown.join(", "));
+                    Err::<(), _>(tonic::Status::not_found(message)).map_tonic_err(&mut spinner, json);
+                }
+            }
+
             match client_command {
-                ClientCommand::Devices => {
+                ClientCommand::Devices { long } => {
                     let devices = client.devices(Empty {}).await.map_tonic_err(&mut spinner, json).into_inner();
                     completions::save_device_completions(&devices.devices);
 
@@ -92,110 +200,513 @@ async fn main() -> anyhow::Result<()> {
                         println!("{}", json!(devices))
                     } else if devices.devices.is_empty() {
                         spinner.success("No devices registered")
-                    } else {
+                    } else if long {
                         spinner.success("Found devices:");
                         println!("{}", devices.devices.iter().map(|dev| {
                             let heading = format!("{}:", dev.name.bold().underline());
                             format!("{}\n{dev}", heading)
                         }).collect::<Vec<_>>().join("\n\n"));
+                    } else {
+                        spinner.success("Found devices:");
+                        println!("{}", devices_table(&devices.devices));
                     }
                 }
 
-                ClientCommand::Set { device, color, brightness, temperature, hue_saturation, power } => {
+                ClientCommand::Set { devices, color, brightness, temperature, hue_saturation, power, atomic, dry_run, explain, if_on, confirm, mireds, from_image, region, .. } => {
+                    // only the success of every device is reported when multiple devices are updated
+                    if devices.len() > 1 && (confirm || dry_run || explain) {
+                        Cli::command().error(ErrorKind::ArgumentConflict, "`--confirm`, `--dry-run` and `--explain` can only be used with a single device").exit()
+                    }
+                    let temperature = match temperature.map(|change| temperature_in_kelvin(change, mireds)) {
+                        Some(Err(err)) => Cli::command().error(ErrorKind::ValueValidation, err).exit(),
+                        Some(Ok(change)) => Some(change),
+                        None => None
+                    };
                     let request = SetRequest {
                         color: color.map(|c| c as i32),
-                        device,
                         brightness,
                         temperature,
                         power,
-                        hue_saturation: {
-                            let hue = hue_saturation.hue;
-                            let saturation = hue_saturation.saturation;
-                            if hue.is_some() && saturation.is_some() {
-                                Some(HueSaturation { saturation, hue })
-                            } else {
-                                None
+                        atomic,
+                        dry_run,
+                        explain,
+                        if_on,
+                        hue_saturation: match from_image {
+                            Some(path) => {
+                                spinner.update(spinners::Dots.into(), "Analyzing image...");
+                                let hsl = match palette::dominant_color(&path, region) {
+                                    Ok(hsl) => hsl,
+                                    Err(err) => Cli::command().error(ErrorKind::Io, err).exit()
+                                };
+                                spinner.update(spinners::Dots.into(), "Sending request...");
+                                Some(HueSaturation {
+                                    hue: Some(IntegerValueChange { absolute: true, value: hsl.hue().round() as i32 % 360 }),
+                                    saturation: Some(IntegerValueChange { absolute: true, value: hsl.saturation().round() as i32 })
+                                })
+                            },
+                            None => {
+                                let hue_saturation = HueSaturation { hue: hue_saturation.hue, saturation: hue_saturation.saturation };
+                                // the argument group should already require both values but a change with only one
+                                // of them would silently be dropped. The same validation as on the server is used
+                                if let Err(err) = validate_hue_saturation(&hue_saturation) {
+                                    Cli::command().error(ErrorKind::MissingRequiredArgument, err.code).exit()
+                                }
+                                (hue_saturation.hue.is_some() && hue_saturation.saturation.is_some()).then_some(hue_saturation)
                             }
-                        }
+                        },
+                        ..SetRequest::default()
                     };
 
-                    let state = client.set(request).await.map_tonic_err(&mut spinner, json).into_inner();
+                    let [device] = devices.as_slice() else {
+                        let requests = devices.iter().map(|device| SetRequest { device: device.clone(), ..request.clone() }).collect();
+                        let response = client.set_many(SetManyRequest { requests }).await.map_tonic_err(&mut spinner, json).into_inner();
+                        print_bulk(response, &mut spinner, json, "Updated devices:");
+                        return Ok(())
+                    };
+                    let mut state = client.set(SetRequest { device: device.clone(), ..request }).await.map_tonic_err(&mut spinner, json).into_inner();
+                    if confirm {
+                        spinner.update(spinners::Dots.into(), "Reading device state...");
+                        let explanation = state.explanation.take();
+                        state = client.info(DeviceRequest { device: state.name.clone(), fresh: true }).await.map_tonic_err(&mut spinner, json).into_inner();
+                        state.explanation = explanation;
+                    }
                     if json {
+                        if mireds { state.temperature = state.temperature.filter(|t| *t > 0).map(kelvin_to_mireds) }
                         println!("{}", json!(state))
                     } else {
-                        spinner.success("Updated device:");
-                        println!("{state}");
+                        spinner.success(if dry_run { "Computed device state:" } else { "Updated device:" });
+                        println!("{}", FormattedInfo { info: &state, mireds, color_fallback });
+                        if let Some(explanation) = &state.explanation {
+                            println!("\n{explanation}");
+                        }
                     }
                 }
-                ClientCommand::Info { device } => {
-                    if json {
-                        let json = client.info_json(DeviceRequest { device }).await.map_tonic_err(&mut spinner, json);
-                        let value: HashMap<String, Value> = serde_json::from_slice(json.into_inner().data.as_slice()).unwrap();
+                ClientCommand::Info { device, mireds, fresh, format } => {
+                    let device = device.unwrap_or_default();
+                    if format == InfoFormat::Env {
+                        let mut info = client.info(DeviceRequest { device, fresh }).await.map_tonic_err(&mut spinner, json).into_inner();
+                        if mireds { info.temperature = info.temperature.filter(|t| *t > 0).map(kelvin_to_mireds) }
+                        println!("{}", InfoAsEnv(&info));
+                    } else if json {
+                        let json = client.info_json(DeviceRequest { device, fresh }).await.map_tonic_err(&mut spinner, json);
+                        let mut value: HashMap<String, Value> = serde_json::from_slice(json.into_inner().data.as_slice()).unwrap();
+                        let raw = |key: &str| value.get(key).and_then(Value::as_u64).map(|v| v as u32);
+                        if let Some(hsl) = any_to_hsl(raw("color_temp").filter(|t| *t > 0), raw("hue"), raw("saturation"), raw("brightness")) {
+                            value.insert(String::from("hsl"), json!(hsl));
+                        }
+                        if mireds {
+                            // the raw device info contains the temperature in the `color_temp` field
+                            if let Some(kelvin) = value.get("color_temp").and_then(Value::as_u64).filter(|t| *t > 0) {
+                                value.insert(String::from("color_temp"), json!(kelvin_to_mireds(kelvin as u32)));
+                            }
+                        }
                         println!("{}", json!(value));
                     } else {
-                        let info = client.info(DeviceRequest { device }).await.map_tonic_err(&mut spinner, json).into_inner();
+                        let info = client.info(DeviceRequest { device, fresh }).await.map_tonic_err(&mut spinner, json).into_inner();
                         spinner.success("Device info:");
+                        println!("{}", FormattedInfo { info: &info, mireds, color_fallback });
+                    }
+                }
+                ClientCommand::Gradient { devices, from, to, timeout_per_device, parallel } => {
+                    let colors = palette::gradient(&from, &to, devices.len());
+                    spinner.update(spinners::Dots.into(), "Updating devices...");
+
+                    let results = fan_out(parallel, devices.into_iter().zip(colors).map(|(device, (hue, saturation))| {
+                        let mut client = client.clone();
+                        async move {
+                            let request = SetRequest {
+                                device: device.clone(),
+                                hue_saturation: Some(HueSaturation {
+                                    hue: Some(IntegerValueChange { absolute: true, value: hue as i32 }),
+                                    saturation: Some(IntegerValueChange { absolute: true, value: saturation as i32 })
+                                }),
+                                ..Default::default()
+                            };
+                            let error = with_device_timeout(timeout_per_device, client.set(request)).await.err();
+                            GradientResult { device, hue, saturation, error }
+                        }
+                    })).await;
+                    let succeeded = results.iter().all(|result| result.error.is_none());
+
+                    if json {
+                        println!("{}", json!(results))
+                    } else {
+                        if succeeded { spinner.success("Applied gradient:") }
+                        else { spinner.fail("Unable to update some devices:") }
+                        println!("{}", gradient_table(&results));
+                    }
+                    if !succeeded { exit(1) }
+                }
+                ClientCommand::InfoBatch { devices, fresh } => {
+                    let batch = client.info_batch(InfoBatchRequest { devices, fresh }).await.map_tonic_err(&mut spinner, json).into_inner();
+                    let succeeded = batch.results.iter().all(|result| result.info.is_some());
+                    if json {
+                        println!("{}", json!(batch))
+                    } else {
+                        if succeeded { spinner.success("Device infos:") }
+                        else { spinner.fail("Unable to fetch some devices:") }
+                        println!("{batch}");
+                    }
+                    if !succeeded { exit(1) }
+                }
+                ClientCommand::Status { device } => {
+                    let status = client.status(DeviceRequest { device, fresh: false }).await.map_tonic_err(&mut spinner, json).into_inner();
+                    if json {
+                        println!("{}", json!(status))
+                    } else {
+                        spinner.success("Device status:");
+                        println!("{status}");
+                    }
+                }
+                ClientCommand::Doctor { timeout_per_device, parallel } => {
+                    let devices = client.devices(Empty {}).await.map_tonic_err(&mut spinner, json).into_inner().devices;
+                    spinner.update(spinners::Dots.into(), "Checking devices...");
+
+                    let checks = fan_out(parallel, devices.into_iter().map(|device| {
+                        let mut client = client.clone();
+                        async move {
+                            match with_device_timeout(timeout_per_device, client.status(DeviceRequest { device: device.name.clone(), fresh: true })).await {
+                                Ok(response) => {
+                                    let status = response.into_inner();
+                                    DoctorCheck { device: status.device.unwrap_or(device), reachable: status.reachable, error: status.error }
+                                },
+                                Err(error) => DoctorCheck { device, reachable: false, error: Some(error) }
+                            }
+                        }
+                    })).await;
+                    let passed = checks.iter().all(DoctorCheck::passed);
+
+                    if json {
+                        let devices = checks.iter().map(|check| {
+                            let mut value = json!(check);
+                            value["passed"] = json!(check.passed());
+                            value
+                        }).collect::<Vec<_>>();
+                        println!("{}", json!({ "passed": passed, "devices": devices }))
+                    } else {
+                        if passed { spinner.success("All devices passed the checks") }
+                        else { spinner.fail("Some devices failed the checks") }
+                        println!("{}", doctor_table(&checks));
+                    }
+                    if !passed { exit(1) }
+                }
+                ClientCommand::Sensor { device, child } => {
+                    let sensor = client.sensor(SensorRequest { device, child }).await.map_tonic_err(&mut spinner, json).into_inner();
+                    if json {
+                        println!("{}", json!(sensor))
+                    } else {
+                        spinner.success("Sensor reading:");
+                        println!("{sensor}");
+                    }
+                }
+                ClientCommand::SetTemperature { device, celsius, frost_protection } => {
+                    let request = TargetTemperatureRequest { device, temperature: celsius, frost_protection };
+                    let info = client.set_target_temperature(request).await.map_tonic_err(&mut spinner, json).into_inner();
+                    if json {
+                        println!("{}", json!(info))
+                    } else {
+                        spinner.success("Device state:");
                         println!("{info}");
                     }
                 }
-                ClientCommand::Usage { device } => {
-                    let usage = client.usage(DeviceRequest { device }).await.map_tonic_err(&mut spinner, json).into_inner();
+                ClientCommand::Usage { device, summary } => {
+                    let usage = client.usage(DeviceRequest { device, fresh: false }).await.map_tonic_err(&mut spinner, json).into_inner();
                     if json {
-                        println!("{}", json!(usage))
+                        let mut value = json!(usage);
+                        if let Some(cost) = cost_today(&usage, price_per_kwh) {
+                            value["cost_today"] = json!(cost);
+                        }
+                        println!("{value}")
                     } else {
                         spinner.success("Device usage:");
                         println!("{usage}");
+                        if summary {
+                            println!("\n{}", UsageSummary { usage: &usage, price_per_kwh });
+                        }
                     }
                 }
-                ClientCommand::On { device } => {
-                    let result = client.on(DeviceRequest { device: device.clone() }).await.map_tonic_err(&mut spinner, json).into_inner();
+                ClientCommand::On { devices, .. } => {
+                    let [device] = devices.as_slice() else {
+                        let request = PowerManyRequest { devices, power: true, instant: false };
+                        let response = client.power_many(request).await.map_tonic_err(&mut spinner, json).into_inner();
+                        print_bulk(response, &mut spinner, json, "Turned on devices:");
+                        return Ok(())
+                    };
+                    let result = client.on(DeviceRequest { device: device.clone(), fresh: false }).await.map_tonic_err(&mut spinner, json).into_inner();
                     if json {
                         println!("{}", json!(result))
                     } else {
                         spinner.success(format!("Device '{device}' is now turned on").as_str())
                     }
                 }
-                ClientCommand::Off { device } => {
-                    let result = client.off(DeviceRequest { device: device.clone() }).await.map_tonic_err(&mut spinner, json).into_inner();
+                ClientCommand::Toggle { device } => {
+                    let device = device.unwrap_or_default();
+                    let result = client.toggle(DeviceRequest { device: device.clone(), fresh: false }).await.map_tonic_err(&mut spinner, json).into_inner();
+                    if json {
+                        println!("{}", json!(result))
+                    } else {
+                        let state = if result.device_on { "on" } else { "off" };
+                        spinner.success(format!("Device '{device}' is now turned {state}").as_str())
+                    }
+                }
+                ClientCommand::Off { devices, instant, .. } => {
+                    let [device] = devices.as_slice() else {
+                        let request = PowerManyRequest { devices, power: false, instant };
+                        let response = client.power_many(request).await.map_tonic_err(&mut spinner, json).into_inner();
+                        print_bulk(response, &mut spinner, json, "Turned off devices:");
+                        return Ok(())
+                    };
+                    let result = client.off(OffRequest { device: device.clone(), instant }).await.map_tonic_err(&mut spinner, json).into_inner();
                     if json {
                         println!("{}", json!(result))
                     } else {
                         spinner.success(format!("Device '{device}' is now turned off").as_str())
                     }
                 }
-                ClientCommand::Reset { device } => {
-                    client.reset(DeviceRequest { device }).await.map_tonic_err(&mut spinner, json);
+                ClientCommand::Sleep { device, duration } => {
+                    let request = SleepRequest { device: device.clone(), delay_ms: duration.as_millis() as u64 };
+                    client.sleep(request).await.map_tonic_err(&mut spinner, json);
+                    if json {
+                        println!("{}", json!({ "success": true }))
+                    } else {
+                        spinner.success(format!("Device '{device}' will be turned off in {}", format_duration(duration)).as_str())
+                    }
+                }
+                ClientCommand::Reset { device, blink: true } => {
+                    if json {
+                        println!("{}", json!({ "code": "Confirmation required", "message": "Blink confirmation isn't supported with json output" }));
+                        exit(1)
+                    }
+                    let token = client.reset_prepare(DeviceRequest { device: device.clone(), fresh: false }).await.map_tonic_err(&mut spinner, json).into_inner();
+                    spinner.clear();
+
+                    let seconds = token.expires_in_ms / 1000;
+                    if !confirm(format!("Device '{device}' is blinking now. Reset it to factory defaults? Expires in {seconds}s").as_str()) {
+                        println!("Aborted reset");
+                        return Ok(());
+                    }
+
+                    spinner = Some(Spinner::new(spinners::Dots, "Sending request...", None));
+                    client.reset_confirm(ResetConfirmRequest { device, token: token.token }).await.map_tonic_err(&mut spinner, json);
+                    spinner.success("Restored factory defaults")
+                }
+                ClientCommand::Reset { device, blink: false } => {
+                    client.reset(DeviceRequest { device, fresh: false }).await.map_tonic_err(&mut spinner, json);
                     if json {
                         println!("{}", json!({ "success": true }))
                     } else {
                         spinner.success("Restored factory defaults")
                     }
                 }
-                ClientCommand::Events { types } => {
-                    let request = EventRequest { types: types.into_iter().map(i32::from).collect() };
-                    let mut events  = client.events(request).await.map_tonic_err(&mut spinner, json).into_inner();
-                    spinner.success("Subscribed to events");
+                ClientCommand::Reboot { device } => {
+                    client.reboot(DeviceRequest { device: device.clone(), fresh: false }).await.map_tonic_err(&mut spinner, json);
+                    if json {
+                        println!("{}", json!({ "success": true }))
+                    } else {
+                        spinner.success(format!("Device '{device}' is rebooting").as_str())
+                    }
+                }
+                ClientCommand::Bench { device, count } => {
+                    let mut latencies = Vec::with_capacity(count as usize);
+                    for i in 0..count {
+                        spinner.update(spinners::Dots.into(), format!("Sending info request {}/{count}...", i + 1).as_str());
+                        let start = Instant::now();
+                        client.info(DeviceRequest { device: device.clone(), fresh: false }).await.map_tonic_err(&mut spinner, json);
+                        latencies.push(start.elapsed());
+                    }
+                    latencies.sort();
 
+                    let to_millis = |duration: Duration| duration.as_secs_f64() * 1000f64;
+                    let min = to_millis(latencies[0]);
+                    let max = to_millis(latencies[latencies.len() - 1]);
+                    let avg = to_millis(latencies.iter().sum::<Duration>() / count);
+                    let p95 = to_millis(latencies[((latencies.len() as f64 * 0.95).ceil() as usize).saturating_sub(1)]);
 
-                    while let Ok(Some(event)) = events.message().await {
-                        if json {
-                            let event_type = EventType::try_from(event.r#type).unwrap_or_default().as_str_name();
-                            let body: HashMap<String, Value> = serde_json::from_slice(event.body.as_slice()).unwrap();
-                            println!("{}", json!({ "type": event_type, "body": body }));
-                            continue
+                    if json {
+                        println!("{}", json!({ "count": count, "min": min, "avg": avg, "p95": p95, "max": max }))
+                    } else {
+                        spinner.success(format!("Sent {count} info requests to device '{device}':").as_str());
+                        println!("{}: {min:.1}ms", "Min".bold());
+                        println!("{}: {avg:.1}ms", "Avg".bold());
+                        println!("{}: {p95:.1}ms", "P95".bold());
+                        println!("{}: {max:.1}ms", "Max".bold());
+                    }
+                }
+                ClientCommand::Audit { device } => {
+                    let audit = client.audit(DeviceRequest { device, fresh: false }).await.map_tonic_err(&mut spinner, json).into_inner();
+                    if json {
+                        println!("{}", json!(audit))
+                    } else if audit.entries.is_empty() {
+                        spinner.success("No commands recorded")
+                    } else {
+                        spinner.success("Recorded commands:");
+                        println!("{audit}");
+                    }
+                }
+                ClientCommand::Stats { device } => {
+                    let stats = client.stats(DeviceRequest { device, fresh: false }).await.map_tonic_err(&mut spinner, json).into_inner();
+                    if json {
+                        println!("{}", json!(stats))
+                    } else if stats.methods.is_empty() {
+                        spinner.success("No requests recorded")
+                    } else {
+                        spinner.success("Request metrics:");
+                        println!("{stats}");
+                    }
+                }
+                ClientCommand::Firmware { device } => {
+                    let firmware = client.firmware_info(DeviceRequest { device, fresh: false }).await.map_tonic_err(&mut spinner, json).into_inner();
+                    if json { println!("{}", json!(firmware)) }
+                    else {
+                        spinner.success("Received firmware info:");
+                        println!("{firmware}");
+                    }
+                }
+                ClientCommand::Version => {
+                    let version = client.version(Empty {}).await.map_tonic_err(&mut spinner, json).into_inner();
+                    if json {
+                        println!("{}", json!({ "client": env!("CARGO_PKG_VERSION"), "server": version }))
+                    } else {
+                        spinner.success(format!("Client version: {}", env!("CARGO_PKG_VERSION")).as_str());
+                        println!("{version}");
+                    }
+                }
+                ClientCommand::Logs => {
+                    let mut logs = client.logs(Empty {}).await.map_tonic_err(&mut spinner, json).into_inner();
+                    spinner.success("Subscribed to server logs");
+
+                    while let Ok(Some(record)) = logs.message().await {
+                        if json { println!("{}", json!(record)) }
+                        else { println!("{record}") }
+                    }
+
+                    if !json {
+                        println!("Finished subscription. Stream closed!")
+                    }
+                }
+                ClientCommand::Cancel { device } => {
+                    let result = client.cancel_operation(DeviceRequest { device: device.clone(), fresh: false }).await.map_tonic_err(&mut spinner, json).into_inner();
+                    if json {
+                        println!("{}", json!(result))
+                    } else if result.cancelled {
+                        spinner.success(format!("Cancelled running operation on device '{device}'").as_str())
+                    } else {
+                        spinner.success(format!("No operation is running on device '{device}'").as_str())
+                    }
+                }
+                ClientCommand::Replay { file, speed } => {
+                    let steps = match replay::load(&file, speed) {
+                        Ok(steps) => steps,
+                        Err(err) => Cli::command().error(ErrorKind::Io, err).exit()
+                    };
+
+                    let total = steps.len();
+                    let mut failed = 0;
+                    for (index, step) in steps.into_iter().enumerate() {
+                        tokio::time::sleep(step.delay).await;
+                        let device = step.request.device.clone();
+                        spinner.update(spinners::Dots.into(), format!("Replaying event {}/{total} on device '{device}'...", index + 1).as_str());
+                        // a single device which can't be updated shouldn't stop the rest of the replay
+                        if let Err(status) = client.set(step.request).await {
+                            warn!("Unable to replay event {} on device '{device}': {}", index + 1, status.message());
+                            failed += 1;
                         }
-                        match event.r#type.try_into() {
-                            Ok(EventType::DeviceStateChange) => {
-                                let body: InfoResponse = serde_json::from_slice(event.body.as_slice()).unwrap();
-                                println!("{}\n{body}\n", format!("Device '{}' changed:", body.name).bold().underline());
-                            },
-                            Ok(EventType::DeviceAuthChange) => {
-                                let body: Device = serde_json::from_slice(event.body.as_slice()).unwrap();
-                                println!("{}\n{body}\n", format!("Auth changed for device '{}':", body.name).bold().underline());
+                    }
+
+                    if json {
+                        println!("{}", json!({ "replayed": total - failed, "failed": failed }))
+                    } else if failed == 0 {
+                        spinner.success(format!("Replayed {total} events").as_str())
+                    } else {
+                        spinner.fail(format!("Replayed {} of {total} events", total - failed).as_str())
+                    }
+                    if failed > 0 { exit(1) }
+                }
+                ClientCommand::ExportState { file, devices, fresh } => {
+                    let devices = if devices.is_empty() {
+                        let registered = client.devices(Empty {}).await.map_tonic_err(&mut spinner, json).into_inner();
+                        registered.devices.into_iter().map(|device| device.name).collect()
+                    } else { devices };
+
+                    let batch = client.info_batch(InfoBatchRequest { devices, fresh }).await.map_tonic_err(&mut spinner, json).into_inner();
+                    let mut failed = vec![];
+                    let mut states = vec![];
+                    for result in batch.results {
+                        match result.info {
+                            Some(info) => states.push(info),
+                            None => {
+                                let outcome = result.result.unwrap_or_default();
+                                warn!("Unable to export state of device '{}': {}", outcome.name, outcome.error_message.unwrap_or_default());
+                                failed.push(outcome.name);
                             }
-                            Err(err) => {
-                                println!("Error whilst decoding event type: {err}")
+                        }
+                    }
+
+                    let exported = states.len();
+                    if let Err(err) = snapshot::StateSnapshot::new(states).write(&file) {
+                        Cli::command().error(ErrorKind::Io, err).exit()
+                    }
+
+                    if json {
+                        println!("{}", json!({ "exported": exported, "failed": failed }))
+                    } else if failed.is_empty() {
+                        spinner.success(format!("Exported state of {exported} devices to {}", file.display()).as_str())
+                    } else {
+                        spinner.fail(format!("Exported state of {exported} devices to {}. Unable to export {}", file.display(), failed.join(", ")).as_str())
+                    }
+                    if !failed.is_empty() { exit(1) }
+                }
+                ClientCommand::ImportState { file, devices } => {
+                    let snapshot = match snapshot::StateSnapshot::read(&file) {
+                        Ok(snapshot) => snapshot,
+                        Err(err) => Cli::command().error(ErrorKind::Io, err).exit()
+                    };
+
+                    let states = snapshot.devices.into_iter()
+                        .filter(|info| devices.is_empty() || devices.contains(&info.name))
+                        .collect::<Vec<_>>();
+                    let total = states.len();
+                    let mut failed = vec![];
+                    for info in states {
+                        let device = info.name.clone();
+                        spinner.update(spinners::Dots.into(), format!("Importing state of device '{device}'...").as_str());
+                        // a single device which can't be updated shouldn't stop the rest of the import
+                        if let Err(status) = client.set(replay::set_request(info)).await {
+                            warn!("Unable to import state of device '{device}': {}", status.message());
+                            failed.push(device);
+                        }
+                    }
+
+                    if json {
+                        println!("{}", json!({ "imported": total - failed.len(), "failed": failed }))
+                    } else if failed.is_empty() {
+                        spinner.success(format!("Imported state of {total} devices").as_str())
+                    } else {
+                        spinner.fail(format!("Imported state of {} of {total} devices. Unable to import {}", total - failed.len(), failed.join(", ")).as_str())
+                    }
+                    if !failed.is_empty() { exit(1) }
+                }
+                ClientCommand::Events { types, strict_json, count, replay } => {
+                    let request = EventRequest { types: types.into_iter().map(i32::from).collect(), replay_count: replay };
+                    let mut events  = client.events(request).await.map_tonic_err(&mut spinner, json).into_inner();
+                    spinner.success("Subscribed to events");
+
+                    let mut remaining = count;
+                    while let Ok(Some(event)) = events.message().await {
+                        if let Err(err) = print_event(&event, json) {
+                            if strict_json {
+                                if json { println!("{}", json!({ "code": "Unable to decode event", "message": err })) }
+                                else { error!("Unable to decode event: {err}") }
+                                exit(1)
                             }
+                            warn!("Skipping event which couldn't be decoded: {err}");
+                            continue;
+                        }
+
+                        // a count of zero keeps the subscription open forever
+                        if count > 0 {
+                            remaining -= 1;
+                            if remaining == 0 { break; }
                         }
                     }
 
@@ -210,24 +721,172 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn get_client(config: Option<ClientConfig>, spinner: &mut Option<Spinner>, json: bool) -> TapoClient<Channel> {
-    let (secure, host, port) = match config {
-        Some(config) => (config.secure, config.address.clone(), config.port),
-        None => (false, String::from("127.0.0.1"), 19191)
+/// Print a single event of the event stream
+///
+/// Returns an error should the event type be unknown or the body not match the event type
+fn print_event(event: &EventResponse, json: bool) -> Result<(), String> {
+    let event_type = EventType::try_from(event.r#type).map_err(|_| format!("Unknown event type {}", event.r#type))?;
+    let body = event.body.as_slice();
+
+    if json {
+        let body: HashMap<String, Value> = serde_json::from_slice(body).map_err(|err| err.to_string())?;
+        // the receive time allows the printed events to be replayed with their original timing
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        println!("{}", json!({ "type": event_type.as_str_name(), "time": time, "body": body }));
+        return Ok(())
+    }
+
+    match event_type {
+        EventType::DeviceStateChange => {
+            let body: InfoResponse = serde_json::from_slice(body).map_err(|err| err.to_string())?;
+            println!("{}\n{body}\n", format!("Device '{}' changed:", body.name).bold().underline());
+        },
+        EventType::DeviceAuthChange => {
+            let body: Device = serde_json::from_slice(body).map_err(|err| err.to_string())?;
+            println!("{}\n{body}\n", format!("Auth changed for device '{}':", body.name).bold().underline());
+        }
+        EventType::OperationProgress => {
+            let body: Operation = serde_json::from_slice(body).map_err(|err| err.to_string())?;
+            println!("{}\n{body}\n", format!("Operation on device '{}':", body.device).bold().underline());
+        }
+        EventType::SafetyCutoff => {
+            let body: SafetyCutoffEvent = serde_json::from_slice(body).map_err(|err| err.to_string())?;
+            println!("{}\n{body}\n", format!("Safety cutoff of device '{}':", body.device).bold().underline());
+        }
+        EventType::EnergyUpdate => {
+            let body: CurrentPowerResponse = serde_json::from_slice(body).map_err(|err| err.to_string())?;
+            println!("{}\n{body}\n", format!("Energy update for device '{}':", body.device).bold().underline());
+        }
+    }
+    Ok(())
+}
+
+/// Print the per-device results of a request which targets multiple devices and exit with an error should any of them have failed
+fn print_bulk(response: BulkResponse, spinner: &mut Option<Spinner>, json: bool, success: &str) {
+    let succeeded = response.results.iter().all(|result| result.ok);
+    if json {
+        println!("{}", json!(response))
+    } else {
+        if succeeded { spinner.success(success) }
+        else { spinner.fail("Unable to update some devices:") }
+        println!("{response}");
+    }
+    if !succeeded { exit(1) }
+}
+
+/// Format a duration in hours, minutes and seconds (e.g. `1h 30m`)
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    [(seconds / 3600, "h"), (seconds / 60 % 60, "m"), (seconds % 60, "s")]
+        .into_iter()
+        .filter(|(value, _)| *value > 0)
+        .map(|(value, unit)| format!("{value}{unit}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Await the request for a single device of a fan-out command
+///
+/// A request which takes longer than the timeout fails like any other request so a stuck device
+/// doesn't hold up the whole command. Returns the message of the error should the request fail
+async fn with_device_timeout<R>(timeout: Option<Duration>, request: impl Future<Output = Result<R, tonic::Status>>) -> Result<R, String> {
+    let result = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, request).await
+            .map_err(|_| format!("No response within {}s", timeout.as_secs_f64()))?,
+        None => request.await
+    };
+    result.map_err(|status| status.message().to_string())
+}
+
+/// Send the requests of a client-side fan-out with at most `parallel` requests in flight
+///
+/// The results are returned in the order of the requests regardless of which request finished first
+async fn fan_out<R>(parallel: u16, requests: impl Iterator<Item = impl Future<Output = R>>) -> Vec<R> {
+    let mut results = futures::stream::iter(requests.enumerate().map(|(index, request)| async move { (index, request.await) }))
+        .buffer_unordered(parallel as usize)
+        .collect::<Vec<_>>().await;
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Ask the user for a yes/no confirmation on stdin
+fn confirm(message: &str) -> bool {
+    print!("{message} [y/N] ");
+    if std::io::stdout().flush().is_err() {
+        return false;
+    }
+
+    let mut answer = String::new();
+    match std::io::stdin().read_line(&mut answer) {
+        Ok(_) => matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"),
+        Err(_) => false
+    }
+}
+
+/// Parse a full server url (e.g. `https://host:1234`) into its secure, host and port parts
+///
+/// The `default_port` is used should the url not contain a port
+fn parse_address_url(address: &str, default_port: u16) -> Result<(bool, String, u16), String> {
+    let uri = address.parse::<Uri>().map_err(|err| err.to_string())?;
+    let secure = match uri.scheme_str() {
+        Some("https") => true,
+        Some("http") => false,
+        Some(scheme) => Err(format!("Unsupported scheme '{scheme}'. Use either http or https"))?,
+        None => Err("Missing scheme")?
+    };
+    let host = uri.host().filter(|host| !host.is_empty()).ok_or("Missing host")?.to_string();
+    let port = uri.port_u16().unwrap_or(default_port);
+    Ok((secure, host, port))
+}
+
+async fn get_client(config: Option<ClientConfig>, env_prefix: &str, retries: u32, spinner: &mut Option<Spinner>, json: bool) -> TapoClient<Channel> {
+    let (secure, host, port, compression) = match config {
+        Some(config) => (config.secure, config.address.clone(), config.port, config.compression),
+        None => (false, String::from("127.0.0.1"), 19191, true)
     };
 
-    let secure = std::env::var("TAPO_SECURE").is_ok() || secure;
-    let host = std::env::var("TAPO_HOST").unwrap_or(host);
-    let port = std::env::var("TAPO_PORT").map(|p| u16::from_str(p.as_str()).unwrap_or(port)).unwrap_or(port);
+    let secure = std::env::var(format!("{env_prefix}_SECURE")).is_ok() || secure;
+    let host = std::env::var(format!("{env_prefix}_HOST")).unwrap_or(host);
+    let port = std::env::var(format!("{env_prefix}_PORT")).map(|p| u16::from_str(p.as_str()).unwrap_or(port)).unwrap_or(port);
+
+    // a url-shaped address overrides the individual secure and port options
+    let (secure, host, port) = if host.contains("://") {
+        parse_address_url(&host, port).unwrap_or_else(|err| {
+            if json {
+                println!("{}", json!({ "code": "Invalid server address", "message": err }))
+            } else {
+                spinner.fail(format!("Invalid server address '{host}': {err}").as_str());
+            }
+            exit(1)
+        })
+    } else { (secure, host, port) };
     let protocol = if secure { "https" } else { "http" };
 
     let format = format!("{protocol}://{host}:{port}");
-    TapoClient::connect(format.clone()).await.unwrap_or_else(|err| {
-        if json {
-            println!("{}", json!({ "code": "Unable to connect to grpc server", "message": err.to_string() }))
-        } else {
-            spinner.fail(format!("Unable to connect to server at {format}. Is it up and running?").as_str());
+    let mut attempt = 0;
+    let client = loop {
+        match TapoClient::connect(format.clone()).await {
+            Ok(client) => break client,
+            Err(_) if attempt < retries => {
+                // the delay doubles with every attempt but is capped to not wait needlessly long for a server which is up again
+                let delay = CONNECT_RETRY_DELAY.saturating_mul(2u32.saturating_pow(attempt)).min(MAX_CONNECT_RETRY_DELAY);
+                attempt += 1;
+                spinner.update(spinners::Dots.into(), format!("Unable to connect to server, retrying in {}s ({attempt}/{retries})...", delay.as_secs_f64()).as_str());
+                tokio::time::sleep(delay).await;
+                spinner.update(spinners::Dots.into(), "Preparing client...");
+            },
+            Err(err) => {
+                if json {
+                    println!("{}", json!({ "code": "Unable to connect to grpc server", "message": err.to_string() }))
+                } else {
+                    spinner.fail(format!("Unable to connect to server at {format}. Is it up and running?").as_str());
+                }
+                exit(1)
+            }
         }
-        exit(1)
-    })
+    };
+
+    // requests are small enough to not benefit from compression. Only the responses (e.g. the raw
+    // json of the `info_json` endpoint) are requested to be compressed
+    if compression { client.accept_compressed(CompressionEncoding::Gzip) } else { client }
 }
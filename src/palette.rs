@@ -0,0 +1,96 @@
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use colorsys::{Hsl, Rgb};
+
+const SAMPLE_SIZE: u32 = 64; // images are downscaled to at most 64x64 pixels before clustering
+const CLUSTERS: usize = 5;
+const ITERATIONS: usize = 10;
+const MIN_ALPHA: u8 = 128; // mostly transparent pixels don't contribute to the dominant color
+
+/// Rectangular region of an image in pixels
+#[derive(Clone, Copy, Debug)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32
+}
+
+impl Display for Region {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{},{},{},{}", self.x, self.y, self.width, self.height)
+    }
+}
+
+/// Get the dominant color of an image or a region of it
+///
+/// The pixels are clustered with k-means and the center of the largest cluster is taken as dominant color
+/// which unlike the average doesn't mix distinct colors into a muddy one
+pub fn dominant_color(path: &Path, region: Option<Region>) -> Result<Hsl, String> {
+    let mut image = image::open(path).map_err(|err| format!("Unable to load image '{}': {err}", path.display()))?;
+    if let Some(region) = region {
+        if region.x.saturating_add(region.width) > image.width() || region.y.saturating_add(region.height) > image.height() {
+            Err(format!("Region '{region}' is outside of the {}x{} image", image.width(), image.height()))?;
+        }
+        image = image.crop_imm(region.x, region.y, region.width, region.height);
+    }
+
+    let pixels = image.thumbnail(SAMPLE_SIZE, SAMPLE_SIZE).to_rgba8().pixels()
+        .filter(|pixel| pixel[3] >= MIN_ALPHA)
+        .map(|pixel| [pixel[0] as f64, pixel[1] as f64, pixel[2] as f64])
+        .collect::<Vec<_>>();
+    if pixels.is_empty() {
+        Err(format!("Image '{}' doesn't contain any opaque pixels", path.display()))?;
+    }
+
+    let [red, green, blue] = largest_cluster(&pixels);
+    Ok(Hsl::from(Rgb::new(red, green, blue, None)))
+}
+
+/// Cluster the pixels with k-means and get the center of the largest cluster
+fn largest_cluster(pixels: &[[f64; 3]]) -> [f64; 3] {
+    // the initial centers are spread evenly over the pixels to get the same result on every run
+    let mut centers = (0..CLUSTERS).map(|index| pixels[index * pixels.len() / CLUSTERS]).collect::<Vec<_>>();
+    let mut counts = vec![0usize; CLUSTERS];
+
+    for _ in 0..ITERATIONS {
+        let mut sums = vec![[0f64; 3]; CLUSTERS];
+        counts = vec![0usize; CLUSTERS];
+        for pixel in pixels {
+            let nearest = nearest_center(&centers, pixel);
+            counts[nearest] += 1;
+            sums[nearest].iter_mut().zip(pixel).for_each(|(sum, value)| *sum += value);
+        }
+        for ((center, sum), count) in centers.iter_mut().zip(sums).zip(&counts) {
+            if *count > 0 {
+                *center = sum.map(|sum| sum / *count as f64);
+            }
+        }
+    }
+
+    let largest = counts.iter().enumerate().max_by_key(|(_, count)| **count).map_or(0, |(index, _)| index);
+    centers[largest]
+}
+
+/// Get the index of the center which is closest to the pixel
+fn nearest_center(centers: &[[f64; 3]], pixel: &[f64; 3]) -> usize {
+    let distance = |center: &[f64; 3]| center.iter().zip(pixel).map(|(a, b)| (a - b).powi(2)).sum::<f64>();
+    centers.iter().enumerate()
+        .min_by(|(_, a), (_, b)| distance(a).total_cmp(&distance(b)))
+        .map_or(0, |(index, _)| index)
+}
+
+/// Interpolate `count` hues and saturations evenly between two colors
+///
+/// The hue takes the shorter way around the color wheel and is returned in the device range of 1 to 360
+pub fn gradient(from: &Hsl, to: &Hsl, count: usize) -> Vec<(u16, u8)> {
+    let mut delta = (to.hue() - from.hue()).rem_euclid(360f64);
+    if delta > 180f64 { delta -= 360f64 }
+
+    (0..count).map(|index| {
+        let position = if count > 1 { index as f64 / (count - 1) as f64 } else { 0f64 };
+        let hue = (from.hue() + delta * position).rem_euclid(360f64).round() as u16;
+        let saturation = from.saturation() + (to.saturation() - from.saturation()) * position;
+        (if hue == 0 { 360 } else { hue }, saturation.round().clamp(0f64, 100f64) as u8)
+    }).collect()
+}
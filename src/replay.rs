@@ -0,0 +1,75 @@
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use serde::Deserialize;
+use crate::tapo::server::rpc::{EventType, HueSaturation, InfoResponse, IntegerValueChange, SetRequest};
+
+/// Single event of an event log as printed by `events --json`
+#[derive(Deserialize)]
+struct RecordedEvent {
+    r#type: String,
+    /// Unix time in milliseconds at which the event was received
+    time: Option<u64>,
+    body: serde_json::Value
+}
+
+/// Recorded device state change together with the delay after the previous one
+pub struct ReplayStep {
+    pub delay: Duration,
+    pub request: SetRequest
+}
+
+/// Read the device state changes of an event log
+///
+/// Other event types are skipped. The delays between the state changes are scaled down by the `speed`
+/// and events without a receive time are applied right after the previous one
+pub fn load(path: &Path, speed: f64) -> Result<Vec<ReplayStep>, String> {
+    let content = fs::read_to_string(path).map_err(|err| format!("Unable to read event log '{}': {err}", path.display()))?;
+
+    let mut steps = vec![];
+    let mut previous_time = None;
+    for (index, line) in content.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        let event: RecordedEvent = serde_json::from_str(line)
+            .map_err(|err| format!("Invalid event in line {}: {err}", index + 1))?;
+        if event.r#type != EventType::DeviceStateChange.as_str_name() { continue }
+
+        let info: InfoResponse = serde_json::from_value(event.body)
+            .map_err(|err| format!("Invalid device state in line {}: {err}", index + 1))?;
+        let delay = match (previous_time, event.time) {
+            (Some(previous), Some(time)) => Duration::from_millis(time.saturating_sub(previous)).div_f64(speed),
+            _ => Duration::ZERO
+        };
+        previous_time = event.time.or(previous_time);
+
+        steps.push(ReplayStep { delay, request: set_request(info) });
+    }
+    Ok(steps)
+}
+
+/// Map a recorded device state back to the request which sets it
+///
+/// The temperature takes precedence over the hue and saturation since the device only reports
+/// a temperature when no color is set
+pub fn set_request(info: InfoResponse) -> SetRequest {
+    let absolute = |value: u32| IntegerValueChange { absolute: true, value: value as i32 };
+
+    // turning the device off together with other properties would turn it on again
+    if info.device_on == Some(false) {
+        return SetRequest { device: info.name, power: Some(false), ..SetRequest::default() }
+    }
+
+    let temperature = info.temperature.filter(|temperature| *temperature > 0);
+    let hue_saturation = match (temperature, info.hue, info.saturation) {
+        (None, Some(hue), Some(saturation)) => Some(HueSaturation { hue: Some(absolute(hue)), saturation: Some(absolute(saturation)) }),
+        _ => None
+    };
+
+    SetRequest {
+        device: info.name,
+        power: info.device_on,
+        brightness: info.brightness.map(absolute),
+        temperature: temperature.map(absolute),
+        hue_saturation,
+        ..SetRequest::default()
+    }
+}
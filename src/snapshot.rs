@@ -0,0 +1,30 @@
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use crate::tapo::server::rpc::InfoResponse;
+
+/// Point-in-time capture of the live device states as written by `export-state`
+#[derive(Serialize, Deserialize)]
+pub struct StateSnapshot {
+    /// Unix time in milliseconds at which the states were captured
+    pub time: u64,
+    pub devices: Vec<InfoResponse>
+}
+
+impl StateSnapshot {
+    pub fn new(devices: Vec<InfoResponse>) -> Self {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        Self { time, devices }
+    }
+
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let content = fs::read_to_string(path).map_err(|err| format!("Unable to read state file '{}': {err}", path.display()))?;
+        serde_json::from_str(&content).map_err(|err| format!("Invalid state file '{}': {err}", path.display()))
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), String> {
+        let content = serde_json::to_string_pretty(self).map_err(|err| format!("Unable to serialize device states: {err}"))?;
+        fs::write(path, content).map_err(|err| format!("Unable to write state file '{}': {err}", path.display()))
+    }
+}
@@ -0,0 +1,39 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::Serialize;
+use crate::tapo::server::rpc::AuditEntry;
+
+const AUDIT_LOG_SIZE: usize = 25; // keep the 25 most recent commands per device
+
+/// In-memory log of the most recent commands which changed a device
+#[derive(Default)]
+pub struct AuditLog {
+    entries: HashMap<String, VecDeque<AuditEntry>>
+}
+
+impl AuditLog {
+    /// Record a command which changed a device
+    ///
+    /// Should the log of the device be full the oldest entry is dropped
+    pub fn record(&mut self, device: &str, command: &str, parameters: impl Serialize, client: Option<SocketAddr>) {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        let entry = AuditEntry {
+            command: command.to_string(),
+            parameters: serde_json::to_string(&parameters).unwrap_or_default(),
+            timestamp,
+            client: client.map(|addr| addr.to_string())
+        };
+
+        let entries = self.entries.entry(device.to_string()).or_default();
+        if entries.len() == AUDIT_LOG_SIZE {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// Get all recorded entries of a device ordered from oldest to newest
+    pub fn get(&self, device: &str) -> Vec<AuditEntry> {
+        self.entries.get(device).map(|entries| entries.iter().cloned().collect()).unwrap_or_default()
+    }
+}
@@ -1,5 +1,5 @@
 use colorsys::Hsl;
-use crate::tapo::server::rpc::Rgb;
+use crate::tapo::server::rpc::{Hsl as RpcHsl, Rgb};
 
 use super::{server::rpc::Color as RpcColor, TapoRpcColorExt};
 
@@ -12,6 +12,16 @@ pub fn any_to_rgb(temperature: Option<u32>, hue: Option<u32>, saturation: Option
     } else { temperature.map(kelvin_to_rgb) }
 }
 
+/// Convert either a kelvin temperature or a hsl value to a normalized hsl value
+///
+/// The hsl value is derived from the result of [`any_to_rgb`] to be consistent with the rgb color
+pub fn any_to_hsl(temperature: Option<u32>, hue: Option<u32>, saturation: Option<u32>, brightness: Option<u32>) -> Option<RpcHsl> {
+    any_to_rgb(temperature, hue, saturation, brightness).map(|rgb| {
+        let hsl = Hsl::from(colorsys::Rgb::new(rgb.red as f64, rgb.green as f64, rgb.blue as f64, None));
+        RpcHsl { hue: hsl.hue().round() as u32 % 360, saturation: hsl.saturation().round() as u32, lightness: hsl.lightness().round() as u32 }
+    })
+}
+
 /// Convert a kelvin temperature value to an approximated rgb value
 ///
 /// https://github.com/spacekookie/colortemp/blob/ed421d6e928d4ed394be241f511661d588142766/src/lib.rs#L51
@@ -1,12 +1,18 @@
-use tapo::ColorLightHandler;
+use log::{debug, error, warn};
+use tapo::{ColorLightHandler, RgbLightStripHandler};
+use tapo::responses::{DeviceInfoColorLightResult, DeviceInfoRgbLightStripResult};
 
-use crate::{device::Device, tapo::{color::any_to_rgb, server::rpc::{InfoJsonResponse, InfoResponse, PowerResponse, UsagePerPeriod, UsageResponse}, TapoDeviceHandlerExt, TapoErrMap}};
+use crate::{config::SupportedDevice, device::Device, tapo::{color::{any_to_hsl, any_to_rgb}, server::rpc::{CurrentPowerResponse, FirmwareResponse, InfoJsonResponse, InfoResponse, PowerResponse, UsagePerPeriod, UsageResponse}, AppliedFields, TapoDeviceHandlerExt, REBOOT_DELAY_SECS, TapoErrMap, UpdateError}};
 
 impl TapoDeviceHandlerExt for ColorLightHandler {
     async fn reset(&self, device: &Device) -> Result<(), tonic::Status> {
         self.device_reset().await.map_tapo_err(device).await
     }
 
+    async fn reboot(&self, device: &Device) -> Result<(), tonic::Status> {
+        self.device_reboot(REBOOT_DELAY_SECS).await.map_tapo_err(device).await
+    }
+
     async fn get_info(&self, device: &Device) -> Result<crate::tapo::server::rpc::InfoResponse, tonic::Status> {
         let info = self.get_device_info().await.map_tapo_err(device).await?;
         let brightness = Some(info.brightness as u32);
@@ -20,10 +26,16 @@ impl TapoDeviceHandlerExt for ColorLightHandler {
             temperature,
             device_on: Some(info.device_on),
             on_time: info.on_time,
+            dynamic_effect_name: info.dynamic_light_effect_id.as_deref().map(|id| dynamic_effect_name(&device.device_type, id)),
             dynamic_effect_id: info.dynamic_light_effect_id,
             overheated: info.overheated,
             name: device.name.clone(),
             color: any_to_rgb(temperature, hue, saturation, brightness),
+            hsl: any_to_hsl(temperature, hue, saturation, brightness),
+            explanation: None,
+            rssi: Some(info.rssi as i32),
+            signal_level: Some(info.signal_level as u32),
+            ..InfoResponse::default()
         })
     }
 
@@ -63,6 +75,18 @@ impl TapoDeviceHandlerExt for ColorLightHandler {
         })
     }
 
+    async fn get_current_power(&self, _device: &Device) -> Result<CurrentPowerResponse, tonic::Status> {
+        Err(tonic::Status::unimplemented("Current power API is not supported by this device type"))
+    }
+
+    async fn get_firmware_info(&self, device: &Device) -> Result<FirmwareResponse, tonic::Status> {
+        let info = self.get_device_info().await.map_tapo_err(device).await?;
+        Ok(FirmwareResponse {
+            current_version: info.fw_ver,
+            hardware_version: info.hw_ver
+        })
+    }
+
     async fn power_on(&self, device: &Device) -> Result<crate::tapo::server::rpc::PowerResponse, tonic::Status> {
         self.on().await.map_tapo_err(device).await?;
 
@@ -81,25 +105,218 @@ impl TapoDeviceHandlerExt for ColorLightHandler {
         power: Option<bool>,
         brightness: Option<u8>,
         temperature: Option<u16>,
-        hue_saturation: Option<(u16, u8)>
-    ) -> Result<(), tonic::Status> {
+        hue_saturation: Option<(u16, u8)>,
+        atomic: bool
+    ) -> Result<AppliedFields, UpdateError> {
+        // capture the state before applying any changes so it can be restored should
+        // any of the sequential updates fail
+        let previous = if atomic {
+            Some(ColorState::from(self.get_device_info().await.map_tapo_err(device).await?))
+        } else { None };
 
-        if let Some(brightness) = brightness {
-            self.set_brightness(brightness).await.map_tapo_err(device).await?;
-        }
-        if let Some(temperature) = temperature {
-            self.set_color_temperature(temperature).await.map_tapo_err(device).await?;
+        update_color(self, device, previous, power, brightness, temperature, hue_saturation).await
+    }
+}
+
+/// Color requests which the color light and the light strip handlers have in common
+///
+/// Lets both handlers share the same update and restore code path
+pub trait ColorControl {
+    async fn send_combined(&self, brightness: Option<u8>, temperature: Option<u16>, hue_saturation: Option<(u16, u8)>) -> Result<(), tapo::Error>;
+
+    async fn send_brightness(&self, brightness: u8) -> Result<(), tapo::Error>;
+
+    async fn send_temperature(&self, temperature: u16) -> Result<(), tapo::Error>;
+
+    async fn send_hue_saturation(&self, hue: u16, saturation: u8) -> Result<(), tapo::Error>;
+}
+
+macro_rules! impl_color_control {
+    ($handler:ty) => {
+        impl ColorControl for $handler {
+            async fn send_combined(&self, brightness: Option<u8>, temperature: Option<u16>, hue_saturation: Option<(u16, u8)>) -> Result<(), tapo::Error> {
+                let mut params = self.set();
+                if let Some(brightness) = brightness {
+                    params = params.brightness(brightness);
+                }
+                if let Some(temperature) = temperature {
+                    params = params.color_temperature(temperature);
+                }
+                if let Some((hue, saturation)) = hue_saturation {
+                    params = params.hue_saturation(hue, saturation);
+                }
+                params.send(self).await
+            }
+
+            async fn send_brightness(&self, brightness: u8) -> Result<(), tapo::Error> {
+                self.set_brightness(brightness).await
+            }
+
+            async fn send_temperature(&self, temperature: u16) -> Result<(), tapo::Error> {
+                self.set_color_temperature(temperature).await
+            }
+
+            async fn send_hue_saturation(&self, hue: u16, saturation: u8) -> Result<(), tapo::Error> {
+                self.set_hue_saturation(hue, saturation).await
+            }
         }
-        if let Some((hue, saturation)) = hue_saturation {
-            self.set_hue_saturation(hue, saturation).await.map_tapo_err(device).await?;
+    };
+}
+
+impl_color_control!(ColorLightHandler);
+impl_color_control!(RgbLightStripHandler);
+
+/// Color state of a device which is restored should an atomic update fail
+pub struct ColorState {
+    device_on: bool,
+    brightness: u8,
+    hue: Option<u16>,
+    saturation: Option<u16>,
+    color_temp: u16
+}
+
+impl From<DeviceInfoColorLightResult> for ColorState {
+    fn from(info: DeviceInfoColorLightResult) -> Self {
+        Self { device_on: info.device_on, brightness: info.brightness, hue: info.hue, saturation: info.saturation, color_temp: info.color_temp }
+    }
+}
+
+impl From<DeviceInfoRgbLightStripResult> for ColorState {
+    fn from(info: DeviceInfoRgbLightStripResult) -> Self {
+        Self { device_on: info.device_on, brightness: info.brightness, hue: info.hue, saturation: info.saturation, color_temp: info.color_temp }
+    }
+}
+
+/// Apply the property updates and restore the previous state should they fail
+///
+/// The state is only restored when a `previous` state was captured for an atomic update
+pub async fn update_color(
+    handler: &(impl ColorControl + TapoDeviceHandlerExt),
+    device: &Device,
+    previous: Option<ColorState>,
+    power: Option<bool>,
+    brightness: Option<u8>,
+    temperature: Option<u16>,
+    hue_saturation: Option<(u16, u8)>
+) -> Result<AppliedFields, UpdateError> {
+    let result = apply_update(handler, device, power, brightness, temperature, hue_saturation).await;
+
+    match (result, previous) {
+        (Err(err), Some(previous)) => {
+            warn!("Update of device '{}' failed: {}. Restoring previous state...", device.name, err.status.message());
+            // nothing of the update is left over when the previous state could be restored
+            if restore_state(handler, device, previous).await {
+                Err(AppliedFields::default().failed(err.status))
+            } else { Err(err) }
         }
+        (result, _) => result
+    }
+}
+
+/// Get the name of a dynamic effect of a color light by its id
+///
+/// Falls back to the id should the effect be unknown for the device type
+pub fn dynamic_effect_name(device_type: &SupportedDevice, id: &str) -> String {
+    let effects: &[(&str, &str)] = match device_type {
+        SupportedDevice::L530 | SupportedDevice::L630 => &[("L1", "Party"), ("L2", "Relax")],
+        _ => &[]
+    };
+    effects.iter()
+        .find(|(effect_id, _)| *effect_id == id)
+        .map_or(id.to_string(), |(_, name)| name.to_string())
+}
 
-        if let Some(power_on) = power {
-            if power_on && brightness.is_none() && temperature.is_none() && hue_saturation.is_none() {
-                self.power_on(device).await?;
-            } else if !power_on { self.power_off(device).await?; }
+/// Check whether the property updates can be sent to the device in a single request
+///
+/// A combined request is only worth it when more than one property changes. Since the device
+/// rejects a temperature together with a hue and saturation and turning the device off together
+/// with other properties those cases are applied sequentially
+fn use_combined_update(power: Option<bool>, brightness: Option<u8>, temperature: Option<u16>, hue_saturation: Option<(u16, u8)>) -> bool {
+    let changes = [brightness.is_some(), temperature.is_some(), hue_saturation.is_some()].into_iter().filter(|c| *c).count();
+    changes > 1 && !(temperature.is_some() && hue_saturation.is_some()) && power != Some(false)
+}
+
+/// Apply the property updates either in a single request or one after another
+async fn apply_update(
+    handler: &(impl ColorControl + TapoDeviceHandlerExt),
+    device: &Device,
+    power: Option<bool>,
+    brightness: Option<u8>,
+    temperature: Option<u16>,
+    hue_saturation: Option<(u16, u8)>
+) -> Result<AppliedFields, UpdateError> {
+    if use_combined_update(power, brightness, temperature, hue_saturation) {
+        // changing any property turns the device on anyways so the power doesn't need to be set
+        handler.send_combined(brightness, temperature, hue_saturation).await.map_tapo_err(device).await?;
+        return Ok(AppliedFields::all(power, brightness, temperature, hue_saturation));
+    }
+
+    let mut applied = AppliedFields::default();
+    if let Some(brightness) = brightness {
+        handler.send_brightness(brightness).await.map_tapo_err(device).await.map_err(|status| applied.failed(status))?;
+        applied.brightness = true;
+    }
+    if let Some(temperature) = temperature {
+        handler.send_temperature(temperature).await.map_tapo_err(device).await.map_err(|status| applied.failed(status))?;
+        applied.temperature = true;
+    }
+    if let Some((hue, saturation)) = hue_saturation {
+        handler.send_hue_saturation(hue, saturation).await.map_tapo_err(device).await.map_err(|status| applied.failed(status))?;
+        applied.hue_saturation = true;
+    }
+
+    if let Some(power_on) = power {
+        if power_on && brightness.is_none() && temperature.is_none() && hue_saturation.is_none() {
+            handler.power_on(device).await.map_err(|status| applied.failed(status))?;
+        } else if !power_on { handler.power_off(device).await.map_err(|status| applied.failed(status))?; }
+        applied.power = true;
+    }
+
+    Ok(applied)
+}
+
+/// Restore a previously captured state of the device
+///
+/// The restore is best-effort: failures are only logged since the original error
+/// is returned to the client anyway. Returns `true` when the previous state was restored
+async fn restore_state(handler: &(impl ColorControl + TapoDeviceHandlerExt), device: &Device, previous: ColorState) -> bool {
+    let color = match previous.hue.zip(previous.saturation) {
+        Some((hue, saturation)) if previous.color_temp == 0 => (None, Some((hue, saturation as u8))),
+        _ => (Some(previous.color_temp), None)
+    };
+    let power = Some(previous.device_on);
+
+    match apply_update(handler, device, power, Some(previous.brightness), color.0, color.1).await {
+        Ok(_) => {
+            debug!("Restored previous state of device '{}'", device.name);
+            true
+        },
+        Err(err) => {
+            error!("Unable to restore previous state of device '{}': {}", device.name, err.status.message());
+            false
         }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::use_combined_update;
+
+    #[test]
+    fn combined_update_for_multiple_applicable_properties() {
+        assert!(use_combined_update(None, Some(50), Some(2700), None));
+        assert!(use_combined_update(Some(true), Some(50), None, Some((120, 80))));
+        assert!(use_combined_update(Some(true), Some(50), Some(2700), None));
+    }
 
-        Ok(())
+    #[test]
+    fn sequential_update_for_single_or_conflicting_properties() {
+        // a single property doesn't profit from a combined request
+        assert!(!use_combined_update(None, Some(50), None, None));
+        assert!(!use_combined_update(Some(true), None, None, Some((120, 80))));
+        // the device rejects a temperature together with a hue and saturation
+        assert!(!use_combined_update(None, Some(50), Some(2700), Some((120, 80))));
+        // turning the device off can't be combined with other properties
+        assert!(!use_combined_update(Some(false), Some(50), Some(2700), None));
     }
-}
\ No newline at end of file
+}
@@ -1,18 +1,24 @@
 use tapo::GenericDeviceHandler;
 
-use crate::tapo::{server::rpc::{InfoJsonResponse, InfoResponse, PowerResponse, UsageResponse}, TapoDeviceHandlerExt, TapoErrMap};
+use crate::tapo::{server::rpc::{CurrentPowerResponse, FirmwareResponse, InfoJsonResponse, InfoResponse, PowerResponse, UsageResponse}, AppliedFields, TapoDeviceHandlerExt, TapoErrMap, UpdateError};
 
 impl TapoDeviceHandlerExt for GenericDeviceHandler {
     async fn reset(&self, _device: &crate::device::Device) -> Result<(), tonic::Status> {
         Err(tonic::Status::unimplemented("Reset API is not supported by this device type"))
     }
 
+    async fn reboot(&self, _device: &crate::device::Device) -> Result<(), tonic::Status> {
+        Err(tonic::Status::unimplemented("Reboot API is not supported by this device type"))
+    }
+
     async fn get_info(&self, device: &crate::device::Device) -> Result<InfoResponse, tonic::Status> {
         let info = self.get_device_info().await.map_tapo_err(device).await?;
         Ok(InfoResponse {
                 device_on: info.device_on,
                 on_time: info.on_time,
                 name: device.name.clone(),
+                rssi: Some(info.rssi as i32),
+                signal_level: Some(info.signal_level as u32),
                 ..InfoResponse::default()
         })
     }
@@ -29,6 +35,18 @@ impl TapoDeviceHandlerExt for GenericDeviceHandler {
         Err(tonic::Status::unimplemented("Device usage API is not supported by this device type"))
     }
 
+    async fn get_current_power(&self, _device: &crate::device::Device) -> Result<CurrentPowerResponse, tonic::Status> {
+        Err(tonic::Status::unimplemented("Current power API is not supported by this device type"))
+    }
+
+    async fn get_firmware_info(&self, device: &crate::device::Device) -> Result<FirmwareResponse, tonic::Status> {
+        let info = self.get_device_info().await.map_tapo_err(device).await?;
+        Ok(FirmwareResponse {
+            current_version: info.fw_ver,
+            hardware_version: info.hw_ver
+        })
+    }
+
     async fn power_on(&self, device: &crate::device::Device) -> Result<PowerResponse, tonic::Status> {
         self.on().await.map_tapo_err(device).await?;
 
@@ -47,13 +65,14 @@ impl TapoDeviceHandlerExt for GenericDeviceHandler {
         power: Option<bool>,
         _brightness: Option<u8>,
         _temperature: Option<u16>,
-        _hue_saturation: Option<(u16, u8)>
-    ) -> Result<(), tonic::Status> {
+        _hue_saturation: Option<(u16, u8)>,
+        _atomic: bool
+    ) -> Result<AppliedFields, UpdateError> {
         if let Some(power_on) = power {
             if power_on { self.power_on(device).await?; }
             else { self.power_off(device).await?; }
         }
 
-        Ok(())
+        Ok(AppliedFields { power: power.is_some(), ..AppliedFields::default() })
     }
 }
\ No newline at end of file
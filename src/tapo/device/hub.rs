@@ -0,0 +1,104 @@
+use tapo::{responses::ChildDeviceHubResult, HubHandler};
+
+use crate::{device::Device, tapo::{server::rpc::{CurrentPowerResponse, FirmwareResponse, InfoJsonResponse, InfoResponse, PowerResponse, SensorResponse, UsageResponse}, AppliedFields, TapoDeviceHandlerExt, REBOOT_DELAY_SECS, TapoErrMap, UpdateError}};
+
+/// Get the latest reading of a child sensor of the hub by its device id or nickname
+///
+/// Children which only measure the temperature (e.g. the KE100 radiator valve) don't report a humidity
+pub async fn sensor_reading(handler: &HubHandler, device: &Device, child: &str) -> Result<SensorResponse, tonic::Status> {
+    let children = handler.get_child_device_list().await.map_tapo_err(device).await?;
+    let response = children.into_iter().find_map(|result| match result {
+        ChildDeviceHubResult::T310(sensor) | ChildDeviceHubResult::T315(sensor) => {
+            (sensor.device_id == child || sensor.nickname == child).then(|| SensorResponse {
+                device: device.name.clone(),
+                child: sensor.nickname,
+                model: sensor.model,
+                temperature: Some(sensor.current_temperature),
+                humidity: Some(sensor.current_humidity as u32),
+                low_battery: sensor.at_low_battery
+            })
+        },
+        ChildDeviceHubResult::KE100(valve) => {
+            (valve.device_id == child || valve.nickname == child).then(|| SensorResponse {
+                device: device.name.clone(),
+                child: valve.nickname,
+                model: valve.model,
+                temperature: Some(valve.current_temperature),
+                humidity: None,
+                low_battery: valve.at_low_battery
+            })
+        },
+        _ => None
+    });
+
+    response.ok_or_else(|| tonic::Status::not_found(format!("No sensor '{child}' is paired with the hub '{}'", device.name)))
+}
+
+impl TapoDeviceHandlerExt for HubHandler {
+    async fn reset(&self, device: &Device) -> Result<(), tonic::Status> {
+        self.device_reset().await.map_tapo_err(device).await
+    }
+
+    async fn reboot(&self, device: &Device) -> Result<(), tonic::Status> {
+        self.device_reboot(REBOOT_DELAY_SECS).await.map_tapo_err(device).await
+    }
+
+    async fn get_info(&self, device: &Device) -> Result<InfoResponse, tonic::Status> {
+        let info = self.get_device_info().await.map_tapo_err(device).await?;
+        Ok(InfoResponse {
+            name: device.name.clone(),
+            rssi: Some(info.rssi as i32),
+            signal_level: Some(info.signal_level as u32),
+            ..InfoResponse::default()
+        })
+    }
+
+    async fn get_info_json(&self, device: &Device) -> Result<InfoJsonResponse, tonic::Status> {
+        let info = self.get_device_info_json().await.map_tapo_err(device).await?;
+        let mut bytes = vec![];
+        serde_json::to_writer(&mut bytes, &info).unwrap_or_default();
+
+        Ok(InfoJsonResponse { data: bytes })
+    }
+
+    async fn get_usage(&self, _device: &Device) -> Result<UsageResponse, tonic::Status> {
+        Err(tonic::Status::unimplemented("Device usage API is not supported by hubs"))
+    }
+
+    async fn get_current_power(&self, _device: &Device) -> Result<CurrentPowerResponse, tonic::Status> {
+        Err(tonic::Status::unimplemented("Current power API is not supported by hubs"))
+    }
+
+    async fn get_firmware_info(&self, device: &Device) -> Result<FirmwareResponse, tonic::Status> {
+        let info = self.get_device_info().await.map_tapo_err(device).await?;
+        Ok(FirmwareResponse {
+            current_version: info.fw_ver,
+            hardware_version: info.hw_ver
+        })
+    }
+
+    async fn power_on(&self, _device: &Device) -> Result<PowerResponse, tonic::Status> {
+        Err(tonic::Status::unimplemented("Hubs can't be turned on or off"))
+    }
+
+    async fn power_off(&self, _device: &Device) -> Result<PowerResponse, tonic::Status> {
+        Err(tonic::Status::unimplemented("Hubs can't be turned on or off"))
+    }
+
+    async fn update(
+        &self,
+        _device: &Device,
+        power: Option<bool>,
+        _brightness: Option<u8>,
+        _temperature: Option<u16>,
+        _hue_saturation: Option<(u16, u8)>,
+        _atomic: bool
+    ) -> Result<AppliedFields, UpdateError> {
+        // hubs don't have any properties which could be set
+        if power.is_some() {
+            return Err(tonic::Status::unimplemented("Hubs can't be turned on or off").into());
+        }
+
+        Ok(AppliedFields::default())
+    }
+}
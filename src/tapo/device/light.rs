@@ -1,12 +1,16 @@
 use tapo::LightHandler;
 
-use crate::tapo::{server::rpc::{InfoJsonResponse, InfoResponse, PowerResponse, UsagePerPeriod, UsageResponse}, TapoDeviceHandlerExt, TapoErrMap};
+use crate::tapo::{server::rpc::{CurrentPowerResponse, FirmwareResponse, InfoJsonResponse, InfoResponse, PowerResponse, UsagePerPeriod, UsageResponse}, AppliedFields, TapoDeviceHandlerExt, REBOOT_DELAY_SECS, TapoErrMap, UpdateError};
 
 impl TapoDeviceHandlerExt for LightHandler {
     async fn reset(&self, device: &crate::device::Device) -> Result<(), tonic::Status> {
         self.device_reset().await.map_tapo_err(device).await
     }
 
+    async fn reboot(&self, device: &crate::device::Device) -> Result<(), tonic::Status> {
+        self.device_reboot(REBOOT_DELAY_SECS).await.map_tapo_err(device).await
+    }
+
     async fn get_info(&self, device: &crate::device::Device) -> Result<InfoResponse, tonic::Status> {
         let info = self.get_device_info().await.map_tapo_err(device).await?;
         Ok(InfoResponse {
@@ -15,6 +19,8 @@ impl TapoDeviceHandlerExt for LightHandler {
             on_time: info.on_time,
             name: device.name.clone(),
             overheated: info.overheated,
+            rssi: Some(info.rssi as i32),
+            signal_level: Some(info.signal_level as u32),
             ..InfoResponse::default()
         })
     }
@@ -55,6 +61,18 @@ impl TapoDeviceHandlerExt for LightHandler {
         })
     }
 
+    async fn get_current_power(&self, _device: &crate::device::Device) -> Result<CurrentPowerResponse, tonic::Status> {
+        Err(tonic::Status::unimplemented("Current power API is not supported by this device type"))
+    }
+
+    async fn get_firmware_info(&self, device: &crate::device::Device) -> Result<FirmwareResponse, tonic::Status> {
+        let info = self.get_device_info().await.map_tapo_err(device).await?;
+        Ok(FirmwareResponse {
+            current_version: info.fw_ver,
+            hardware_version: info.hw_ver
+        })
+    }
+
     async fn power_on(&self, device: &crate::device::Device) -> Result<PowerResponse, tonic::Status> {
         self.on().await.map_tapo_err(device).await?;
 
@@ -73,19 +91,26 @@ impl TapoDeviceHandlerExt for LightHandler {
         power: Option<bool>,
         brightness: Option<u8>,
         _temperature: Option<u16>,
-        _hue_saturation: Option<(u16, u8)>
-    ) -> Result<(), tonic::Status> {
+        _hue_saturation: Option<(u16, u8)>,
+        _atomic: bool
+    ) -> Result<AppliedFields, UpdateError> {
+        let mut applied = AppliedFields::default();
         if let Some(brightness) = brightness {
             self.set_brightness(brightness).await.map_tapo_err(device).await?;
+            applied.brightness = true;
             // if power is true at the same time we can ignore it since changing the brightness
             // turns the lamp on anyways
-            if power.is_some_and(|v| v) { return Ok(()); }
+            if power.is_some_and(|v| v) {
+                applied.power = true;
+                return Ok(applied);
+            }
         }
         if let Some(power_on) = power {
-            if power_on { self.power_on(device).await?; }
-            else { self.power_off(device).await?; }
+            if power_on { self.power_on(device).await.map_err(|status| applied.failed(status))?; }
+            else { self.power_off(device).await.map_err(|status| applied.failed(status))?; }
+            applied.power = true;
         }
 
-        Ok(())
+        Ok(applied)
     }
 }
\ No newline at end of file
@@ -0,0 +1,114 @@
+use tapo::RgbLightStripHandler;
+
+use crate::{device::Device, tapo::{color::{any_to_hsl, any_to_rgb}, server::rpc::{CurrentPowerResponse, FirmwareResponse, InfoJsonResponse, InfoResponse, PowerResponse, UsagePerPeriod, UsageResponse}, AppliedFields, TapoDeviceHandlerExt, REBOOT_DELAY_SECS, TapoErrMap, UpdateError}};
+
+use super::color_light::{update_color, ColorState};
+
+impl TapoDeviceHandlerExt for RgbLightStripHandler {
+    async fn reset(&self, device: &Device) -> Result<(), tonic::Status> {
+        self.device_reset().await.map_tapo_err(device).await
+    }
+
+    async fn reboot(&self, device: &Device) -> Result<(), tonic::Status> {
+        self.device_reboot(REBOOT_DELAY_SECS).await.map_tapo_err(device).await
+    }
+
+    async fn get_info(&self, device: &Device) -> Result<InfoResponse, tonic::Status> {
+        let info = self.get_device_info().await.map_tapo_err(device).await?;
+        let brightness = Some(info.brightness as u32);
+        let hue = info.hue.map(|v| v as u32);
+        let saturation = info.saturation.map(|v| v as u32);
+        let temperature = Some(info.color_temp as u32);
+        Ok(InfoResponse {
+            brightness,
+            hue,
+            saturation,
+            temperature,
+            device_on: Some(info.device_on),
+            overheated: info.overheated,
+            name: device.name.clone(),
+            color: any_to_rgb(temperature, hue, saturation, brightness),
+            hsl: any_to_hsl(temperature, hue, saturation, brightness),
+            rssi: Some(info.rssi as i32),
+            signal_level: Some(info.signal_level as u32),
+            ..InfoResponse::default()
+        })
+    }
+
+    async fn get_info_json(&self, device: &Device) -> Result<InfoJsonResponse, tonic::Status> {
+        let info = self.get_device_info_json().await.map_tapo_err(device).await?;
+        let mut bytes = vec![];
+        serde_json::to_writer(&mut bytes, &info).unwrap_or_default();
+
+        Ok(InfoJsonResponse { data: bytes })
+    }
+
+    async fn get_usage(&self, device: &Device) -> Result<UsageResponse, tonic::Status> {
+        let usage = self.get_device_usage().await.map_tapo_err(device).await?;
+
+        let power_usage = UsagePerPeriod {
+            today: usage.power_usage.today,
+            week: usage.power_usage.past7,
+            month: usage.power_usage.past30
+        };
+
+        let time_usage = UsagePerPeriod {
+            today: usage.time_usage.today,
+            week: usage.time_usage.past7,
+            month: usage.time_usage.past30
+        };
+
+        let saved_power = UsagePerPeriod {
+            today: usage.saved_power.today,
+            week: usage.saved_power.past7,
+            month: usage.saved_power.past30
+        };
+
+        Ok(UsageResponse {
+            power_usage: Some(power_usage),
+            time_usage: Some(time_usage),
+            saved_power: Some(saved_power)
+        })
+    }
+
+    async fn get_current_power(&self, _device: &Device) -> Result<CurrentPowerResponse, tonic::Status> {
+        Err(tonic::Status::unimplemented("Current power API is not supported by this device type"))
+    }
+
+    async fn get_firmware_info(&self, device: &Device) -> Result<FirmwareResponse, tonic::Status> {
+        let info = self.get_device_info().await.map_tapo_err(device).await?;
+        Ok(FirmwareResponse {
+            current_version: info.fw_ver,
+            hardware_version: info.hw_ver
+        })
+    }
+
+    async fn power_on(&self, device: &Device) -> Result<PowerResponse, tonic::Status> {
+        self.on().await.map_tapo_err(device).await?;
+
+        Ok(PowerResponse { device_on: true })
+    }
+
+    async fn power_off(&self, device: &Device) -> Result<PowerResponse, tonic::Status> {
+        self.off().await.map_tapo_err(device).await?;
+
+        Ok(PowerResponse { device_on: false })
+    }
+
+    async fn update(
+        &self,
+        device: &Device,
+        power: Option<bool>,
+        brightness: Option<u8>,
+        temperature: Option<u16>,
+        hue_saturation: Option<(u16, u8)>,
+        atomic: bool
+    ) -> Result<AppliedFields, UpdateError> {
+        // the light strip accepts the same color requests as the color lights
+        let previous = if atomic {
+            Some(ColorState::from(self.get_device_info().await.map_tapo_err(device).await?))
+        } else { None };
+
+        update_color(self, device, previous, power, brightness, temperature, hue_saturation).await
+    }
+}
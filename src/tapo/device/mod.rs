@@ -2,59 +2,143 @@ use tonic::Response;
 
 use crate::device::{Device, DeviceHandler};
 
-use super::server::rpc::{Empty, InfoJsonResponse, InfoResponse, PowerResponse, UsageResponse};
-use super::{TapoDeviceExt, TapoDeviceHandlerExt};
+use super::server::rpc::{CurrentPowerResponse, Empty, FirmwareResponse, InfoJsonResponse, InfoResponse, PowerResponse, SensorResponse, UsageResponse};
+use super::{TapoDeviceExt, TapoDeviceHandlerExt, UpdateError};
 
 pub mod color_light;
+pub mod light_strip;
 pub mod light;
 pub mod generic;
+pub mod hub;
+pub mod plug;
+pub mod valve;
+pub mod virtual_device;
 
 impl TapoDeviceExt for Device {
     async fn reset(&self) -> Result<Response<Empty>, tonic::Status> {
         match self.get_handler()? {
             DeviceHandler::ColorLight(handler) => handler.reset(self).await,
+            DeviceHandler::LightStrip(handler) => handler.reset(self).await,
             DeviceHandler::Light(handler) => handler.reset(self).await,
             DeviceHandler::Generic(handler) => handler.reset(self).await,
+            DeviceHandler::Hub(handler) => handler.reset(self).await,
+            DeviceHandler::Valve(handler) => handler.reset(self).await,
+            DeviceHandler::Plug(handler) => handler.reset(self).await,
+            DeviceHandler::EnergyPlug(handler) => handler.reset(self).await,
+            DeviceHandler::Virtual(handler) => handler.reset(self).await,
+        }.map(|_| Response::new(Empty {}))
+    }
+
+    async fn reboot(&self) -> Result<Response<Empty>, tonic::Status> {
+        match self.get_handler()? {
+            DeviceHandler::ColorLight(handler) => handler.reboot(self).await,
+            DeviceHandler::LightStrip(handler) => handler.reboot(self).await,
+            DeviceHandler::Light(handler) => handler.reboot(self).await,
+            DeviceHandler::Generic(handler) => handler.reboot(self).await,
+            DeviceHandler::Hub(handler) => handler.reboot(self).await,
+            DeviceHandler::Valve(handler) => handler.reboot(self).await,
+            DeviceHandler::Plug(handler) => handler.reboot(self).await,
+            DeviceHandler::EnergyPlug(handler) => handler.reboot(self).await,
+            DeviceHandler::Virtual(handler) => handler.reboot(self).await,
         }.map(|_| Response::new(Empty {}))
     }
 
     async fn get_info(&self) -> Result<Response<InfoResponse>, tonic::Status> {
         match self.get_handler()? {
             DeviceHandler::ColorLight(handler) => handler.get_info(self).await,
+            DeviceHandler::LightStrip(handler) => handler.get_info(self).await,
             DeviceHandler::Light(handler) => handler.get_info(self).await,
             DeviceHandler::Generic(handler) => handler.get_info(self).await,
+            DeviceHandler::Hub(handler) => handler.get_info(self).await,
+            DeviceHandler::Valve(handler) => handler.get_info(self).await,
+            DeviceHandler::Plug(handler) => handler.get_info(self).await,
+            DeviceHandler::EnergyPlug(handler) => handler.get_info(self).await,
+            DeviceHandler::Virtual(handler) => handler.get_info(self).await,
         }.map(Response::new)
     }
 
     async fn get_info_json(&self) -> Result<Response<InfoJsonResponse>, tonic::Status> {
         match self.get_handler()? {
             DeviceHandler::ColorLight(handler) => handler.get_info_json(self).await,
+            DeviceHandler::LightStrip(handler) => handler.get_info_json(self).await,
             DeviceHandler::Light(handler) => handler.get_info_json(self).await,
             DeviceHandler::Generic(handler) => handler.get_info_json(self).await,
+            DeviceHandler::Hub(handler) => handler.get_info_json(self).await,
+            DeviceHandler::Valve(handler) => handler.get_info_json(self).await,
+            DeviceHandler::Plug(handler) => handler.get_info_json(self).await,
+            DeviceHandler::EnergyPlug(handler) => handler.get_info_json(self).await,
+            DeviceHandler::Virtual(handler) => handler.get_info_json(self).await,
         }.map(Response::new)
     }
 
     async fn get_usage(&self) -> Result<Response<UsageResponse>, tonic::Status> {
         match self.get_handler()? {
             DeviceHandler::ColorLight(handler) => handler.get_usage(self).await,
+            DeviceHandler::LightStrip(handler) => handler.get_usage(self).await,
             DeviceHandler::Light(handler) => handler.get_usage(self).await,
             DeviceHandler::Generic(handler) => handler.get_usage(self).await,
+            DeviceHandler::Hub(handler) => handler.get_usage(self).await,
+            DeviceHandler::Valve(handler) => handler.get_usage(self).await,
+            DeviceHandler::Plug(handler) => handler.get_usage(self).await,
+            DeviceHandler::EnergyPlug(handler) => handler.get_usage(self).await,
+            DeviceHandler::Virtual(handler) => handler.get_usage(self).await,
+        }.map(Response::new)
+    }
+
+    async fn get_current_power(&self) -> Result<Response<CurrentPowerResponse>, tonic::Status> {
+        match self.get_handler()? {
+            DeviceHandler::ColorLight(handler) => handler.get_current_power(self).await,
+            DeviceHandler::LightStrip(handler) => handler.get_current_power(self).await,
+            DeviceHandler::Light(handler) => handler.get_current_power(self).await,
+            DeviceHandler::Generic(handler) => handler.get_current_power(self).await,
+            DeviceHandler::Hub(handler) => handler.get_current_power(self).await,
+            DeviceHandler::Valve(handler) => handler.get_current_power(self).await,
+            DeviceHandler::Plug(handler) => handler.get_current_power(self).await,
+            // the plug handler has an inherent method with the same name
+            DeviceHandler::EnergyPlug(handler) => TapoDeviceHandlerExt::get_current_power(handler, self).await,
+            DeviceHandler::Virtual(handler) => handler.get_current_power(self).await,
+        }.map(Response::new)
+    }
+
+    async fn get_firmware_info(&self) -> Result<Response<FirmwareResponse>, tonic::Status> {
+        match self.get_handler()? {
+            DeviceHandler::ColorLight(handler) => handler.get_firmware_info(self).await,
+            DeviceHandler::LightStrip(handler) => handler.get_firmware_info(self).await,
+            DeviceHandler::Light(handler) => handler.get_firmware_info(self).await,
+            DeviceHandler::Generic(handler) => handler.get_firmware_info(self).await,
+            DeviceHandler::Hub(handler) => handler.get_firmware_info(self).await,
+            DeviceHandler::Valve(handler) => handler.get_firmware_info(self).await,
+            DeviceHandler::Plug(handler) => handler.get_firmware_info(self).await,
+            DeviceHandler::EnergyPlug(handler) => handler.get_firmware_info(self).await,
+            DeviceHandler::Virtual(handler) => handler.get_firmware_info(self).await,
         }.map(Response::new)
     }
 
     async fn on(&self) -> Result<Response<PowerResponse>, tonic::Status> {
         match self.get_handler()? {
             DeviceHandler::ColorLight(handler) => handler.power_on(self).await,
+            DeviceHandler::LightStrip(handler) => handler.power_on(self).await,
             DeviceHandler::Light(handler) => handler.power_on(self).await,
             DeviceHandler::Generic(handler) => handler.power_on(self).await,
+            DeviceHandler::Hub(handler) => handler.power_on(self).await,
+            DeviceHandler::Valve(handler) => handler.power_on(self).await,
+            DeviceHandler::Plug(handler) => handler.power_on(self).await,
+            DeviceHandler::EnergyPlug(handler) => handler.power_on(self).await,
+            DeviceHandler::Virtual(handler) => handler.power_on(self).await,
         }.map(Response::new)
     }
 
     async fn off(&self) -> Result<Response<PowerResponse>, tonic::Status> {
         match self.get_handler()? {
             DeviceHandler::ColorLight(handler) => handler.power_off(self).await,
+            DeviceHandler::LightStrip(handler) => handler.power_off(self).await,
             DeviceHandler::Light(handler) => handler.power_off(self).await,
             DeviceHandler::Generic(handler) => handler.power_off(self).await,
+            DeviceHandler::Hub(handler) => handler.power_off(self).await,
+            DeviceHandler::Valve(handler) => handler.power_off(self).await,
+            DeviceHandler::Plug(handler) => handler.power_off(self).await,
+            DeviceHandler::EnergyPlug(handler) => handler.power_off(self).await,
+            DeviceHandler::Virtual(handler) => handler.power_off(self).await,
         }.map(Response::new)
     }
 
@@ -64,26 +148,73 @@ impl TapoDeviceExt for Device {
         power: Option<bool>,
         brightness: Option<u8>,
         temperature: Option<u16>,
-        hue_saturation: Option<(u16, u8)>
-    ) -> Result<Response<InfoResponse>, tonic::Status> {
+        hue_saturation: Option<(u16, u8)>,
+        atomic: bool
+    ) -> Result<Response<InfoResponse>, UpdateError> {
         match self.get_handler()? {
             DeviceHandler::ColorLight(handler) =>
-                handler.update(self, power, brightness, temperature, hue_saturation).await,
+                handler.update(self, power, brightness, temperature, hue_saturation, atomic).await,
+            DeviceHandler::LightStrip(handler) =>
+                handler.update(self, power, brightness, temperature, hue_saturation, atomic).await,
             DeviceHandler::Light(handler) => {
                 info.hue = None;
                 info.saturation = None;
                 info.temperature = None;
-                handler.update(self, power, brightness, temperature, hue_saturation).await
+                handler.update(self, power, brightness, temperature, hue_saturation, atomic).await
+            },
+            DeviceHandler::EnergyPlug(handler) => {
+                info.hue = None;
+                info.saturation = None;
+                info.temperature = None;
+                info.brightness = None;
+                handler.update(self, power, brightness, temperature, hue_saturation, atomic).await
+            },
+            DeviceHandler::Plug(handler) => {
+                info.hue = None;
+                info.saturation = None;
+                info.temperature = None;
+                info.brightness = None;
+                handler.update(self, power, brightness, temperature, hue_saturation, atomic).await
             },
             DeviceHandler::Generic(handler) => {
                 info.hue = None;
                 info.saturation = None;
                 info.temperature = None;
                 info.brightness = None;
-                handler.update(self, power, brightness, temperature, hue_saturation).await
-            }
+                handler.update(self, power, brightness, temperature, hue_saturation, atomic).await
+            },
+            DeviceHandler::Hub(handler) => {
+                info.hue = None;
+                info.saturation = None;
+                info.temperature = None;
+                info.brightness = None;
+                handler.update(self, power, brightness, temperature, hue_saturation, atomic).await
+            },
+            DeviceHandler::Valve(handler) => {
+                info.hue = None;
+                info.saturation = None;
+                info.temperature = None;
+                info.brightness = None;
+                handler.update(self, power, brightness, temperature, hue_saturation, atomic).await
+            },
+            DeviceHandler::Virtual(handler) =>
+                handler.update(self, power, brightness, temperature, hue_saturation, atomic).await
         }?;
 
         Ok(Response::new(info))
     }
+
+    async fn get_sensor(&self, child: &str) -> Result<Response<SensorResponse>, tonic::Status> {
+        match self.get_handler()? {
+            DeviceHandler::Hub(handler) => hub::sensor_reading(handler, self, child).await.map(Response::new),
+            _ => Err(tonic::Status::unimplemented(format!("The device '{}' isn't a hub with child sensors", self.name)))
+        }
+    }
+
+    async fn set_target_temperature(&self, temperature: Option<u8>, frost_protection: Option<bool>) -> Result<Response<Empty>, tonic::Status> {
+        match self.get_handler()? {
+            DeviceHandler::Valve(handler) => valve::set_target(handler, self, temperature, frost_protection).await.map(|_| Response::new(Empty {})),
+            _ => Err(tonic::Status::unimplemented(format!("The device '{}' isn't a radiator valve", self.name)))
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,180 @@
+use tapo::{PlugEnergyMonitoringHandler, PlugHandler};
+
+use crate::tapo::{server::rpc::{CurrentPowerResponse, FirmwareResponse, InfoJsonResponse, InfoResponse, PowerResponse, UsagePerPeriod, UsageResponse}, AppliedFields, TapoDeviceHandlerExt, REBOOT_DELAY_SECS, TapoErrMap, UpdateError};
+
+impl TapoDeviceHandlerExt for PlugEnergyMonitoringHandler {
+    async fn reset(&self, device: &crate::device::Device) -> Result<(), tonic::Status> {
+        self.device_reset().await.map_tapo_err(device).await
+    }
+
+    async fn reboot(&self, device: &crate::device::Device) -> Result<(), tonic::Status> {
+        self.device_reboot(REBOOT_DELAY_SECS).await.map_tapo_err(device).await
+    }
+
+    async fn get_info(&self, device: &crate::device::Device) -> Result<InfoResponse, tonic::Status> {
+        let info = self.get_device_info().await.map_tapo_err(device).await?;
+        Ok(InfoResponse {
+            device_on: Some(info.device_on),
+            on_time: Some(info.on_time),
+            overheated: info.overheated,
+            name: device.name.clone(),
+            rssi: Some(info.rssi as i32),
+            signal_level: Some(info.signal_level as u32),
+            ..InfoResponse::default()
+        })
+    }
+
+    async fn get_info_json(&self, device: &crate::device::Device) -> Result<InfoJsonResponse, tonic::Status> {
+        let info = self.get_device_info_json().await.map_tapo_err(device).await?;
+        let mut bytes = vec![];
+        serde_json::to_writer(&mut bytes, &info).unwrap_or_default();
+
+        Ok(InfoJsonResponse { data: bytes })
+    }
+
+    async fn get_usage(&self, device: &crate::device::Device) -> Result<UsageResponse, tonic::Status> {
+        // the energy usage of the plug is only reported for today and the current month
+        let usage = self.get_energy_usage().await.map_tapo_err(device).await?;
+
+        let power_usage = UsagePerPeriod {
+            today: Some(usage.today_energy),
+            week: None,
+            month: Some(usage.month_energy)
+        };
+
+        let time_usage = UsagePerPeriod {
+            today: Some(usage.today_runtime),
+            week: None,
+            month: Some(usage.month_runtime)
+        };
+
+        Ok(UsageResponse {
+            power_usage: Some(power_usage),
+            time_usage: Some(time_usage),
+            saved_power: None
+        })
+    }
+
+    async fn get_current_power(&self, device: &crate::device::Device) -> Result<CurrentPowerResponse, tonic::Status> {
+        let power = PlugEnergyMonitoringHandler::get_current_power(self).await.map_tapo_err(device).await?;
+        Ok(CurrentPowerResponse {
+            device: device.name.clone(),
+            current_power: power.current_power,
+            smoothed_power: None
+        })
+    }
+
+    async fn get_firmware_info(&self, device: &crate::device::Device) -> Result<FirmwareResponse, tonic::Status> {
+        let info = self.get_device_info().await.map_tapo_err(device).await?;
+        Ok(FirmwareResponse {
+            current_version: info.fw_ver,
+            hardware_version: info.hw_ver
+        })
+    }
+
+    async fn power_on(&self, device: &crate::device::Device) -> Result<PowerResponse, tonic::Status> {
+        self.on().await.map_tapo_err(device).await?;
+
+        Ok(PowerResponse { device_on: true })
+    }
+
+    async fn power_off(&self, device: &crate::device::Device) -> Result<PowerResponse, tonic::Status> {
+        self.off().await.map_tapo_err(device).await?;
+
+        Ok(PowerResponse { device_on: false })
+    }
+
+    async fn update(
+        &self,
+        device: &crate::device::Device,
+        power: Option<bool>,
+        _brightness: Option<u8>,
+        _temperature: Option<u16>,
+        _hue_saturation: Option<(u16, u8)>,
+        _atomic: bool
+    ) -> Result<AppliedFields, UpdateError> {
+        // plugs can only be switched on and off
+        if let Some(power_on) = power {
+            if power_on { self.power_on(device).await?; }
+            else { self.power_off(device).await?; }
+        }
+
+        Ok(AppliedFields { power: power.is_some(), ..AppliedFields::default() })
+    }
+}
+
+impl TapoDeviceHandlerExt for PlugHandler {
+    async fn reset(&self, device: &crate::device::Device) -> Result<(), tonic::Status> {
+        self.device_reset().await.map_tapo_err(device).await
+    }
+
+    async fn reboot(&self, device: &crate::device::Device) -> Result<(), tonic::Status> {
+        self.device_reboot(REBOOT_DELAY_SECS).await.map_tapo_err(device).await
+    }
+
+    async fn get_info(&self, device: &crate::device::Device) -> Result<InfoResponse, tonic::Status> {
+        let info = self.get_device_info().await.map_tapo_err(device).await?;
+        Ok(InfoResponse {
+            device_on: Some(info.device_on),
+            on_time: Some(info.on_time),
+            name: device.name.clone(),
+            rssi: Some(info.rssi as i32),
+            signal_level: Some(info.signal_level as u32),
+            ..InfoResponse::default()
+        })
+    }
+
+    async fn get_info_json(&self, device: &crate::device::Device) -> Result<InfoJsonResponse, tonic::Status> {
+        let info = self.get_device_info_json().await.map_tapo_err(device).await?;
+        let mut bytes = vec![];
+        serde_json::to_writer(&mut bytes, &info).unwrap_or_default();
+
+        Ok(InfoJsonResponse { data: bytes })
+    }
+
+    async fn get_usage(&self, _device: &crate::device::Device) -> Result<UsageResponse, tonic::Status> {
+        Err(tonic::Status::unimplemented("Device usage API is not supported by plugs without energy monitoring"))
+    }
+
+    async fn get_current_power(&self, _device: &crate::device::Device) -> Result<CurrentPowerResponse, tonic::Status> {
+        Err(tonic::Status::unimplemented("Current power API is not supported by plugs without energy monitoring"))
+    }
+
+    async fn get_firmware_info(&self, device: &crate::device::Device) -> Result<FirmwareResponse, tonic::Status> {
+        let info = self.get_device_info().await.map_tapo_err(device).await?;
+        Ok(FirmwareResponse {
+            current_version: info.fw_ver,
+            hardware_version: info.hw_ver
+        })
+    }
+
+    async fn power_on(&self, device: &crate::device::Device) -> Result<PowerResponse, tonic::Status> {
+        self.on().await.map_tapo_err(device).await?;
+
+        Ok(PowerResponse { device_on: true })
+    }
+
+    async fn power_off(&self, device: &crate::device::Device) -> Result<PowerResponse, tonic::Status> {
+        self.off().await.map_tapo_err(device).await?;
+
+        Ok(PowerResponse { device_on: false })
+    }
+
+    async fn update(
+        &self,
+        device: &crate::device::Device,
+        power: Option<bool>,
+        _brightness: Option<u8>,
+        _temperature: Option<u16>,
+        _hue_saturation: Option<(u16, u8)>,
+        _atomic: bool
+    ) -> Result<AppliedFields, UpdateError> {
+        // plugs can only be switched on and off
+        if let Some(power_on) = power {
+            if power_on { self.power_on(device).await?; }
+            else { self.power_off(device).await?; }
+        }
+
+        Ok(AppliedFields { power: power.is_some(), ..AppliedFields::default() })
+    }
+}
@@ -0,0 +1,93 @@
+use tapo::{requests::TemperatureUnitKE100, KE100Handler};
+
+use crate::{device::Device, tapo::{server::rpc::{CurrentPowerResponse, FirmwareResponse, InfoJsonResponse, InfoResponse, PowerResponse, UsageResponse}, AppliedFields, TapoDeviceHandlerExt, TapoErrMap, UpdateError}};
+
+/// Change the target temperature in celsius and the frost protection of the radiator valve
+///
+/// The target temperature has to be within the control range reported by the valve
+pub async fn set_target(handler: &KE100Handler, device: &Device, temperature: Option<u8>, frost_protection: Option<bool>) -> Result<(), tonic::Status> {
+    if let Some(temperature) = temperature {
+        let info = handler.get_device_info().await.map_tapo_err(device).await?;
+        let (min, max) = (info.min_control_temperature, info.max_control_temperature);
+        if !(min..=max).contains(&temperature) {
+            return Err(tonic::Status::invalid_argument(format!("Target temperature {temperature}°C is outside of the range {min}-{max}°C of the device '{}'", device.name)))
+        }
+        handler.set_target_temperature(temperature, TemperatureUnitKE100::Celsius).await.map_tapo_err(device).await?;
+    }
+
+    if let Some(frost_protection) = frost_protection {
+        handler.set_frost_protection(frost_protection).await.map_tapo_err(device).await?;
+    }
+    Ok(())
+}
+
+impl TapoDeviceHandlerExt for KE100Handler {
+    async fn reset(&self, _device: &Device) -> Result<(), tonic::Status> {
+        Err(tonic::Status::unimplemented("Reset API is not supported by devices behind a hub"))
+    }
+
+    async fn reboot(&self, _device: &Device) -> Result<(), tonic::Status> {
+        Err(tonic::Status::unimplemented("Reboot API is not supported by devices behind a hub"))
+    }
+
+    async fn get_info(&self, device: &Device) -> Result<InfoResponse, tonic::Status> {
+        let info = self.get_device_info().await.map_tapo_err(device).await?;
+        Ok(InfoResponse {
+            name: device.name.clone(),
+            rssi: Some(info.rssi as i32),
+            current_temperature: Some(info.current_temperature),
+            target_temperature: Some(info.target_temperature),
+            frost_protection: Some(info.frost_protection_on),
+            ..InfoResponse::default()
+        })
+    }
+
+    async fn get_info_json(&self, device: &Device) -> Result<InfoJsonResponse, tonic::Status> {
+        let info = self.get_device_info_json().await.map_tapo_err(device).await?;
+        let mut bytes = vec![];
+        serde_json::to_writer(&mut bytes, &info).unwrap_or_default();
+
+        Ok(InfoJsonResponse { data: bytes })
+    }
+
+    async fn get_usage(&self, _device: &Device) -> Result<UsageResponse, tonic::Status> {
+        Err(tonic::Status::unimplemented("Device usage API is not supported by radiator valves"))
+    }
+
+    async fn get_current_power(&self, _device: &Device) -> Result<CurrentPowerResponse, tonic::Status> {
+        Err(tonic::Status::unimplemented("Current power API is not supported by radiator valves"))
+    }
+
+    async fn get_firmware_info(&self, device: &Device) -> Result<FirmwareResponse, tonic::Status> {
+        let info = self.get_device_info().await.map_tapo_err(device).await?;
+        Ok(FirmwareResponse {
+            current_version: info.fw_ver,
+            hardware_version: info.hw_ver
+        })
+    }
+
+    async fn power_on(&self, _device: &Device) -> Result<PowerResponse, tonic::Status> {
+        Err(tonic::Status::unimplemented("Radiator valves can't be turned on or off. Use the frost protection instead"))
+    }
+
+    async fn power_off(&self, _device: &Device) -> Result<PowerResponse, tonic::Status> {
+        Err(tonic::Status::unimplemented("Radiator valves can't be turned on or off. Use the frost protection instead"))
+    }
+
+    async fn update(
+        &self,
+        _device: &Device,
+        power: Option<bool>,
+        _brightness: Option<u8>,
+        _temperature: Option<u16>,
+        _hue_saturation: Option<(u16, u8)>,
+        _atomic: bool
+    ) -> Result<AppliedFields, UpdateError> {
+        // the target temperature is set using `set_target_temperature` instead
+        if power.is_some() {
+            return Err(tonic::Status::unimplemented("Radiator valves can't be turned on or off. Use the frost protection instead").into());
+        }
+
+        Ok(AppliedFields::default())
+    }
+}
@@ -0,0 +1,165 @@
+use std::sync::Mutex;
+use std::time::SystemTime;
+use serde_json::json;
+
+use crate::device::Device;
+use crate::tapo::{color::{any_to_hsl, any_to_rgb}, server::rpc::{CurrentPowerResponse, FirmwareResponse, InfoJsonResponse, InfoResponse, PowerResponse, UsageResponse}, AppliedFields, TapoDeviceHandlerExt, UpdateError};
+
+/// In-memory device which simulates a color light bulb without any network access
+#[derive(Default)]
+pub struct VirtualDeviceHandler {
+    state: Mutex<VirtualState>,
+    /// Number of the next info requests which fail like with an unreachable device
+    #[cfg(test)]
+    pub failing_infos: std::sync::atomic::AtomicU32
+}
+
+#[derive(Clone, Debug)]
+struct VirtualState {
+    device_on: bool,
+    brightness: u8,
+    hue: Option<u16>,
+    saturation: Option<u8>,
+    /// Color temperature in kelvin. Zero when the color is set using hue and saturation
+    color_temp: u16,
+    on_since: Option<SystemTime>
+}
+
+impl Default for VirtualState {
+    fn default() -> Self {
+        Self { device_on: false, brightness: 100, hue: None, saturation: None, color_temp: 2700, on_since: None }
+    }
+}
+
+impl VirtualState {
+    fn set_power(&mut self, device_on: bool) {
+        if device_on && !self.device_on {
+            self.on_since = Some(SystemTime::now());
+        } else if !device_on {
+            self.on_since = None;
+        }
+        self.device_on = device_on;
+    }
+
+    fn on_time(&self) -> Option<u64> {
+        self.on_since.map(|since| since.elapsed().unwrap_or_default().as_secs())
+    }
+}
+
+impl VirtualDeviceHandler {
+    fn state(&self) -> std::sync::MutexGuard<'_, VirtualState> {
+        // the state stays consistent even if another thread panicked whilst holding the lock
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl TapoDeviceHandlerExt for VirtualDeviceHandler {
+    async fn reset(&self, _device: &Device) -> Result<(), tonic::Status> {
+        *self.state() = VirtualState::default();
+        Ok(())
+    }
+
+    async fn reboot(&self, _device: &Device) -> Result<(), tonic::Status> {
+        Ok(())
+    }
+
+    async fn get_info(&self, device: &Device) -> Result<InfoResponse, tonic::Status> {
+        #[cfg(test)]
+        {
+            use std::sync::atomic::Ordering;
+            if self.failing_infos.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| count.checked_sub(1)).is_ok() {
+                return Err(tonic::Status::unavailable("Simulated unreachable device"));
+            }
+        }
+        let state = self.state().clone();
+        let brightness = Some(state.brightness as u32);
+        let hue = state.hue.map(|v| v as u32);
+        let saturation = state.saturation.map(|v| v as u32);
+        let temperature = Some(state.color_temp as u32);
+        Ok(InfoResponse {
+            brightness,
+            hue,
+            saturation,
+            temperature,
+            device_on: Some(state.device_on),
+            on_time: state.on_time(),
+            name: device.name.clone(),
+            color: any_to_rgb(temperature, hue, saturation, brightness),
+            hsl: any_to_hsl(temperature, hue, saturation, brightness),
+            ..InfoResponse::default()
+        })
+    }
+
+    async fn get_info_json(&self, _device: &Device) -> Result<InfoJsonResponse, tonic::Status> {
+        let state = self.state().clone();
+        let info = json!({
+            "device_on": state.device_on,
+            "brightness": state.brightness,
+            "hue": state.hue,
+            "saturation": state.saturation,
+            "color_temp": state.color_temp,
+            "on_time": state.on_time(),
+            "model": "Virtual"
+        });
+
+        Ok(InfoJsonResponse { data: serde_json::to_vec(&info).unwrap_or_default() })
+    }
+
+    async fn get_usage(&self, _device: &Device) -> Result<UsageResponse, tonic::Status> {
+        Err(tonic::Status::unimplemented("Device usage API is not supported by this device type"))
+    }
+
+    async fn get_current_power(&self, _device: &Device) -> Result<CurrentPowerResponse, tonic::Status> {
+        Err(tonic::Status::unimplemented("Current power API is not supported by this device type"))
+    }
+
+    async fn get_firmware_info(&self, _device: &Device) -> Result<FirmwareResponse, tonic::Status> {
+        Ok(FirmwareResponse {
+            current_version: env!("CARGO_PKG_VERSION").to_string(),
+            hardware_version: String::from("virtual")
+        })
+    }
+
+    async fn power_on(&self, _device: &Device) -> Result<PowerResponse, tonic::Status> {
+        self.state().set_power(true);
+        Ok(PowerResponse { device_on: true })
+    }
+
+    async fn power_off(&self, _device: &Device) -> Result<PowerResponse, tonic::Status> {
+        self.state().set_power(false);
+        Ok(PowerResponse { device_on: false })
+    }
+
+    async fn update(
+        &self,
+        _device: &Device,
+        power: Option<bool>,
+        brightness: Option<u8>,
+        temperature: Option<u16>,
+        hue_saturation: Option<(u16, u8)>,
+        _atomic: bool
+    ) -> Result<AppliedFields, UpdateError> {
+        let mut state = self.state();
+        if let Some(brightness) = brightness {
+            state.brightness = brightness;
+        }
+        if let Some(temperature) = temperature {
+            state.color_temp = temperature;
+        }
+        if let Some((hue, saturation)) = hue_saturation {
+            state.hue = Some(hue);
+            state.saturation = Some(saturation);
+            state.color_temp = 0;
+        }
+
+        // changing any property turns the device on like it does on a real device
+        let changed = brightness.is_some() || temperature.is_some() || hue_saturation.is_some();
+        match power {
+            Some(false) => state.set_power(false),
+            _ if changed || power == Some(true) => state.set_power(true),
+            _ => {}
+        }
+
+        Ok(AppliedFields::all(power, brightness, temperature, hue_saturation))
+    }
+}
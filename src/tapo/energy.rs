@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use crate::tapo::server::rpc::EnergyDeltaResponse;
+
+/// Last energy readings of the devices for computing the consumption between two requests
+#[derive(Clone, Default)]
+pub struct EnergyBaselines {
+    readings: Arc<Mutex<HashMap<String, u64>>>
+}
+
+impl EnergyBaselines {
+    /// Compute the energy consumed since the previous reading of the device and keep the reading as new baseline
+    ///
+    /// A reading lower than the previous one means the counter of the device was reset (e.g. at midnight
+    /// or by a reboot) in which case the whole new reading was consumed since
+    pub fn delta(&self, device: &str, reading: u64) -> EnergyDeltaResponse {
+        let mut readings = self.readings.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let previous = readings.insert(device.to_string(), reading);
+        let reset = previous.is_some_and(|previous| reading < previous);
+        let consumed = match previous {
+            Some(previous) if !reset => reading - previous,
+            Some(_) => reading,
+            None => 0
+        };
+
+        EnergyDeltaResponse {
+            device: device.to_string(),
+            delta: consumed as f64 / 1000f64,
+            reading,
+            reset,
+            initial: previous.is_none()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_reading_is_the_baseline() {
+        let baselines = EnergyBaselines::default();
+        let response = baselines.delta("plug", 1200);
+        assert!(response.initial && !response.reset);
+        assert_eq!(response.delta, 0f64);
+    }
+
+    #[test]
+    fn delta_is_the_difference_to_the_previous_reading() {
+        let baselines = EnergyBaselines::default();
+        baselines.delta("plug", 1200);
+        let response = baselines.delta("plug", 1450);
+        assert!(!response.initial && !response.reset);
+        assert_eq!(response.delta, 0.25);
+        assert_eq!(baselines.delta("other", 300).delta, 0f64);
+    }
+
+    #[test]
+    fn midnight_rollover_counts_the_new_reading() {
+        let baselines = EnergyBaselines::default();
+        baselines.delta("plug", 5800);
+        // the daily counter restarted at midnight and counted 150 Wh since
+        let response = baselines.delta("plug", 150);
+        assert!(response.reset);
+        assert_eq!(response.delta, 0.15);
+        assert_eq!(response.reading, 150);
+
+        // the reading after the rollover is the new baseline
+        assert_eq!(baselines.delta("plug", 400).delta, 0.25);
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use log::debug;
+use tonic::body::BoxBody;
+use tonic::codegen::{http, BoxFuture, Service};
+use tonic::server::NamedService;
+use tonic::Status;
+
+/// Names of the rpc methods of the service as in the proto definition (e.g. `InfoJson`)
+const METHODS: &[&str] = include!(concat!(env!("OUT_DIR"), "/methods.rs"));
+
+/// Get the names which don't belong to any method of the service
+pub fn unknown_methods(names: &[String]) -> Vec<&String> {
+    let known = METHODS.iter().map(|method| method_name(method)).collect::<HashSet<String>>();
+    names.iter().filter(|name| !known.contains(*name)).collect()
+}
+
+/// Service which rejects all requests to methods which aren't enabled in the server config
+#[derive(Clone)]
+pub struct MethodFilter<S> {
+    inner: S,
+    enabled: Option<Arc<HashSet<String>>>
+}
+
+impl<S> MethodFilter<S> {
+    /// Wrap the service and only allow the given methods. All methods are allowed when `None`
+    pub fn new(inner: S, enabled: Option<Vec<String>>) -> Self {
+        Self { inner, enabled: enabled.map(|methods| Arc::new(methods.into_iter().collect())) }
+    }
+}
+
+impl<S: NamedService> NamedService for MethodFilter<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+impl<S, B> Service<http::Request<B>> for MethodFilter<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<BoxBody>>,
+    S::Future: Send + 'static
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let method = method_name(request.uri().path());
+        if self.enabled.as_ref().is_some_and(|enabled| !enabled.contains(&method)) {
+            debug!("Rejecting request to disabled method '{method}'");
+            let response = Status::unimplemented(format!("Method '{method}' is disabled on this server")).to_http();
+            return Box::pin(async move { Ok(response) })
+        }
+        Box::pin(self.inner.call(request))
+    }
+}
+
+/// Get the snake case name of the method from the request path (e.g. `/tapo.Tapo/InfoJson` becomes `info_json`)
+pub fn method_name(path: &str) -> String {
+    let method = path.rsplit('/').next().unwrap_or_default();
+    let mut name = String::with_capacity(method.len() + 4);
+    for (index, char) in method.chars().enumerate() {
+        if char.is_uppercase() && index > 0 {
+            name.push('_');
+        }
+        name.extend(char.to_lowercase());
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn method_names_are_snake_case() {
+        assert_eq!(method_name("/tapo.Tapo/InfoJson"), "info_json");
+        assert_eq!(method_name("/tapo.Tapo/Set"), "set");
+    }
+
+    #[test]
+    fn unknown_methods_are_reported() {
+        let names = ["info", "info_json", "set_target_temperature", "infojson", "Info", "turn_on"].map(String::from);
+        assert_eq!(unknown_methods(&names), [&names[3], &names[4], &names[5]]);
+    }
+}
@@ -0,0 +1,60 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use log::{info, warn};
+use tokio::sync::broadcast::error::RecvError;
+use crate::tapo::server::EventSender;
+use crate::tapo::server::rpc::EventResponse;
+
+/// Ring buffer of the last events which were broadcast
+#[derive(Clone)]
+pub struct EventHistory {
+    capacity: usize,
+    events: Arc<Mutex<VecDeque<EventResponse>>>
+}
+
+impl EventHistory {
+    /// Create a history which keeps at most `capacity` events. The history is disabled with a capacity of zero
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, events: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))) }
+    }
+
+    /// Record all events of the broadcast channel in the background
+    pub fn spawn_recorder(&self, sender: &EventSender) {
+        if self.capacity == 0 { return }
+        info!("Keeping the last {} events for replaying them to new subscribers", self.capacity);
+
+        let history = self.clone();
+        let mut receiver = sender.subscribe();
+        tokio::spawn(async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => history.push(event),
+                    Err(RecvError::Lagged(skipped)) => warn!("Skipped {skipped} events which couldn't be recorded fast enough"),
+                    Err(RecvError::Closed) => return
+                }
+            }
+        });
+    }
+
+    fn push(&self, event: EventResponse) {
+        let mut events = self.events.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if events.len() == self.capacity {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+
+    /// Get the last `count` events of the given types with the oldest event first
+    ///
+    /// Events of all types are returned when no types are given
+    pub fn tail(&self, types: &[i32], count: usize) -> Vec<EventResponse> {
+        let events = self.events.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut tail = events.iter().rev()
+            .filter(|event| types.is_empty() || types.contains(&event.r#type))
+            .take(count)
+            .cloned()
+            .collect::<Vec<_>>();
+        tail.reverse();
+        tail
+    }
+}
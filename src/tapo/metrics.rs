@@ -0,0 +1,206 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use tonic::body::BoxBody;
+use tonic::codegen::{http, BoxFuture, Service};
+use tonic::server::NamedService;
+use tonic::Code;
+use crate::tapo::filter::method_name;
+use crate::tapo::server::rpc::{MethodStats, StatsResponse};
+
+const LATENCY_SAMPLE_SIZE: usize = 100; // compute the latency percentiles from the 100 most recent requests per method
+const UNMEASURED_METHODS: [&str; 1] = ["stats"]; // reading the metrics of a device doesn't count as a request to it
+
+tokio::task_local! {
+    /// Devices which the request currently handled by the [`MetricsRecorder`] was resolved to
+    static REQUEST_DEVICES: Arc<Mutex<Vec<String>>>;
+}
+
+/// Attribute the request which is currently handled to the device
+///
+/// Outside of a request recorded by the [`MetricsRecorder`] (e.g. in tests calling the service directly) this does nothing
+pub fn record_device(device: &str) {
+    let _ = REQUEST_DEVICES.try_with(|devices| {
+        let Ok(mut devices) = devices.lock() else { return };
+        if !devices.iter().any(|name| name == device) {
+            devices.push(device.to_string());
+        }
+    });
+}
+
+/// In-memory request metrics of the devices grouped by the requested method
+#[derive(Default)]
+pub struct Metrics {
+    devices: HashMap<String, HashMap<String, MethodMetrics>>
+}
+
+#[derive(Default)]
+struct MethodMetrics {
+    requests: u64,
+    errors: HashMap<String, u64>,
+    latencies: VecDeque<Duration>
+}
+
+impl Metrics {
+    /// Record a finished request to a device
+    ///
+    /// Failed requests are additionally counted by their status code
+    pub fn record(&mut self, device: &str, method: &str, latency: Duration, error: Option<Code>) {
+        let metrics = self.devices.entry(device.to_string()).or_default()
+            .entry(method.to_string()).or_default();
+
+        metrics.requests += 1;
+        if let Some(code) = error {
+            *metrics.errors.entry(format!("{code:?}")).or_default() += 1;
+        }
+        if metrics.latencies.len() == LATENCY_SAMPLE_SIZE {
+            metrics.latencies.pop_front();
+        }
+        metrics.latencies.push_back(latency);
+    }
+
+    /// Get the metrics of a device ordered by the method name
+    pub fn get(&self, device: &str) -> StatsResponse {
+        let Some(methods) = self.devices.get(device) else { return StatsResponse::default() };
+        let mut methods = methods.iter().map(|(method, metrics)| {
+            let mut latencies = metrics.latencies.iter().copied().collect::<Vec<Duration>>();
+            latencies.sort();
+            MethodStats {
+                method: method.clone(),
+                requests: metrics.requests,
+                errors: metrics.errors.clone(),
+                p50_ms: percentile(&latencies, 50),
+                p95_ms: percentile(&latencies, 95),
+                p99_ms: percentile(&latencies, 99)
+            }
+        }).collect::<Vec<MethodStats>>();
+        methods.sort_by(|a, b| a.method.cmp(&b.method));
+        StatsResponse { methods }
+    }
+}
+
+/// Get the nearest-rank percentile of the sorted latencies in milliseconds
+fn percentile(sorted: &[Duration], percentile: usize) -> u32 {
+    let rank = (sorted.len() * percentile).div_ceil(100);
+    sorted.get(rank.saturating_sub(1)).map(|latency| latency.as_millis() as u32).unwrap_or_default()
+}
+
+/// Service which records the latency and the outcome of every request in the metrics of the devices it targeted
+///
+/// The devices are attributed through [`record_device`] once their name was resolved. Requests which didn't resolve
+/// any device (e.g. to unknown devices) aren't recorded to prevent arbitrary names from filling up the metrics
+#[derive(Clone)]
+pub struct MetricsRecorder<S> {
+    inner: S,
+    metrics: Arc<RwLock<Metrics>>
+}
+
+impl<S> MetricsRecorder<S> {
+    pub fn new(inner: S, metrics: Arc<RwLock<Metrics>>) -> Self {
+        Self { inner, metrics }
+    }
+}
+
+impl<S: NamedService> NamedService for MetricsRecorder<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+impl<S, B> Service<http::Request<B>> for MetricsRecorder<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<BoxBody>>,
+    S::Future: Send + 'static
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let method = method_name(request.uri().path());
+        if UNMEASURED_METHODS.contains(&method.as_str()) {
+            return Box::pin(self.inner.call(request))
+        }
+
+        let start = Instant::now();
+        let devices = Arc::new(Mutex::new(Vec::new()));
+        let response = REQUEST_DEVICES.scope(devices.clone(), self.inner.call(request));
+        let metrics = self.metrics.clone();
+        Box::pin(async move {
+            let response = response.await?;
+            let latency = start.elapsed();
+
+            // failed requests are answered without a body and carry their status code in the headers
+            let error = response.headers().get("grpc-status")
+                .map(|code| Code::from_bytes(code.as_bytes()))
+                .filter(|code| *code != Code::Ok);
+            let devices = devices.lock().map(|devices| devices.clone()).unwrap_or_default();
+            let mut metrics = metrics.write().await;
+            for device in devices {
+                metrics.record(&device, &method, latency, error);
+            }
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use tonic::Status;
+    use super::*;
+
+    /// Service which resolves the devices listed in the `devices` header and fails when asked to
+    struct DeviceService;
+
+    impl Service<http::Request<()>> for DeviceService {
+        type Response = http::Response<BoxBody>;
+        type Error = Infallible;
+        type Future = BoxFuture<Self::Response, Self::Error>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: http::Request<()>) -> Self::Future {
+            let devices = request.headers().get("devices").and_then(|devices| devices.to_str().ok()).unwrap_or_default().to_string();
+            let fail = request.headers().contains_key("fail");
+            Box::pin(async move {
+                devices.split(',').filter(|device| !device.is_empty()).for_each(record_device);
+                if fail { Ok(Status::unavailable("Device is offline").to_http()) }
+                else { Ok(http::Response::new(tonic::body::empty_body())) }
+            })
+        }
+    }
+
+    async fn send(recorder: &mut MetricsRecorder<DeviceService>, method: &str, devices: &str, fail: bool) {
+        let mut request = http::Request::builder().uri(format!("/tapo.Tapo/{method}")).header("devices", devices);
+        if fail {
+            request = request.header("fail", "");
+        }
+        recorder.call(request.body(()).unwrap()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn recorder_counts_requests_of_resolved_devices() {
+        let metrics = Arc::new(RwLock::new(Metrics::default()));
+        let mut recorder = MetricsRecorder::new(DeviceService, metrics.clone());
+
+        send(&mut recorder, "Set", "lamp", false).await;
+        send(&mut recorder, "Set", "lamp", true).await;
+        send(&mut recorder, "InfoBatch", "lamp,strip,lamp", false).await;
+        // unresolved devices and reads of the metrics aren't recorded
+        send(&mut recorder, "Set", "", true).await;
+        send(&mut recorder, "Stats", "lamp", false).await;
+
+        let stats = metrics.read().await.get("lamp");
+        let methods = stats.methods.iter().map(|stats| (stats.method.as_str(), stats.requests)).collect::<Vec<_>>();
+        assert_eq!(methods, [("info_batch", 1), ("set", 2)]);
+        assert_eq!(stats.methods[1].errors.get("Unavailable"), Some(&1));
+        assert_eq!(metrics.read().await.get("strip").methods.len(), 1);
+    }
+}
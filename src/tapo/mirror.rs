@@ -0,0 +1,298 @@
+use std::collections::HashMap;
+use std::process::exit;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use log::{error, info, warn};
+use tokio::sync::RwLock;
+use tonic::codec::CompressionEncoding;
+use tonic::codegen::tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+use crate::tapo::history::EventHistory;
+use crate::tapo::server::rpc::tapo_client::TapoClient;
+use crate::tapo::server::rpc::tapo_server::{Tapo, TapoServer};
+use crate::tapo::server::rpc::{self, AuditResponse, BulkResponse, CancelResponse, CurrentPowerResponse, DeviceRequest, DevicesResponse, Empty, EnergyDeltaResponse, EventRequest, EventResponse, EventType, FirmwareResponse, InfoBatchRequest, InfoBatchResponse, InfoJsonResponse, InfoResponse, LogRecord, OffRequest, PowerManyRequest, PowerResponse, ResetConfirmRequest, ResetToken, SensorRequest, SensorResponse, SetManyRequest, SetRequest, SleepRequest, StatsResponse, StatusResponse, TargetTemperatureRequest, UsageResponse, VersionResponse};
+use crate::tapo::server::{device_info_result, subscribe_events, EventChannel, EventSender};
+use crate::tapo::state::State;
+
+const RECONNECT_DELAY_SECS: u64 = 5; // wait 5 seconds before connecting to the upstream server again
+
+/// Read-only replica of another server which mirrors its devices and their states through its event stream
+///
+/// The mirror doesn't log into any device. All requests which would have to access a device are rejected
+#[derive(Clone)]
+pub struct MirrorService {
+    upstream: String,
+    devices: Arc<RwLock<HashMap<String, rpc::Device>>>,
+    state: Arc<RwLock<State>>,
+    history: EventHistory,
+    start_time: SystemTime,
+    started: Instant,
+    channel: Arc<EventChannel>
+}
+
+impl MirrorService {
+    pub fn new(upstream: String, channel: EventChannel, history: EventHistory) -> Self {
+        Self {
+            upstream,
+            devices: Arc::new(RwLock::new(HashMap::new())),
+            state: Arc::new(RwLock::new(State::new(channel.0.clone(), None))),
+            history,
+            start_time: SystemTime::now(),
+            started: Instant::now(),
+            channel: Arc::new(channel)
+        }
+    }
+
+    /// Keep the devices and their states in sync with the upstream server in the background
+    ///
+    /// The connection is established again after a delay whenever it's lost
+    pub fn spawn_sync(&self) {
+        let mirror = self.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(status) = mirror.sync().await {
+                    warn!("Lost connection to upstream server {}: {}. Reconnecting in {RECONNECT_DELAY_SECS}s...", mirror.upstream, status.message());
+                }
+                tokio::time::sleep(Duration::from_secs(RECONNECT_DELAY_SECS)).await;
+            }
+        });
+    }
+
+    /// Copy the devices and their states from the upstream server and apply its events until the stream ends
+    async fn sync(&self) -> Result<(), Status> {
+        let mut client = TapoClient::connect(self.upstream.clone()).await
+            .map_err(|err| Status::unavailable(err.to_string()))?
+            .accept_compressed(CompressionEncoding::Gzip);
+
+        // the subscription is set up before the states are copied so no change is lost in between
+        let mut events = client.events(EventRequest { types: vec![], replay_count: 0 }).await?.into_inner();
+        let devices = client.devices(Empty {}).await?.into_inner().devices;
+        let names = devices.iter().map(|device| device.name.clone()).collect();
+        *self.devices.write().await = devices.into_iter().map(|device| (device.name.clone(), device)).collect();
+
+        let batch = client.info_batch(InfoBatchRequest { devices: names, fresh: false }).await?.into_inner();
+        for result in batch.results {
+            if let Some(info) = result.info {
+                self.state.write().await.update_info_optimistically(info.name.clone(), info);
+            }
+        }
+        info!("Mirroring {} devices of upstream server {}", self.devices.read().await.len(), self.upstream);
+
+        while let Some(event) = events.message().await? {
+            self.apply(event).await;
+        }
+        Err(Status::unavailable("Event stream closed"))
+    }
+
+    /// Update the mirrored devices and states with the upstream event and forward it to the own subscribers
+    async fn apply(&self, event: EventResponse) {
+        match EventType::try_from(event.r#type) {
+            Ok(EventType::DeviceStateChange) => match serde_json::from_slice::<InfoResponse>(&event.body) {
+                // the state sends the change to the subscribers itself
+                Ok(info) => return self.state.write().await.update_info_optimistically(info.name.clone(), info),
+                Err(err) => warn!("Unable to decode mirrored device state: {err}")
+            },
+            Ok(EventType::DeviceAuthChange) => match serde_json::from_slice::<rpc::Device>(&event.body) {
+                Ok(device) => { self.devices.write().await.insert(device.name.clone(), device); },
+                Err(err) => warn!("Unable to decode mirrored device: {err}")
+            },
+            _ => {}
+        }
+
+        // there being no subscribers isn't an error
+        let _ = self.sender().send(event);
+    }
+
+    fn sender(&self) -> &EventSender {
+        &self.channel.0
+    }
+
+    /// Get the last mirrored state of a device
+    async fn mirrored_info(&self, device: &str) -> Result<InfoResponse, Status> {
+        if !self.devices.read().await.contains_key(device) {
+            return Err(Status::not_found(format!("Device '{device}' could not be found")))
+        }
+        self.state.read().await.get_last_info(device)
+            .ok_or_else(|| Status::unavailable(format!("The state of device '{device}' wasn't mirrored yet")))
+    }
+
+    fn read_only(&self) -> Status {
+        Status::unimplemented(format!("This server is a read-only mirror of {}", self.upstream))
+    }
+}
+
+#[tonic::async_trait]
+impl Tapo for MirrorService {
+    /// Get a list of all mirrored devices
+    async fn devices(&self, _: Request<Empty>) -> Result<Response<DevicesResponse>, Status> {
+        let mut devices = self.devices.read().await.values().cloned().collect::<Vec<_>>();
+        devices.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(Response::new(DevicesResponse { devices }))
+    }
+
+    /// Get the version and the uptime of the mirror
+    async fn version(&self, _: Request<Empty>) -> Result<Response<VersionResponse>, Status> {
+        Ok(Response::new(VersionResponse {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            start_time: self.start_time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+            uptime: self.started.elapsed().as_secs()
+        }))
+    }
+
+    type EventsStream = ReceiverStream<Result<EventResponse, Status>>;
+
+    /// Subscribe to the events which are forwarded from the upstream server
+    async fn events(&self, request: Request<EventRequest>) -> Result<Response<Self::EventsStream>, Status> {
+        Ok(Response::new(subscribe_events(&self.channel, &self.history, request.into_inner())))
+    }
+
+    type LogsStream = ReceiverStream<Result<LogRecord, Status>>;
+
+    async fn logs(&self, _: Request<Empty>) -> Result<Response<Self::LogsStream>, Status> {
+        Err(self.read_only())
+    }
+
+    async fn reset(&self, _: Request<DeviceRequest>) -> Result<Response<Empty>, Status> {
+        Err(self.read_only())
+    }
+
+    async fn reset_prepare(&self, _: Request<DeviceRequest>) -> Result<Response<ResetToken>, Status> {
+        Err(self.read_only())
+    }
+
+    async fn reset_confirm(&self, _: Request<ResetConfirmRequest>) -> Result<Response<Empty>, Status> {
+        Err(self.read_only())
+    }
+
+    async fn reboot(&self, _: Request<DeviceRequest>) -> Result<Response<Empty>, Status> {
+        Err(self.read_only())
+    }
+
+    /// Get the last mirrored state of the device
+    ///
+    /// The state can't be refreshed by the mirror which is why fresh infos are rejected
+    async fn info(&self, request: Request<DeviceRequest>) -> Result<Response<InfoResponse>, Status> {
+        let inner = request.into_inner();
+        if inner.fresh {
+            return Err(self.read_only())
+        }
+        self.mirrored_info(&inner.device).await.map(Response::new)
+    }
+
+    /// Get the last mirrored states of multiple devices at once
+    async fn info_batch(&self, request: Request<InfoBatchRequest>) -> Result<Response<InfoBatchResponse>, Status> {
+        let inner = request.into_inner();
+        let mut results = vec![];
+        for device in inner.devices {
+            let info = if inner.fresh { Err(self.read_only()) } else { self.mirrored_info(&device).await };
+            results.push(device_info_result(device, info));
+        }
+
+        Ok(Response::new(InfoBatchResponse { results }))
+    }
+
+    async fn info_json(&self, _: Request<DeviceRequest>) -> Result<Response<InfoJsonResponse>, Status> {
+        Err(self.read_only())
+    }
+
+    async fn status(&self, _: Request<DeviceRequest>) -> Result<Response<StatusResponse>, Status> {
+        Err(self.read_only())
+    }
+
+    async fn usage(&self, _: Request<DeviceRequest>) -> Result<Response<UsageResponse>, Status> {
+        Err(self.read_only())
+    }
+
+    async fn current_power(&self, _: Request<DeviceRequest>) -> Result<Response<CurrentPowerResponse>, Status> {
+        Err(self.read_only())
+    }
+
+    async fn energy_delta(&self, _: Request<DeviceRequest>) -> Result<Response<EnergyDeltaResponse>, Status> {
+        Err(self.read_only())
+    }
+
+    async fn sensor(&self, _: Request<SensorRequest>) -> Result<Response<SensorResponse>, Status> {
+        Err(self.read_only())
+    }
+
+    async fn set_target_temperature(&self, _: Request<TargetTemperatureRequest>) -> Result<Response<InfoResponse>, Status> {
+        Err(self.read_only())
+    }
+
+    async fn firmware_info(&self, _: Request<DeviceRequest>) -> Result<Response<FirmwareResponse>, Status> {
+        Err(self.read_only())
+    }
+
+    async fn on(&self, _: Request<DeviceRequest>) -> Result<Response<PowerResponse>, Status> {
+        Err(self.read_only())
+    }
+
+    async fn off(&self, _: Request<OffRequest>) -> Result<Response<PowerResponse>, Status> {
+        Err(self.read_only())
+    }
+
+    async fn toggle(&self, _: Request<DeviceRequest>) -> Result<Response<PowerResponse>, Status> {
+        Err(self.read_only())
+    }
+
+    async fn power_many(&self, _: Request<PowerManyRequest>) -> Result<Response<BulkResponse>, Status> {
+        Err(self.read_only())
+    }
+
+    async fn sleep(&self, _: Request<SleepRequest>) -> Result<Response<Empty>, Status> {
+        Err(self.read_only())
+    }
+
+    async fn set(&self, _: Request<SetRequest>) -> Result<Response<InfoResponse>, Status> {
+        Err(self.read_only())
+    }
+
+    async fn set_many(&self, _: Request<SetManyRequest>) -> Result<Response<BulkResponse>, Status> {
+        Err(self.read_only())
+    }
+
+    async fn cancel_operation(&self, _: Request<DeviceRequest>) -> Result<Response<CancelResponse>, Status> {
+        Err(self.read_only())
+    }
+
+    async fn audit(&self, _: Request<DeviceRequest>) -> Result<Response<AuditResponse>, Status> {
+        Err(self.read_only())
+    }
+
+    async fn stats(&self, _: Request<DeviceRequest>) -> Result<Response<StatsResponse>, Status> {
+        Err(self.read_only())
+    }
+}
+
+/// Serve a read-only mirror of the devices and states of the upstream server
+pub async fn start_mirror(upstream: String, port: u16, event_history: usize) {
+    let (tx, rx) = tokio::sync::broadcast::channel(10);
+
+    let format = format!("0.0.0.0:{port}");
+    let addr = match format.parse() {
+        Ok(addr) => addr,
+        Err(_) => {
+            error!("'{format}' is not a valid socket address");
+            exit(1)
+        }
+    };
+
+    let history = EventHistory::new(event_history);
+    history.spawn_recorder(&tx);
+
+    let mirror = MirrorService::new(upstream.clone(), (tx, rx), history);
+    mirror.spawn_sync();
+
+    let svc = TapoServer::new(mirror)
+        .accept_compressed(CompressionEncoding::Gzip)
+        .send_compressed(CompressionEncoding::Gzip);
+    info!("Starting mirror of {upstream} at {format}");
+    match Server::builder().add_service(svc).serve(addr).await {
+        Ok(_) => info!("Stopped mirror"),
+        Err(err) => {
+            error!("Unable to serve at {format}. Reason: {err}");
+            exit(1)
+        }
+    }
+}
@@ -1,46 +1,130 @@
 use std::collections::HashMap;
 use std::process::exit;
+use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
-use log::{error, info};
+use log::{debug, error, info, warn, LevelFilter};
 use serde::Serialize;
 use serde_json::json;
-use server::rpc::{Empty, InfoJsonResponse, PowerResponse, UsageResponse};
+use server::rpc::{CurrentPowerResponse, Empty, FirmwareResponse, InfoJsonResponse, PowerResponse, SensorResponse, UsageResponse};
 use spinoff::Spinner;
 use tapo::ApiClient;
 use tokio::sync::RwLock;
+use tonic::codec::CompressionEncoding;
 use tonic::transport::Server;
 use tonic::Response;
 use crate::cli::SpinnerOpt;
-use crate::config::ServerConfig;
+use crate::config::{NightLimit, ServerConfig, SupportedDevice};
 use crate::device::Device;
 use crate::tapo::server::rpc::{EventResponse, EventType, InfoResponse, SessionStatus};
 use crate::tapo::server::rpc::tapo_server::TapoServer;
-use crate::tapo::server::{rpc, TapoService};
+use crate::tapo::server::{rpc, EventSender, TapoService};
+use crate::tapo::smoothing::PowerSmoothing;
+use crate::tapo::state::State;
+use crate::tapo::history::EventHistory;
+use crate::tapo::filter::{unknown_methods, MethodFilter};
+use crate::tapo::metrics::MetricsRecorder;
+use crate::tapo::webhook::{spawn_webhooks, Webhook};
+use crate::tapo::notifier::{spawn_notifiers, Notifier};
 
 pub mod server;
-mod color;
+pub mod color;
 mod state;
-mod print;
-mod device;
-mod validation;
-
-pub async fn start_server(port: Option<u16>, config: Option<ServerConfig>) {
+pub mod print;
+pub mod device;
+pub mod validation;
+mod operation;
+mod audit;
+mod metrics;
+mod smoothing;
+mod energy;
+mod filter;
+mod redaction;
+mod history;
+mod webhook;
+mod notifier;
+pub mod mirror;
+
+const SAFETY_CUTOFF_INTERVAL_SECS: u64 = 30; // check the on-time of devices with a maximum on-time every 30 seconds
+
+pub const REBOOT_DELAY_SECS: u16 = 1; // gives the device time to respond before it goes down
+const BLINK_INTERVAL_MILLIS: u64 = 500;
+const FADE_STEPS: u32 = 10;
+
+pub async fn start_server(port: Option<u16>, config: Option<ServerConfig>, strict: bool) {
     let Some(config) = config else {
         error!("Please specify a server config for setting up the server");
         exit(1);
     };
 
+    if let Some(Err(err)) = config.night.as_ref().map(NightLimit::validate) {
+        error!("Invalid night limit: {err}");
+        exit(1)
+    }
+    for (name, definition) in &config.devices {
+        if definition.on_temperature.is_some_and(|temperature| !(2500..=6500).contains(&temperature)) {
+            error!("'on_temperature' of device '{name}' is not in range 2500 to 6500 kelvin");
+            exit(1)
+        }
+        if definition.on_brightness.is_some_and(|brightness| !(1..=100).contains(&brightness)) {
+            error!("'on_brightness' of device '{name}' is not in range 1 to 100");
+            exit(1)
+        }
+        let calibration = &definition.calibration;
+        if calibration.saturation_scale <= 0f64 || calibration.brightness_scale <= 0f64 {
+            error!("The calibration scales of device '{name}' have to be greater than 0");
+            exit(1)
+        }
+        if matches!(definition.device_type, SupportedDevice::KE100) && definition.child.is_none() {
+            error!("The device '{name}' of type KE100 requires the nickname of the valve paired with the hub at its address as `child`");
+            exit(1)
+        }
+        if let Some(Err(err)) = definition.night.as_ref().map(NightLimit::validate) {
+            error!("Invalid night limit of device '{name}': {err}");
+            exit(1)
+        }
+        let capabilities = definition.device_type.capabilities().names();
+        let capabilities = if capabilities.is_empty() { String::from("power only") } else { capabilities.join(", ") };
+        debug!("Device '{name}' ({}) supports: {capabilities}", definition.device_type);
+    }
+
+    if let Some(methods) = &config.enabled_methods {
+        let unknown = unknown_methods(methods);
+        if !unknown.is_empty() {
+            let unknown = unknown.iter().map(|method| format!("'{method}'")).collect::<Vec<String>>().join(", ");
+            error!("The enabled methods {unknown} aren't methods of the server. The names are the snake case names of the methods in the proto definition");
+            exit(1)
+        }
+    }
+
+    let mut warnings = config.capability_warnings();
+    warnings.extend(config.duplicate_address_warnings());
+    if strict && !warnings.is_empty() {
+        warnings.iter().for_each(|warning| error!("{warning}"));
+        error!("Found {} problem(s) in the server config", warnings.len());
+        exit(1);
+    }
+    warnings.iter().for_each(|warning| warn!("{warning}"));
+
     let mut devices = HashMap::<String, Arc<RwLock<Device>>>::new();
     let (tx, rx) = tokio::sync::broadcast::channel(10);
 
     info!("Starting device login phase");
 
-    let devices_async = config.devices.into_iter().map(|(name, definition)| {
+    let max_on_times = config.devices.iter()
+        .filter_map(|(name, definition)| definition.max_on_time.map(|max_on_time| (name.clone(), max_on_time)))
+        .collect::<HashMap<String, u64>>();
+
+    // the login futures are polled in order which lets the devices with a higher priority send their login first
+    let mut definitions = config.devices.clone().into_iter().collect::<Vec<_>>();
+    definitions.sort_by(|(a_name, a), (b_name, b)| b.priority.cmp(&a.priority).then_with(|| a_name.cmp(b_name)));
+
+    let devices_async = definitions.into_iter().map(|(name, mut definition)| {
+        definition.night = definition.night.or(config.night.clone());
         // give every device its own client for more parallelism since it seems as if sharing the same client
         // causes blocking when sending requests for multiple devices in a short period of time
         let client = ApiClient::new(&config.auth.username, &config.auth.password).with_timeout(Duration::from_millis(config.timeout as u64));
-        Device::new(name, definition, client, tx.clone())
+        Device::new(name, definition, client, tx.clone(), config.auto_relogin)
     });
 
     futures::future::join_all(devices_async).await.into_iter()
@@ -62,7 +146,81 @@ pub async fn start_server(port: Option<u16>, config: Option<ServerConfig>) {
         }
     };
 
-    let svc = TapoServer::new(TapoService::new(devices, (tx, rx)));
+    let smoothing = config.power_smoothing.map(|factor| {
+        if !(factor > 0f64 && factor <= 1f64) {
+            error!("'{factor}' is not a valid power smoothing factor. It has to be greater than 0 and at most 1");
+            exit(1)
+        }
+        PowerSmoothing::new(factor)
+    });
+
+    if let Some(interval) = config.energy_interval {
+        spawn_energy_poller(devices.values().cloned().collect(), tx.clone(), Duration::from_secs(interval), smoothing.clone());
+    }
+
+    let webhooks = config.webhooks.iter().map(|webhook| {
+        let events = webhook.events.iter().map(|name| match EventType::from_str_name(name) {
+            Some(event_type) => event_type,
+            None => {
+                error!("'{name}' of webhook {} is not a valid event type", webhook.url);
+                exit(1)
+            }
+        }).collect();
+        Webhook { url: webhook.url.clone(), events }
+    }).collect::<Vec<_>>();
+    if !webhooks.is_empty() {
+        spawn_webhooks(webhooks, &tx, Duration::from_millis(config.timeout as u64));
+    }
+
+    let notifiers = config.notifiers.iter().map(|notifier| {
+        let Some(event) = EventType::from_str_name(&notifier.event) else {
+            error!("'{}' of the notifier of device '{}' is not a valid event type", notifier.event, notifier.device);
+            exit(1)
+        };
+        let Some(device) = devices.get(&notifier.device) else {
+            error!("Notifier device '{}' could not be found", notifier.device);
+            exit(1)
+        };
+        Notifier { event, name: notifier.device.clone(), device: device.clone() }
+    }).collect::<Vec<_>>();
+    if !notifiers.is_empty() {
+        spawn_notifiers(notifiers, &tx);
+    }
+
+    let logs = config.log_level.as_ref().map(|level| match LevelFilter::from_str(level) {
+        Ok(level) => {
+            info!("Streaming log records up to level {level} to subscribed clients");
+            crate::logging::enable_forwarding(level)
+        },
+        Err(_) => {
+            error!("'{level}' is not a valid log level");
+            exit(1)
+        }
+    });
+
+    let history = EventHistory::new(config.event_history);
+    history.spawn_recorder(&tx);
+
+    let cutoff_devices = max_on_times.into_iter()
+        .filter_map(|(name, max_on_time)| devices.get(&name).map(|device| (device.clone(), max_on_time)))
+        .collect::<Vec<_>>();
+    let cutoff_sender = tx.clone();
+
+    let service = TapoService::new(devices, (tx, rx), &config, logs, smoothing, history);
+    if !cutoff_devices.is_empty() {
+        spawn_safety_cutoff(cutoff_devices, service.state(), cutoff_sender);
+    }
+    let metrics = service.metrics();
+    let mut svc = TapoServer::new(service)
+        .accept_compressed(CompressionEncoding::Gzip);
+    // responses are only compressed when the client accepts gzip compressed responses
+    if config.compression {
+        svc = svc.send_compressed(CompressionEncoding::Gzip);
+    }
+    if let Some(methods) = &config.enabled_methods {
+        info!("Only enabling the methods {}", methods.join(", "));
+    }
+    let svc = MethodFilter::new(MetricsRecorder::new(svc, metrics), config.enabled_methods.clone());
     info!("Starting server at {format}");
     match Server::builder().add_service(svc).serve(addr).await {
         Ok(_) => info!("Stopped server"),
@@ -73,10 +231,123 @@ pub async fn start_server(port: Option<u16>, config: Option<ServerConfig>) {
     }
 }
 
-pub fn create_event(event_type: EventType, body: impl Serialize) -> EventResponse {
-    let mut bytes = vec![];
-    serde_json::to_writer(&mut bytes, &body).unwrap_or_default();
-    EventResponse { body: bytes, r#type: i32::from(event_type) }
+/// Periodically broadcast the current power draw of all energy-capable devices
+///
+/// Devices which don't support the current power api are skipped silently
+fn spawn_energy_poller(devices: Vec<Arc<RwLock<Device>>>, sender: EventSender, interval: Duration, smoothing: Option<PowerSmoothing>) {
+    info!("Broadcasting energy updates every {}s", interval.as_secs());
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            for device in &devices {
+                let mut device = device.write().await;
+                if device.try_refresh_session().await.is_err() { continue }
+
+                match device.get_current_power().await {
+                    Ok(response) => {
+                        let mut response = response.into_inner();
+                        if let Some(smoothing) = &smoothing {
+                            smoothing.apply(&mut response);
+                        }
+                        let Some(event) = create_event(EventType::EnergyUpdate, response) else { continue };
+                        if let Err(err) = sender.send(event) {
+                            debug!("Error whilst sending energy update: {err}")
+                        }
+                    },
+                    Err(status) if status.code() == tonic::Code::Unimplemented => {},
+                    Err(status) => warn!("Unable to get current power of device '{}': {}", device.name, status.message())
+                }
+            }
+        }
+    });
+}
+
+/// Periodically turn off devices which are turned on for longer than their maximum on-time
+///
+/// Every cutoff is sent as a safety cutoff event to the subscribed clients. The on-time is read through the
+/// shared state cache which also receives the new state after the cutoff
+fn spawn_safety_cutoff(devices: Vec<(Arc<RwLock<Device>>, u64)>, state: Arc<RwLock<State>>, sender: EventSender) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(SAFETY_CUTOFF_INTERVAL_SECS));
+        loop {
+            interval.tick().await;
+            for (device, max_on_time) in &devices {
+                let mut device = device.write().await;
+                if device.try_refresh_session().await.is_err() { continue }
+
+                let mut info = match state.write().await.get_info(&device).await {
+                    Ok(info) => info,
+                    Err(status) => {
+                        warn!("Unable to get the on-time of device '{}': {}", device.name, status.message());
+                        continue
+                    }
+                };
+                let on_time = info.on_time.unwrap_or_default();
+                if !info.device_on.unwrap_or_default() || on_time < *max_on_time { continue }
+
+                warn!("Device '{}' is turned on for {on_time}s which exceeds its maximum on-time of {max_on_time}s. Turning it off", device.name);
+                if let Err(status) = device.off().await {
+                    error!("Unable to turn off device '{}' after exceeding its maximum on-time: {}", device.name, status.message());
+                    continue
+                }
+                info.device_on = Some(false);
+                info.on_time = Some(0);
+                state.write().await.update_info_optimistically(device.name.clone(), info);
+
+                let cutoff = rpc::SafetyCutoffEvent { device: device.name.clone(), on_time, max_on_time: *max_on_time };
+                let Some(event) = create_event(EventType::SafetyCutoff, cutoff) else { continue };
+                if let Err(err) = sender.send(event) {
+                    error!("Error whilst sending safety cutoff: {err}")
+                }
+            }
+        }
+    });
+}
+
+/// Let the device blink by turning it off and on again
+///
+/// The device is turned off again afterwards should it have been turned off before. The callback is called with
+/// the number of finished blinks after every blink
+pub async fn blink(device: &Device, count: u32, device_on: bool, on_blink: impl Fn(u32)) -> Result<(), tonic::Status> {
+    for blink in 1..=count {
+        device.off().await?;
+        tokio::time::sleep(Duration::from_millis(BLINK_INTERVAL_MILLIS)).await;
+        device.on().await?;
+        tokio::time::sleep(Duration::from_millis(BLINK_INTERVAL_MILLIS)).await;
+        on_blink(blink);
+    }
+
+    // the device was turned on by the blinking
+    if !device_on {
+        device.off().await?;
+    }
+    Ok(())
+}
+
+/// Lower the brightness of the device in steps over the duration before turning it off
+pub async fn fade_off(device: &Device, brightness: u8, duration: Duration) -> Result<Response<PowerResponse>, tonic::Status> {
+    let steps = FADE_STEPS.min(brightness.saturating_sub(1) as u32);
+    for step in 1..=steps {
+        let value = brightness as u32 - (brightness as u32 - 1) * step / steps;
+        device.set(InfoResponse::default(), None, Some(value as u8), None, None, false).await.map_err(|err| err.status)?;
+        tokio::time::sleep(duration / steps).await;
+    }
+    device.off().await
+}
+
+/// Create an event with the json serialized body
+///
+/// Returns `None` should the body fail to serialize. The failure is logged and the event shouldn't be
+/// broadcast since subscribers are unable to decode an empty body
+pub fn create_event(event_type: EventType, body: impl Serialize) -> Option<EventResponse> {
+    match serde_json::to_vec(&body) {
+        Ok(bytes) => Some(EventResponse { body: bytes, r#type: i32::from(event_type) }),
+        Err(err) => {
+            error!("Unable to serialize body of {} event: {err}", event_type.as_str_name());
+            None
+        }
+    }
 }
 
 pub trait TapoRpcColorExt {
@@ -99,10 +370,83 @@ impl TapoSessionStatusExt for crate::device::SessionStatus {
     }
 }
 
+/// Properties which were confirmed to be applied by a device update
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct AppliedFields {
+    pub power: bool,
+    pub brightness: bool,
+    pub temperature: bool,
+    pub hue_saturation: bool
+}
+
+impl AppliedFields {
+    /// Fields of an update which is applied as a whole
+    pub fn all(power: Option<bool>, brightness: Option<u8>, temperature: Option<u16>, hue_saturation: Option<(u16, u8)>) -> Self {
+        Self {
+            power: power.is_some(),
+            brightness: brightness.is_some(),
+            temperature: temperature.is_some(),
+            hue_saturation: hue_saturation.is_some()
+        }
+    }
+
+    /// Boolean whether any of the properties was applied
+    pub fn any(&self) -> bool {
+        self.power || self.brightness || self.temperature || self.hue_saturation
+    }
+
+    /// Turn a failed property update into an update error which keeps the previously applied properties
+    pub fn failed(self, status: tonic::Status) -> UpdateError {
+        UpdateError { applied: self, status }
+    }
+
+    /// Merge the applied properties of the requested state into the previous state
+    ///
+    /// Changing any property turns the device on. Therefore, the device is expected to be
+    /// on if any property besides the power was applied
+    pub fn merge(&self, previous: InfoResponse, requested: InfoResponse) -> InfoResponse {
+        let mut info = previous;
+        if self.brightness {
+            info.brightness = requested.brightness;
+        }
+        if self.temperature {
+            info.temperature = requested.temperature;
+        }
+        if self.hue_saturation {
+            info.hue = requested.hue;
+            info.saturation = requested.saturation;
+        }
+        if self.power {
+            info.device_on = requested.device_on;
+            info.on_time = requested.on_time;
+        } else if self.any() {
+            info.on_time = info.on_time.or(Some(0));
+            info.device_on = Some(true);
+        }
+        info
+    }
+}
+
+/// Error of a device update which might have applied some of the properties before failing
+#[derive(Debug)]
+pub struct UpdateError {
+    pub applied: AppliedFields,
+    pub status: tonic::Status
+}
+
+impl From<tonic::Status> for UpdateError {
+    fn from(status: tonic::Status) -> Self {
+        Self { applied: AppliedFields::default(), status }
+    }
+}
+
 pub trait TapoDeviceExt {
     /// Reset the device to factory defaults
     async fn reset(&self) -> Result<Response<Empty>, tonic::Status>;
 
+    /// Reboot the device without changing its settings
+    async fn reboot(&self) -> Result<Response<Empty>, tonic::Status>;
+
     /// Get some information about the device
     async fn get_info(&self) -> Result<Response<InfoResponse>, tonic::Status>;
 
@@ -112,6 +456,12 @@ pub trait TapoDeviceExt {
     /// Get the power and energy usage of the device
     async fn get_usage(&self) -> Result<Response<UsageResponse>, tonic::Status>;
 
+    /// Get the current power draw of the device
+    async fn get_current_power(&self) -> Result<Response<CurrentPowerResponse>, tonic::Status>;
+
+    /// Get the installed firmware and hardware version
+    async fn get_firmware_info(&self) -> Result<Response<FirmwareResponse>, tonic::Status>;
+
     /// Power the device on
     async fn on(&self) -> Result<Response<PowerResponse>, tonic::Status>;
 
@@ -125,14 +475,24 @@ pub trait TapoDeviceExt {
         power: Option<bool>,
         brightness: Option<u8>,
         temperature: Option<u16>,
-        hue_saturation: Option<(u16, u8)>
-    ) -> Result<Response<InfoResponse>, tonic::Status>;
+        hue_saturation: Option<(u16, u8)>,
+        atomic: bool
+    ) -> Result<Response<InfoResponse>, UpdateError>;
+
+    /// Get the latest reading of a child sensor of the hub
+    async fn get_sensor(&self, child: &str) -> Result<Response<SensorResponse>, tonic::Status>;
+
+    /// Set the target temperature in celsius and the frost protection of a radiator valve
+    async fn set_target_temperature(&self, temperature: Option<u8>, frost_protection: Option<bool>) -> Result<Response<Empty>, tonic::Status>;
 }
 
 pub trait TapoDeviceHandlerExt {
     /// Reset the device to factory defaults
     async fn reset(&self, device: &Device) -> Result<(), tonic::Status>;
 
+    /// Reboot the device without changing its settings
+    async fn reboot(&self, device: &Device) -> Result<(), tonic::Status>;
+
     /// Get some information about the device
     async fn get_info(&self, device: &Device) -> Result<InfoResponse, tonic::Status>;
 
@@ -142,6 +502,12 @@ pub trait TapoDeviceHandlerExt {
     /// Get the power and energy usage of the device
     async fn get_usage(&self, device: &Device) -> Result<UsageResponse, tonic::Status>;
 
+    /// Get the current power draw of the device
+    async fn get_current_power(&self, device: &Device) -> Result<CurrentPowerResponse, tonic::Status>;
+
+    /// Get the installed firmware and hardware version
+    async fn get_firmware_info(&self, device: &Device) -> Result<FirmwareResponse, tonic::Status>;
+
     /// Power the device on
     async fn power_on(&self, device: &Device) -> Result<PowerResponse, tonic::Status>;
 
@@ -149,14 +515,19 @@ pub trait TapoDeviceHandlerExt {
     async fn power_off(&self, device: &Device) -> Result<PowerResponse, tonic::Status>;
 
     /// Set multiple properties of the device at once
+    ///
+    /// When `atomic` is set the handler tries to restore the previous state of the device
+    /// should any of the property updates fail. The returned fields contain the properties which
+    /// were confirmed to be applied, on failure they're part of the error
     async fn update(
         &self,
         device: &Device,
         power: Option<bool>,
         brightness: Option<u8>,
         temperature: Option<u16>,
-        hue_saturation: Option<(u16, u8)>
-    ) -> Result<(), tonic::Status>;
+        hue_saturation: Option<(u16, u8)>,
+        atomic: bool
+    ) -> Result<AppliedFields, UpdateError>;
 }
 
 pub trait TonicErrMap<R> {
@@ -0,0 +1,80 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use log::{debug, info, warn};
+use serde_json::Value;
+use tokio::sync::RwLock;
+use tokio::sync::broadcast::error::RecvError;
+use crate::device::Device;
+use crate::tapo::{blink, TapoDeviceExt};
+use crate::tapo::server::EventSender;
+use crate::tapo::server::rpc::{EventResponse, EventType, SessionStatus};
+
+const NOTIFIER_BLINK_COUNT: u32 = 2;
+
+/// Device which blinks whenever an event of the configured type is broadcast
+pub struct Notifier {
+    pub event: EventType,
+    pub name: String,
+    pub device: Arc<RwLock<Device>>
+}
+
+/// Let the notifier devices blink on matching events of the broadcast channel
+///
+/// To prevent feedback loops events which originate from the notifier device itself are ignored as are all
+/// events which are broadcast whilst the notifier device is blinking. Authentication changes only trigger
+/// the notifiers when a device loses its session
+pub fn spawn_notifiers(notifiers: Vec<Notifier>, sender: &EventSender) {
+    notifiers.iter().for_each(|notifier| info!("Device '{}' blinks on {} events", notifier.name, notifier.event.as_str_name()));
+    let notifiers = notifiers.into_iter().map(|notifier| (notifier, Arc::new(AtomicBool::new(false)))).collect::<Vec<_>>();
+
+    let mut receiver = sender.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Skipped {skipped} events which couldn't be handled by the notifiers fast enough");
+                    continue
+                },
+                Err(RecvError::Closed) => return
+            };
+            let Ok(body) = serde_json::from_slice::<Value>(event.body.as_slice()) else { continue };
+            if !triggers(&event, &body) { continue }
+            let source = body.get("name").or(body.get("device")).and_then(Value::as_str);
+
+            for (notifier, blinking) in &notifiers {
+                if i32::from(notifier.event) != event.r#type || source == Some(notifier.name.as_str()) {
+                    continue
+                }
+                // events which are broadcast whilst the device is still blinking are dropped
+                if blinking.swap(true, Ordering::AcqRel) {
+                    continue
+                }
+
+                debug!("Notifying with device '{}' about {} event", notifier.name, notifier.event.as_str_name());
+                let (name, device, blinking) = (notifier.name.clone(), notifier.device.clone(), blinking.clone());
+                tokio::spawn(async move {
+                    if let Err(status) = notify(&device).await {
+                        warn!("Unable to notify with device '{name}': {}", status.message());
+                    }
+                    blinking.store(false, Ordering::Release);
+                });
+            }
+        }
+    });
+}
+
+/// Whether the event should trigger the notifiers of its type
+fn triggers(event: &EventResponse, body: &Value) -> bool {
+    if event.r#type != i32::from(EventType::DeviceAuthChange) {
+        return true
+    }
+    body.get("status").and_then(Value::as_i64) != Some(i64::from(i32::from(SessionStatus::Authenticated)))
+}
+
+async fn notify(device: &RwLock<Device>) -> Result<(), tonic::Status> {
+    let mut device = device.write().await;
+    device.try_refresh_session().await?;
+    let device_on = device.get_info().await?.into_inner().device_on.unwrap_or_default();
+    blink(&device, NOTIFIER_BLINK_COUNT, device_on, |_| {}).await
+}
@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use log::{debug, error, warn};
+use tokio::sync::Mutex;
+use tokio::task::AbortHandle;
+use tonic::Status;
+use crate::tapo::create_event;
+use crate::tapo::server::EventSender;
+use crate::tapo::server::rpc::{self, EventType};
+
+/// Registry of the long-running operations which are currently running on the devices
+///
+/// Every device can have at most one running operation. Starting a new operation on a device
+/// cancels the previous one
+#[derive(Clone)]
+pub struct Operations {
+    running: Arc<Mutex<HashMap<String, RunningOperation>>>,
+    next_id: Arc<AtomicU64>,
+    sender: EventSender
+}
+
+struct RunningOperation {
+    id: u64,
+    name: String,
+    handle: AbortHandle
+}
+
+/// Handle passed to a running operation to report its progress to the event subscribers
+#[derive(Clone)]
+pub struct Progress {
+    device: String,
+    name: String,
+    sender: EventSender
+}
+
+impl Progress {
+    /// Send an operation progress event with the given percentage
+    pub fn report(&self, percent: u32) {
+        self.send(percent.min(100), false)
+    }
+
+    fn send(&self, percent: u32, cancelled: bool) {
+        let operation = rpc::Operation { device: self.device.clone(), name: self.name.clone(), percent, cancelled };
+        if let Some(Err(err)) = create_event(EventType::OperationProgress, operation).map(|event| self.sender.send(event)) {
+            error!("Error whilst sending operation progress: {err}")
+        }
+    }
+}
+
+impl Operations {
+    pub fn new(sender: EventSender) -> Self {
+        Self { running: Arc::new(Mutex::new(HashMap::new())), next_id: Arc::new(AtomicU64::new(0)), sender }
+    }
+
+    /// Start a long-running operation in the background
+    ///
+    /// The operation is spawned onto its own task so the request which started it can return immediately
+    /// and isn't bound to the deadline of the request. The operation reports `0` and `100` percent by itself
+    /// when starting and finishing
+    pub async fn start<F, Fut>(&self, device: String, name: &str, operation: F)
+    where
+        F: FnOnce(Progress) -> Fut,
+        Fut: Future<Output = Result<(), Status>> + Send + 'static
+    {
+        self.cancel(&device).await;
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let progress = Progress { device: device.clone(), name: name.to_string(), sender: self.sender.clone() };
+        let future = operation(progress.clone());
+        let operations = self.clone();
+
+        // the lock is held until the operation is registered to prevent a fast operation from
+        // finishing before it's inserted into the registry
+        let mut running = self.running.lock().await;
+        let task_device = device.clone();
+        let handle = tokio::spawn(async move {
+            progress.report(0);
+            match future.await {
+                Ok(_) => progress.report(100),
+                Err(status) => warn!("Operation '{}' on device '{}' failed: {}", progress.name, progress.device, status.message())
+            }
+            operations.finish(&task_device, id).await;
+        });
+
+        debug!("Started operation '{name}' on device '{device}'");
+        running.insert(device, RunningOperation { id, name: name.to_string(), handle: handle.abort_handle() });
+    }
+
+    /// Cancel the running operation of a device
+    ///
+    /// Returns `true` when there was a running operation which got cancelled
+    pub async fn cancel(&self, device: &str) -> bool {
+        let Some(operation) = self.running.lock().await.remove(device) else { return false };
+        operation.handle.abort();
+        debug!("Cancelled operation '{}' on device '{device}'", operation.name);
+
+        let progress = Progress { device: device.to_string(), name: operation.name, sender: self.sender.clone() };
+        progress.send(0, true);
+        true
+    }
+
+    async fn finish(&self, device: &str, id: u64) {
+        let mut running = self.running.lock().await;
+        // only remove the operation when it wasn't replaced in the meantime
+        if running.get(device).is_some_and(|operation| operation.id == id) {
+            running.remove(device);
+        }
+    }
+}
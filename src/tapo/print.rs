@@ -1,45 +1,127 @@
 use std::fmt::{Display, Formatter};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use colored::{Colorize, CustomColor};
+use colored::{ColoredString, Colorize, CustomColor};
 use colorsys::Rgb;
 
-use super::server::rpc::{self, InfoResponse, UsageResponse};
+use crate::cli::kelvin_to_mireds;
+use crate::config::ColorFallback;
 
-impl Display for InfoResponse {
+use super::server::rpc::{self, AuditResponse, BulkResponse, CurrentPowerResponse, FirmwareResponse, InfoBatchResponse, InfoResponse, LogRecord, SensorResponse, SetExplanation, StatsResponse, StatusResponse, UsageResponse};
+
+/// Wrapper for printing the info with the preferences of the client
+pub struct FormattedInfo<'a> {
+    pub info: &'a InfoResponse,
+    /// Print the color temperature in mireds instead of kelvin
+    pub mireds: bool,
+    pub color_fallback: ColorFallback
+}
+
+impl Display for FormattedInfo<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        format_info(self.info, f, self.mireds, self.color_fallback)
+    }
+}
+
+/// Wrapper for printing the info as `KEY=VALUE` lines which can be evaluated by a shell
+///
+/// Nested messages are flattened with underscores (e.g. `TAPO_COLOR_RED`) and unset fields are omitted
+pub struct InfoAsEnv<'a>(pub &'a InfoResponse);
+
+impl Display for InfoAsEnv<'_> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let mut lines = vec![];
-        if let Some(on) = &self.device_on {
-            let state = on.then_some("Turned on").unwrap_or("Turned off");
-            lines.push(format!("{}: {state}", "State".bold()))
-        }
-        let overheated = if self.overheated { "Overheated" } else { "Normal" };
-        lines.push(format!("{}: {overheated}", "Thermals".bold()));
-        if let Some(on_time) = &self.on_time {
-            lines.push(format!("{}: {}min", "Uptime".bold(), on_time / 60u64))
-        }
-        if let Some(temperature) = &self.temperature {
-            if temperature > &0 {
-                lines.push(format!("{}: {temperature}K", "Temperature".bold()))
+        env_lines("TAPO", &serde_json::json!(self.0), &mut lines);
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+fn env_lines(key: &str, value: &serde_json::Value, lines: &mut Vec<String>) {
+    match value {
+        serde_json::Value::Null | serde_json::Value::Array(_) => {},
+        serde_json::Value::Object(map) => {
+            for (name, value) in map {
+                env_lines(&format!("{key}_{}", name.to_uppercase()), value, lines);
             }
+        },
+        // single quotes keep the shell from expanding anything inside the value
+        serde_json::Value::String(string) => lines.push(format!("{key}='{}'", string.replace('\'', "'\\''"))),
+        value => lines.push(format!("{key}={value}"))
+    }
+}
+
+impl Display for InfoResponse {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        format_info(self, f, false, ColorFallback::Omit)
+    }
+}
+
+/// Format the info with the color temperature either in kelvin or in mireds
+fn format_info(info: &InfoResponse, f: &mut Formatter<'_>, mireds: bool, color_fallback: ColorFallback) -> std::fmt::Result {
+    let mut lines = vec![];
+    if let Some(on) = &info.device_on {
+        let state = on.then_some("Turned on").unwrap_or("Turned off");
+        lines.push(format!("{}: {state}", "State".bold()))
+    }
+    let overheated = if info.overheated { "Overheated" } else { "Normal" };
+    lines.push(format!("{}: {overheated}", "Thermals".bold()));
+    if let Some(on_time) = &info.on_time {
+        lines.push(format!("{}: {}min", "Uptime".bold(), on_time / 60u64))
+    }
+    if let Some(temperature) = &info.temperature {
+        if temperature > &0 && mireds {
+            lines.push(format!("{}: {} mireds", "Temperature".bold(), kelvin_to_mireds(*temperature)))
+        } else if temperature > &0 {
+            lines.push(format!("{}: {temperature}K", "Temperature".bold()))
         }
-        if let Some(color) = &self.color {
-            let block = "  ".on_custom_color(CustomColor::new(u8::try_from(color.red).unwrap_or_default(), u8::try_from(color.green).unwrap_or_default(), u8::try_from(color.blue).unwrap_or_default()));
-            let color = Rgb::new(color.red as f64, color.green as f64, color.blue as f64, None).to_hex_string();
-            lines.push(format!("{}: {color} {block}", "Color".bold()));
-        }
-        if let Some(brightness) = &self.brightness {
-            lines.push(format!("{}: {brightness}%", "Brightness".bold()))
-        }
-        if let Some((hue, saturation)) = &self.hue.zip(self.saturation) {
-            lines.push(format!("{}: {hue}", "Hue".bold()));
-            lines.push(format!("{}: {saturation}%", "Saturation".bold()))
-        }
-        if let Some(effect_id) = &self.dynamic_effect_id {
-            lines.push(format!("{}: {effect_id}", "Effect".bold()))
+    }
+    if let Some(color) = &info.color {
+        let block = "  ".on_custom_color(CustomColor::new(u8::try_from(color.red).unwrap_or_default(), u8::try_from(color.green).unwrap_or_default(), u8::try_from(color.blue).unwrap_or_default()));
+        let color = Rgb::new(color.red as f64, color.green as f64, color.blue as f64, None).to_hex_string();
+        lines.push(format!("{}: {color} {block}", "Color".bold()));
+    } else if color_fallback == ColorFallback::Placeholder && info.brightness.is_some() {
+        // only lights have a color which could be missing
+        let block = "  ".on_custom_color(CustomColor::new(128, 128, 128));
+        lines.push(format!("{}: {block} {}", "Color".bold(), "Unknown (not reported by the device)".dimmed()));
+    }
+    if let Some(hsl) = &info.hsl {
+        lines.push(format!("{}: {}, {}%, {}%", "HSL".bold(), hsl.hue, hsl.saturation, hsl.lightness));
+    }
+    if let Some(brightness) = &info.brightness {
+        lines.push(format!("{}: {brightness}%", "Brightness".bold()))
+    }
+    if let Some((hue, saturation)) = &info.hue.zip(info.saturation) {
+        lines.push(format!("{}: {hue}", "Hue".bold()));
+        lines.push(format!("{}: {saturation}%", "Saturation".bold()))
+    }
+    if let Some(effect_id) = &info.dynamic_effect_id {
+        match info.dynamic_effect_name.as_ref().filter(|name| *name != effect_id) {
+            Some(name) => lines.push(format!("{}: {name} {}", "Effect".bold(), format!("({effect_id})").dimmed())),
+            None => lines.push(format!("{}: {effect_id}", "Effect".bold()))
         }
-
-        f.write_str(lines.join("\n").as_str())
     }
+    if let Some(current) = &info.current_temperature {
+        lines.push(format!("{}: {current:.1}°C", "Room temperature".bold()))
+    }
+    if let Some(target) = &info.target_temperature {
+        lines.push(format!("{}: {target:.1}°C", "Target temperature".bold()))
+    }
+    if let Some(frost_protection) = &info.frost_protection {
+        let state = frost_protection.then_some("Enabled").unwrap_or("Disabled");
+        lines.push(format!("{}: {state}", "Frost protection".bold()))
+    }
+    if let Some(signal_level) = &info.signal_level {
+        let rssi = info.rssi.map(|rssi| format!(" ({rssi} dBm)")).unwrap_or_default();
+        lines.push(format!("{}: {}{rssi}", "Signal".bold(), signal_bars(*signal_level)))
+    }
+
+    f.write_str(lines.join("\n").as_str())
+}
+
+/// Render the signal level reported by the device (0-3) as signal bars
+fn signal_bars(level: u32) -> String {
+    let level = level.min(3) as usize;
+    format!("{}{}", "▂▄▆"[..level * 3].green(), "▂▄▆"[level * 3..].dimmed())
 }
 
 impl Display for UsageResponse {
@@ -76,17 +158,355 @@ impl Display for UsageResponse {
     }
 }
 
+impl rpc::Device {
+    /// Get a human-readable description of the session status
+    ///
+    /// Statuses which were added in a newer server version are shown with their raw value
+    fn status_text(&self) -> String {
+        match rpc::SessionStatus::try_from(self.status) {
+            Ok(rpc::SessionStatus::Authenticated) => String::from("Authenticated"),
+            Ok(rpc::SessionStatus::Failure) => String::from("Authentication failed"),
+            Ok(rpc::SessionStatus::RepeatedFailure) => String::from("Authentication failed multiple times"),
+            Err(_) => format!("Unknown session status ({})", self.status)
+        }
+    }
+
+    /// Get a colored dot representing the session status
+    fn status_dot(&self) -> ColoredString {
+        match rpc::SessionStatus::try_from(self.status) {
+            Ok(rpc::SessionStatus::Authenticated) => "●".green(),
+            Ok(rpc::SessionStatus::Failure) => "●".yellow(),
+            Ok(rpc::SessionStatus::RepeatedFailure) => "●".red(),
+            Err(_) => "●".dimmed()
+        }
+    }
+}
+
+/// Render a list of devices as an aligned table with one row per device
+pub fn devices_table(devices: &[rpc::Device]) -> String {
+    let header = ["Name", "Type", "Session", "Address"];
+    let rows = devices.iter()
+        .map(|dev| (dev.status_dot(), [dev.name.clone(), dev.r#type.clone(), dev.status_text(), dev.address.clone()]))
+        .collect::<Vec<_>>();
+    table(header, &rows)
+}
+
+/// Result of the reachability check of a single device
+#[derive(serde::Serialize)]
+pub struct DoctorCheck {
+    pub device: rpc::Device,
+    pub reachable: bool,
+    pub error: Option<String>
+}
+
+impl DoctorCheck {
+    /// Whether the device is authenticated and answered the info request
+    pub fn passed(&self) -> bool {
+        self.reachable && self.device.status == i32::from(rpc::SessionStatus::Authenticated)
+    }
+}
+
+/// Format the pass/fail matrix of the device checks as table
+pub fn doctor_table(checks: &[DoctorCheck]) -> String {
+    let header = ["Name", "Session", "Reachable", "Result"];
+    let rows = checks.iter()
+        .map(|check| {
+            let reachable = match &check.error {
+                Some(error) if !check.reachable => format!("No ({error})"),
+                _ => String::from(if check.reachable { "Yes" } else { "No" })
+            };
+            let dot = if check.passed() { "●".green() } else { "●".red() };
+            let result = String::from(if check.passed() { "Pass" } else { "Fail" });
+            (dot, [check.device.name.clone(), check.device.status_text(), reachable, result])
+        })
+        .collect::<Vec<_>>();
+    table(header, &rows)
+}
+
+/// Result of setting the color of a single device of a gradient
+#[derive(serde::Serialize)]
+pub struct GradientResult {
+    pub device: String,
+    pub hue: u16,
+    pub saturation: u8,
+    pub error: Option<String>
+}
+
+/// Format the per-device results of a gradient as table
+pub fn gradient_table(results: &[GradientResult]) -> String {
+    let header = ["Name", "Hue", "Saturation", "Result"];
+    let rows = results.iter()
+        .map(|result| {
+            let dot = if result.error.is_none() { "●".green() } else { "●".red() };
+            let outcome = match &result.error {
+                Some(error) => format!("Failed ({error})"),
+                None => String::from("Updated")
+            };
+            (dot, [result.device.clone(), result.hue.to_string(), result.saturation.to_string(), outcome])
+        })
+        .collect::<Vec<_>>();
+    table(header, &rows)
+}
+
+impl Display for BulkResponse {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let rows = self.results.iter()
+            .map(|result| {
+                let dot = if result.ok { "●".green() } else { "●".red() };
+                let outcome = match (result.ok, &result.error_message) {
+                    (true, _) => String::from("Updated"),
+                    (false, Some(error)) => format!("Failed ({error})"),
+                    (false, None) => String::from("Failed")
+                };
+                (dot, [result.name.clone(), outcome])
+            })
+            .collect::<Vec<_>>();
+        write!(f, "{}", table(["Name", "Result"], &rows))
+    }
+}
+
+/// Format the rows as table with a colored dot in front of every row
+fn table<const N: usize>(header: [&str; N], rows: &[(ColoredString, [String; N])]) -> String {
+    // the widths are computed on the uncolored text since the ansi escape codes
+    // don't take up any space when displayed
+    let mut widths = header.map(|h| h.chars().count());
+    for (_, row) in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let pad = |text: &str, width: usize| format!("{text}{}", " ".repeat(width - text.chars().count()));
+
+    let mut lines = vec![];
+    let heading = header.iter().zip(widths).map(|(h, w)| pad(h, w)).collect::<Vec<_>>().join("  ");
+    lines.push(format!("  {}", heading.bold()));
+    for (dot, row) in rows {
+        let cells = row.iter().zip(widths).map(|(cell, w)| pad(cell, w)).collect::<Vec<_>>();
+        lines.push(format!("{dot} {}", cells.join("  ").trim_end()));
+    }
+    lines.join("\n")
+}
+
+/// Summary of today's power usage and uptime together with the estimated cost
+pub struct UsageSummary<'a> {
+    pub usage: &'a UsageResponse,
+    pub price_per_kwh: Option<f64>
+}
+
+/// Estimate the cost of today's power usage with the given price per kWh
+pub fn cost_today(usage: &UsageResponse, price_per_kwh: Option<f64>) -> Option<f64> {
+    let power = usage.power_usage.as_ref().and_then(|power| power.today)?;
+    price_per_kwh.map(|price| power as f64 / 1000f64 * price)
+}
+
+impl Display for UsageSummary<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut parts = vec![];
+        if let Some(power) = self.usage.power_usage.as_ref().and_then(|power| power.today) {
+            parts.push(format!("{:.3}kWh", power as f32 / 1000f32));
+        }
+        if let Some(time) = self.usage.time_usage.as_ref().and_then(|time| time.today) {
+            parts.push(format!("{:.2}h uptime", time as f32 / 60f32));
+        }
+        if let Some(cost) = cost_today(self.usage, self.price_per_kwh) {
+            parts.push(format!("{cost:.2} estimated cost"));
+        }
+
+        let summary = if parts.is_empty() { "No information".dimmed().to_string() } else { parts.join(", ") };
+        f.write_str(format!("{}: {summary}", "Today".bold()).as_str())
+    }
+}
+
 impl Display for rpc::Device {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let mut lines = vec![];
-        let status = match rpc::SessionStatus::try_from(self.status).unwrap_or_default() {
-            rpc::SessionStatus::Authenticated => "Authenticated",
-            rpc::SessionStatus::Failure => "Authentication failed",
-            rpc::SessionStatus::RepeatedFailure => "Authentication failed multiple times",
-        };
         lines.push(format!("{}: {}", "Type".bold(), self.r#type));
-        lines.push(format!("{}: {}", "Session".bold(), status));
+        lines.push(format!("{}: {}", "Session".bold(), self.status_text()));
         lines.push(format!("{}: {}", "Address".bold(), self.address));
         f.write_str(lines.join("\n").as_str())
     }
-}
\ No newline at end of file
+}
+
+impl Display for rpc::Operation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut lines = vec![];
+        lines.push(format!("{}: {}", "Operation".bold(), self.name));
+        if self.cancelled {
+            lines.push(format!("{}: {}", "Progress".bold(), "Cancelled".dimmed()));
+        } else {
+            lines.push(format!("{}: {}%", "Progress".bold(), self.percent));
+        }
+        f.write_str(lines.join("\n").as_str())
+    }
+}
+
+impl Display for rpc::SafetyCutoffEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut lines = vec![];
+        lines.push(format!("{}: {}min", "Uptime".bold(), self.on_time / 60));
+        lines.push(format!("{}: {}min", "Maximum on-time".bold(), self.max_on_time / 60));
+        f.write_str(lines.join("\n").as_str())
+    }
+}
+
+impl Display for AuditResponse {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let lines = self.entries.iter().rev().map(|entry| {
+            let elapsed = now.saturating_sub(Duration::from_millis(entry.timestamp)).as_secs();
+            let ago = if elapsed < 60 { format!("{elapsed}s ago") }
+                else if elapsed < 60 * 60 { format!("{}min ago", elapsed / 60) }
+                else { format!("{}h {}min ago", elapsed / 3600, (elapsed % 3600) / 60) };
+            let client = entry.client.as_deref().unwrap_or("unknown client");
+            format!("{} {}: {} {}", entry.command.bold(), ago.dimmed(), entry.parameters, format!("({client})").dimmed())
+        }).collect::<Vec<_>>();
+        f.write_str(lines.join("\n").as_str())
+    }
+}
+
+impl Display for StatsResponse {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let lines = self.methods.iter().map(|stats| {
+            let mut errors = stats.errors.iter().collect::<Vec<_>>();
+            errors.sort();
+            let errors = errors.iter().map(|(code, count)| format!("{code}: {count}")).collect::<Vec<_>>();
+            let errors = if errors.is_empty() { String::new() } else { format!(" ({})", errors.join(", ")) };
+            let failed = stats.errors.values().sum::<u64>();
+            format!(
+                "{}: {} requests, {failed} errors{errors}, {} p50 {}ms p95 {}ms p99 {}ms",
+                stats.method.bold(), stats.requests, "latency".dimmed(), stats.p50_ms, stats.p95_ms, stats.p99_ms
+            )
+        }).collect::<Vec<_>>();
+        f.write_str(lines.join("\n").as_str())
+    }
+}
+
+impl Display for CurrentPowerResponse {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut lines = vec![format!("{}: {}W", "Current power".bold(), self.current_power)];
+        if let Some(smoothed) = &self.smoothed_power {
+            lines.push(format!("{}: {smoothed:.1}W", "Smoothed power".bold()));
+        }
+        f.write_str(lines.join("\n").as_str())
+    }
+}
+
+impl Display for SensorResponse {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let temperature = self.temperature.map_or("No information".dimmed(), |t| format!("{t:.1}°C").into());
+        let humidity = self.humidity.map_or("No information".dimmed(), |h| format!("{h}%").into());
+        let mut lines = vec![
+            format!("{}: {} ({})", "Sensor".bold(), self.child, self.model),
+            format!("{}: {temperature}", "Temperature".bold()),
+            format!("{}: {humidity}", "Humidity".bold()),
+        ];
+        if self.low_battery {
+            lines.push(format!("{}: {}", "Battery".bold(), "Low".red()));
+        }
+        f.write_str(lines.join("\n").as_str())
+    }
+}
+
+impl Display for FirmwareResponse {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let lines = [
+            format!("{}: {}", "Firmware version".bold(), self.current_version),
+            format!("{}: {}", "Hardware version".bold(), self.hardware_version),
+        ];
+        f.write_str(lines.join("\n").as_str())
+    }
+}
+
+impl Display for LogRecord {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let level = match self.level.as_str() {
+            "ERROR" => self.level.red(),
+            "WARN" => self.level.yellow(),
+            "INFO" => self.level.green(),
+            _ => self.level.dimmed()
+        };
+        f.write_str(format!("{} {level} {}: {}", time_of_day(self.timestamp).dimmed(), self.target.bold(), self.message).as_str())
+    }
+}
+
+impl Display for InfoBatchResponse {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let results = self.results.iter().map(|result| {
+            let outcome = result.result.clone().unwrap_or_default();
+            let heading = format!("{}:", outcome.name.bold().underline());
+            match (&result.info, &outcome.error_message) {
+                (Some(info), _) => format!("{heading}\n{info}"),
+                (None, message) => format!("{heading}\n{}: {}", "Error".bold(), message.as_deref().unwrap_or("Unknown error").red())
+            }
+        }).collect::<Vec<_>>();
+        f.write_str(results.join("\n\n").as_str())
+    }
+}
+
+impl Display for StatusResponse {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut lines = vec![];
+        if let Some(device) = &self.device {
+            lines.push(device.to_string());
+        }
+        match &self.error {
+            Some(error) if !self.reachable => lines.push(format!("{}: {} ({error})", "Reachable".bold(), "No".red())),
+            _ => lines.push(format!("{}: {}", "Reachable".bold(), if self.reachable { "Yes".green() } else { "No".red() }))
+        }
+        if let Some(info) = &self.info {
+            lines.push(String::new());
+            lines.push(info.to_string());
+        }
+        if let Some(usage) = &self.usage {
+            lines.push(String::new());
+            lines.push("Today:".underline().bold().to_string());
+            let uptime = usage.time_usage.as_ref().and_then(|time| time.today)
+                .map_or("No information".dimmed(), |t| format!("{:.2}h", t as f32 / 60f32).into());
+            let power = usage.power_usage.as_ref().and_then(|power| power.today)
+                .map_or("No information".dimmed(), |p| format!("{:.3}kWh", p as f32 / 1000f32).into());
+            lines.push(format!("{}: {uptime}", "Uptime".bold()));
+            lines.push(format!("{}: {power}", "Power used".bold()));
+        }
+        f.write_str(lines.join("\n").as_str())
+    }
+}
+
+impl Display for SetExplanation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut lines = vec!["Explanation:".underline().bold().to_string()];
+        for field in &self.fields {
+            let computation = match field.base {
+                Some(base) => format!("{base} {:+} = {}", field.change, field.computed),
+                None => format!("{} (absolute)", field.computed)
+            };
+            let clamped = if field.computed != field.result { format!(" -> {} (clamped)", field.result) } else { String::new() };
+            lines.push(format!("{}: {computation}{clamped}", field.field.bold()));
+        }
+        if let Some(color) = &self.color {
+            lines.push(format!("{}: {color}", "Color".bold()));
+            if !self.overridden.is_empty() {
+                lines.push(format!("{}: {}", "Overridden by color".bold(), self.overridden.join(", ")));
+            }
+        }
+        f.write_str(lines.join("\n").as_str())
+    }
+}
+
+impl Display for rpc::VersionResponse {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let uptime = if self.uptime < 60 * 60 { format!("{}min", self.uptime / 60) }
+            else if self.uptime < 60 * 60 * 24 { format!("{}h {}min", self.uptime / 3600, (self.uptime % 3600) / 60) }
+            else { format!("{}d {}h", self.uptime / 86400, (self.uptime % 86400) / 3600) };
+        let mut lines = vec![];
+        lines.push(format!("{}: {}", "Server version".bold(), self.version));
+        lines.push(format!("{}: {uptime} {}", "Uptime".bold(), format!("(since {} UTC)", time_of_day(self.start_time)).dimmed()));
+        f.write_str(lines.join("\n").as_str())
+    }
+}
+
+/// Format the time of day in UTC of a unix timestamp in milliseconds
+fn time_of_day(timestamp: u64) -> String {
+    let seconds = (timestamp / 1000) % 86400;
+    format!("{:02}:{:02}:{:02}", seconds / 3600, (seconds % 3600) / 60, seconds % 60)
+}
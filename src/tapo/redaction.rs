@@ -0,0 +1,48 @@
+use std::collections::HashSet;
+use serde_json::Value;
+use crate::tapo::server::rpc::InfoJsonResponse;
+
+/// Filter for the top-level keys of the raw device info
+///
+/// Keys which aren't in the allowlist (if any) or which are in the denylist are removed before the info is returned
+#[derive(Clone, Default)]
+pub struct KeyFilter {
+    allow: Option<HashSet<String>>,
+    deny: HashSet<String>
+}
+
+impl KeyFilter {
+    pub fn new(allow: Option<Vec<String>>, deny: Vec<String>) -> Self {
+        Self { allow: allow.map(HashSet::from_iter), deny: HashSet::from_iter(deny) }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.allow.is_none() && self.deny.is_empty()
+    }
+
+    fn keeps(&self, key: &str) -> bool {
+        let allowed = match &self.allow {
+            Some(allow) => allow.contains(key),
+            None => true
+        };
+        allowed && !self.deny.contains(key)
+    }
+
+    /// Remove the filtered keys from the serialized device info
+    ///
+    /// Returns the message of the error should the info not be valid json
+    pub fn apply(&self, mut response: InfoJsonResponse) -> Result<InfoJsonResponse, String> {
+        if self.is_empty() {
+            return Ok(response)
+        }
+
+        let mut value: Value = serde_json::from_slice(&response.data)
+            .map_err(|err| format!("Unable to parse device info: {err}"))?;
+        if let Value::Object(map) = &mut value {
+            map.retain(|key, _| self.keeps(key));
+        }
+        response.data = serde_json::to_vec(&value)
+            .map_err(|err| format!("Unable to serialize device info: {err}"))?;
+        Ok(response)
+    }
+}
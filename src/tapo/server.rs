@@ -1,52 +1,299 @@
 use std::cmp::{max, min};
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use log::{debug, info, warn};
 use futures::future::join_all;
 use tokio::sync::{RwLock, RwLockWriteGuard};
+use tokio::sync::broadcast::error::RecvError;
 use tonic::{Request, Response, Status};
 use tonic::codegen::tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::server::TcpConnectInfo;
 use rpc::tapo_server::Tapo;
-use crate::tapo::server::rpc::{DeviceRequest, DevicesResponse, Empty, EventRequest, EventResponse, InfoJsonResponse, InfoResponse, PowerResponse, SetRequest, UsageResponse};
+use crate::tapo::server::rpc::{AuditResponse, BulkResponse, CancelResponse, CurrentPowerResponse, DeviceInfoResult, DeviceRequest, DeviceResult, EnergyDeltaResponse, FieldExplanation, FirmwareResponse, IntegerValueChange, SetExplanation, DevicesResponse, Empty, EventRequest, EventResponse, InfoBatchRequest, InfoBatchResponse, InfoJsonResponse, InfoResponse, LogRecord, OffRequest, PowerManyRequest, PowerResponse, ResetConfirmRequest, ResetToken, SensorRequest, SensorResponse, SetManyRequest, SetRequest, SleepRequest, StatsResponse, StatusResponse, TargetTemperatureRequest, UsageResponse, VersionResponse};
 use crate::device::Device;
+use crate::logging::LogSender;
 use crate::tapo::TapoRpcColorExt;
+use crate::tapo::audit::AuditLog;
+use crate::tapo::metrics::{record_device, Metrics};
+use crate::tapo::smoothing::PowerSmoothing;
+use crate::tapo::energy::EnergyBaselines;
+use crate::tapo::redaction::KeyFilter;
+use crate::tapo::history::EventHistory;
+use crate::config::{ServerConfig, SupportedDevice};
+use crate::tapo::operation::Operations;
 use crate::tapo::state::State;
 
-use super::{TapoDeviceExt, TapoSessionStatusExt};
+use super::{blink, fade_off, TapoDeviceExt, UpdateError};
 
 pub mod rpc {
     tonic::include_proto!("tapo");
 }
 
+/// Time in milliseconds in which a prepared reset has to be confirmed
+const RESET_TOKEN_VALIDITY_MILLIS: u64 = 30000;
+
+/// Number of times the device is turned off and on again when a reset is prepared
+const RESET_BLINK_COUNT: u32 = 3;
+
 pub type EventSender = tokio::sync::broadcast::Sender<EventResponse>;
 pub type EventReceiver = tokio::sync::broadcast::Receiver<EventResponse>;
 pub type EventChannel = (EventSender, EventReceiver);
 
+/// Reset of a device which was prepared and waits for its confirmation
+struct PendingReset {
+    token: String,
+    expires: Instant
+}
+
 #[derive(Clone)]
 pub struct TapoService {
     devices: Arc<HashMap<String, Arc<RwLock<Device>>>>,
     state: Arc<RwLock<State>>,
+    operations: Operations,
+    audit: Arc<RwLock<AuditLog>>,
+    metrics: Arc<RwLock<Metrics>>,
+    logs: Option<LogSender>,
+    resets: Arc<RwLock<HashMap<String, PendingReset>>>,
+    loose_names: bool,
+    smoothing: Option<PowerSmoothing>,
+    energy: EnergyBaselines,
+    info_json_filter: KeyFilter,
+    history: EventHistory,
+    start_time: SystemTime,
+    started: Instant,
     channel: Arc<EventChannel>
 }
 
 impl TapoService {
-    pub fn new(devices: HashMap<String, Arc<RwLock<Device>>>, channel: EventChannel) -> Self {
+    /// Create the service over already constructed devices
+    ///
+    /// The service doesn't bind a port by itself, so it can also be exercised in-process by calling the methods of the
+    /// [`Tapo`] trait directly with a [`Request`]. Devices of the type [`SupportedDevice::Virtual`](crate::config::SupportedDevice::Virtual)
+    /// don't need any network access which makes them suitable for such a setup. Tests use [`TapoService::in_process`] for it
+    pub fn new(devices: HashMap<String, Arc<RwLock<Device>>>, channel: EventChannel, config: &ServerConfig, logs: Option<LogSender>, smoothing: Option<PowerSmoothing>, history: EventHistory) -> Self {
         Self {
             devices: Arc::new(devices),
-            state: Arc::new(RwLock::new(State::new(channel.0.clone()))),
+            state: Arc::new(RwLock::new(State::new(channel.0.clone(), config.state_file.clone()))),
+            operations: Operations::new(channel.0.clone()),
+            audit: Arc::new(RwLock::new(AuditLog::default())),
+            metrics: Arc::new(RwLock::new(Metrics::default())),
+            logs,
+            resets: Arc::new(RwLock::new(HashMap::new())),
+            loose_names: config.loose_names,
+            smoothing,
+            energy: EnergyBaselines::default(),
+            info_json_filter: KeyFilter::new(config.info_json_allow.clone(), config.info_json_deny.clone()),
+            history,
+            start_time: SystemTime::now(),
+            started: Instant::now(),
             channel: Arc::new(channel)
         }
     }
 
-    async fn get_device_by_name(&self, name: &String) -> Result<Arc<RwLock<Device>>, Status> {
-        match self.devices.get(name) {
-            Some(dev) => Ok(dev.clone()),
-            None => Err(Status::not_found(format!("Device '{name}' could not be found")))
+    /// Get the request metrics which are shared with the [`MetricsRecorder`](crate::tapo::metrics::MetricsRecorder)
+    pub fn metrics(&self) -> Arc<RwLock<Metrics>> {
+        self.metrics.clone()
+    }
+
+    /// Get the cached device states which are shared with the background tasks of the server
+    pub fn state(&self) -> Arc<RwLock<State>> {
+        self.state.clone()
+    }
+
+    /// Get a device by its name
+    ///
+    /// With loose names enabled the name is matched case and separator insensitive should there be no exact
+    /// match. The name is replaced with the name of the matched device so the state, audit log and operations
+    /// are always keyed by the configured name. The request is counted in the metrics of the matched device
+    async fn get_device_by_name(&self, name: &mut String) -> Result<Arc<RwLock<Device>>, Status> {
+        if let Some(dev) = self.devices.get(name) {
+            record_device(name);
+            return Ok(dev.clone())
+        }
+        if !self.loose_names {
+            return Err(Status::not_found(format!("Device '{name}' could not be found")))
+        }
+
+        let normalized = normalize_name(name);
+        let mut candidates = self.devices.keys().filter(|key| normalize_name(key) == normalized).collect::<Vec<&String>>();
+        candidates.sort();
+        match candidates.as_slice() {
+            [] => Err(Status::not_found(format!("Device '{name}' could not be found"))),
+            [key] => {
+                *name = key.to_string();
+                record_device(key);
+                Ok(self.devices[*key].clone())
+            },
+            _ => {
+                let candidates = candidates.iter().map(|key| format!("'{key}'")).collect::<Vec<String>>().join(", ");
+                Err(Status::invalid_argument(format!("Device name '{name}' is ambiguous. Candidates are {candidates}")))
+            }
+        }
+    }
+
+    /// Turn the device on with its configured brightness and color temperature like `on`
+    async fn switch_on(&self, device: &mut Device) -> Result<Response<PowerResponse>, Status> {
+        // turning the device on cancels a pending sleep timer
+        self.operations.cancel(&device.name).await;
+        let capabilities = device.device_type.capabilities();
+        let faded_brightness = device.faded_brightness.take();
+        let mut brightness = device.on_brightness.or(faded_brightness).filter(|_| capabilities.brightness);
+        // the night limit caps the restored brightness like in `set`. Without a brightness the device falls
+        // back to the limit should its current brightness be higher. Devices without brightness support are
+        // only switched on
+        if let Some(limit) = device.night_limit().filter(|_| capabilities.brightness) {
+            let current = self.get_state_mut().await.get_info(device).await?.brightness;
+            brightness = match brightness {
+                Some(requested) if requested > limit => Some(limit),
+                None if current.is_none_or(|current| current > limit as u32) => Some(limit),
+                brightness => brightness
+            };
+        }
+        let temperature = device.on_temperature.filter(|_| capabilities.temperature);
+        let response = if brightness.is_some() || temperature.is_some() {
+            // setting the properties turns the device on with them at once
+            device.set(InfoResponse::default(), Some(true), brightness, temperature, None, false).await
+                .map(|_| Response::new(PowerResponse { device_on: true }))
+                .map_err(|err| err.status)?
+        } else {
+            device.on().await?
+        };
+        let mut info = self.get_state_mut().await.get_info(device).await?;
+        if let Some(brightness) = brightness {
+            info.brightness = Some(brightness as u32);
+        }
+        if let Some(temperature) = temperature {
+            info.temperature = Some(temperature as u32);
+            info.hue = None;
+            info.saturation = None;
         }
+        info.device_on = Some(true);
+        info.on_time = Some(0);
+        self.get_state_mut().await.update_info_optimistically(device.name.clone(), info);
+
+        Ok(response)
+    }
+
+    /// Turn the device off like `off`. The info is the current state of the device
+    async fn switch_off(&self, device: &mut Device, mut info: InfoResponse, instant: bool) -> Result<Response<PowerResponse>, Status> {
+        let fade = device.off_fade.filter(|_| !instant && info.device_on.unwrap_or_default());
+        let response = match (fade, info.brightness.filter(|brightness| *brightness > 1)) {
+            (Some(duration), Some(brightness)) => {
+                let response = fade_off(device, brightness as u8, duration).await?;
+                device.faded_brightness = Some(brightness as u8);
+                response
+            },
+            _ => device.off().await?
+        };
+        info.device_on = Some(false);
+        info.on_time = Some(0);
+        self.get_state_mut().await.update_info_optimistically(device.name.clone(), info);
+
+        Ok(response)
     }
 
     async fn get_state_mut(&self) -> RwLockWriteGuard<'_, State> {
         self.state.write().await
     }
+
+    /// Get the info of a device through the state cache
+    async fn cached_info(&self, mut name: String, fresh: bool) -> Result<InfoResponse, Status> {
+        let device = self.get_device_by_name(&mut name).await?;
+        let mut device = device.write().await;
+
+        device.try_refresh_session().await?;
+        if fresh {
+            self.get_state_mut().await.get_fresh_info(&device).await
+        } else {
+            self.get_state_mut().await.get_info(&device).await
+        }
+    }
+}
+
+#[cfg(test)]
+impl TapoService {
+    /// Create the service over the devices of the config without binding a port
+    ///
+    /// The devices are logged into like on startup, so they should be of the type `Virtual` which doesn't
+    /// require any network access. The methods of the [`Tapo`] trait can then be called directly in a `#[tokio::test]`
+    pub async fn in_process(config: ServerConfig) -> Self {
+        let (tx, rx) = tokio::sync::broadcast::channel(10);
+        let mut devices = HashMap::new();
+        for (name, definition) in config.devices.clone() {
+            let client = tapo::ApiClient::new(&config.auth.username, &config.auth.password);
+            if let Some(device) = Device::new(name.clone(), definition, client, tx.clone(), config.auto_relogin).await {
+                devices.insert(name, Arc::new(RwLock::new(device)));
+            }
+        }
+        Self::new(devices, (tx, rx), &config, None, None, EventHistory::new(config.event_history))
+    }
+}
+
+/// Create a request for another method which keeps the connection info of the original request for the audit log
+fn forwarded<T, M>(request: &Request<T>, message: M) -> Request<M> {
+    let mut forwarded = Request::new(message);
+    if let Some(info) = request.extensions().get::<TcpConnectInfo>() {
+        forwarded.extensions_mut().insert(info.clone());
+    }
+    forwarded
+}
+
+/// Get the result of a single device of a request which targets multiple devices
+fn device_result<T>(name: String, result: Result<T, Status>) -> DeviceResult {
+    match result {
+        Ok(_) => DeviceResult { name, ok: true, ..DeviceResult::default() },
+        Err(status) => DeviceResult {
+            name,
+            ok: false,
+            error_code: Some(status.code() as i32),
+            error_message: Some(status.message().to_string())
+        }
+    }
+}
+
+/// Get the info or error of a single device of the batch info request
+pub fn device_info_result(name: String, result: Result<InfoResponse, Status>) -> DeviceInfoResult {
+    let (info, result) = match result {
+        Ok(info) => (Some(info), Ok(())),
+        Err(status) => (None, Err(status))
+    };
+    DeviceInfoResult { result: Some(device_result(name, result)), info }
+}
+
+/// Stream the events of the channel with the given types after the last `replay_count` recorded events
+pub fn subscribe_events(channel: &EventChannel, history: &EventHistory, request: EventRequest) -> ReceiverStream<Result<EventResponse, Status>> {
+    let (tx, rx) = tokio::sync::mpsc::channel(4);
+    let types = request.types;
+    // the receiver is created before the replayed events are taken so no event is lost in between. An event
+    // can therefore be received twice should it be broadcast whilst the subscription is set up
+    let mut receiver = channel.1.resubscribe();
+    let replay = history.tail(&types, request.replay_count as usize);
+
+    tokio::spawn(async move {
+        for event in replay {
+            if tx.send(Ok(event)).await.is_err() {
+                return
+            }
+        }
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    if (types.contains(&event.r#type) || types.is_empty()) && tx.send(Ok(event)).await.is_err() {
+                        return
+                    }
+                },
+                Err(_) => {
+                    tx.send(Err(Status::internal("Error whilst receiving event"))).await.unwrap();
+                    return
+                },
+            }
+        }
+    });
+
+    ReceiverStream::new(rx)
 }
 
 #[tonic::async_trait]
@@ -55,40 +302,51 @@ impl Tapo for TapoService {
     async fn devices(&self, _: Request<Empty>) -> Result<Response<DevicesResponse>, Status> {
         let map_async = self.devices.values().map(|dev| dev.read()).collect::<Vec<_>>();
         let devices = join_all(map_async).await.into_iter()
-            .map(|dev| {
-                rpc::Device {
-                    name: dev.name.clone(),
-                    r#type: dev.device_type.to_string(),
-                    address: dev.address.clone(),
-                    status: dev.session_status.rpc().into()
-                }
-            })
+            .map(|dev| dev.rpc())
             .collect::<Vec<_>>();
 
         Ok(Response::new(DevicesResponse { devices }))
     }
 
+    /// Get the version and the uptime of the server
+    async fn version(&self, _: Request<Empty>) -> Result<Response<VersionResponse>, Status> {
+        Ok(Response::new(VersionResponse {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            start_time: self.start_time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+            uptime: self.started.elapsed().as_secs()
+        }))
+    }
+
     type EventsStream = ReceiverStream<Result<EventResponse, Status>>;
 
     /// Subscribe to server events
+    ///
+    /// The last `replay_count` recorded events of the subscribed types are sent before the live events
     async fn events(&self, request: Request<EventRequest>) -> Result<Response<Self::EventsStream>, Status> {
-        let (tx, rx) = tokio::sync::mpsc::channel(4);
-        let types = request.into_inner().types;
-        let broadcast = self.channel.clone();
-        let mut receiver = broadcast.1.resubscribe();
+        Ok(Response::new(subscribe_events(&self.channel, &self.history, request.into_inner())))
+    }
+
+    type LogsStream = ReceiverStream<Result<LogRecord, Status>>;
+
+    /// Subscribe to the log records of the server
+    async fn logs(&self, _request: Request<Empty>) -> Result<Response<Self::LogsStream>, Status> {
+        let Some(logs) = &self.logs else {
+            return Err(Status::failed_precondition("Log streaming is disabled in the server config"))
+        };
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let mut receiver = logs.subscribe();
 
         tokio::spawn(async move {
             loop {
                 match receiver.recv().await {
-                    Ok(event) => {
-                        if (types.contains(&event.r#type) || types.is_empty()) && tx.send(Ok(event)).await.is_err() {
+                    Ok(record) => {
+                        if tx.send(Ok(record)).await.is_err() {
                             return
                         }
                     },
-                    Err(_) => {
-                        tx.send(Err(Status::internal("Error whilst receiving event"))).await.unwrap();
-                        return
-                    },
+                    // slow clients miss some records instead of closing the stream
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return
                 }
             }
         });
@@ -98,139 +356,457 @@ impl Tapo for TapoService {
 
     /// Reset the device to it's factory defaults
     async fn reset(&self, request: Request<DeviceRequest>) -> Result<Response<Empty>, Status> {
-        let inner = request.into_inner();
-        let device = self.get_device_by_name(&inner.device).await?;
+        let client = request.remote_addr();
+        let mut inner = request.into_inner();
+        let device = self.get_device_by_name(&mut inner.device).await?;
         let mut device = device.write().await;
 
         device.try_refresh_session().await?;
-        device.reset().await
+        let response = device.reset().await?;
+        self.audit.write().await.record(&inner.device, "reset", &inner, client);
+
+        Ok(response)
+    }
+
+    async fn reboot(&self, request: Request<DeviceRequest>) -> Result<Response<Empty>, Status> {
+        let client = request.remote_addr();
+        let mut inner = request.into_inner();
+        let device = self.get_device_by_name(&mut inner.device).await?;
+        let mut device = device.write().await;
+
+        device.try_refresh_session().await?;
+        let response = device.reboot().await?;
+        self.audit.write().await.record(&inner.device, "reboot", &inner, client);
+
+        Ok(response)
+    }
+
+    /// Prepare the reset of the device
+    ///
+    /// The device blinks to physically signal which device is about to be reset. The reset is only
+    /// executed once it's confirmed with the returned token before the token expires
+    async fn reset_prepare(&self, request: Request<DeviceRequest>) -> Result<Response<ResetToken>, Status> {
+        let mut inner = request.into_inner();
+        let device = self.get_device_by_name(&mut inner.device).await?;
+        let state = self.state.clone();
+
+        let token = generate_token(&inner.device);
+        let pending = PendingReset { token: token.clone(), expires: Instant::now() + Duration::from_millis(RESET_TOKEN_VALIDITY_MILLIS) };
+        self.resets.write().await.insert(inner.device.clone(), pending);
+
+        self.operations.start(inner.device, "blink", move |progress| async move {
+            let mut device = device.write().await;
+            device.try_refresh_session().await?;
+            let device_on = state.write().await.get_info(&device).await?.device_on.unwrap_or_default();
+
+            blink(&device, RESET_BLINK_COUNT, device_on, |blinks| {
+                // the operation reports the completion by itself
+                if blinks < RESET_BLINK_COUNT {
+                    progress.report(blinks * 100 / RESET_BLINK_COUNT);
+                }
+            }).await
+        }).await;
+
+        Ok(Response::new(ResetToken { token, expires_in_ms: RESET_TOKEN_VALIDITY_MILLIS }))
+    }
+
+    /// Reset the device to it's factory defaults if the token of the prepared reset is valid
+    async fn reset_confirm(&self, request: Request<ResetConfirmRequest>) -> Result<Response<Empty>, Status> {
+        let client = request.remote_addr();
+        let mut inner = request.into_inner();
+        let device = self.get_device_by_name(&mut inner.device).await?;
+
+        let pending = self.resets.write().await.remove(&inner.device);
+        match pending {
+            Some(pending) if pending.token == inner.token && Instant::now() < pending.expires => {},
+            _ => return Err(Status::permission_denied(format!("Invalid or expired reset token for device '{}'", inner.device)))
+        }
+
+        // the blinking has to stop before the device is reset
+        self.operations.cancel(&inner.device).await;
+        let mut device = device.write().await;
+
+        device.try_refresh_session().await?;
+        let response = device.reset().await?;
+        self.audit.write().await.record(&inner.device, "reset", &inner.device, client);
+
+        Ok(response)
     }
 
     /// Get some selected information about the device
     async fn info(&self, request: Request<DeviceRequest>) -> Result<Response<InfoResponse>, Status> {
-        let inner = request.into_inner();
-        let device = self.get_device_by_name(&inner.device).await?;
+        let mut inner = request.into_inner();
+        let device = self.get_device_by_name(&mut inner.device).await?;
         let mut device = device.write().await;
 
         device.try_refresh_session().await?;
+        if inner.fresh {
+            return self.get_state_mut().await.get_fresh_info(&device).await.map(Response::new);
+        }
         device.get_info().await
     }
 
+    /// Get the info of multiple devices at once
+    ///
+    /// The infos are fetched concurrently. A device which can't be found or whose info can't be fetched
+    /// doesn't fail the request. Instead, its result contains the error
+    async fn info_batch(&self, request: Request<InfoBatchRequest>) -> Result<Response<InfoBatchResponse>, Status> {
+        let inner = request.into_inner();
+        let fresh = inner.fresh;
+        let results = join_all(inner.devices.into_iter().map(|device| async move {
+            let result = self.cached_info(device.clone(), fresh).await;
+            device_info_result(device, result)
+        })).await;
+
+        Ok(Response::new(InfoBatchResponse { results }))
+    }
+
+    /// Get the session, reachability, state and usage of the device at once
+    ///
+    /// An unreachable device or unsupported usage information doesn't fail the request. Instead,
+    /// the corresponding parts are omitted from the response
+    async fn status(&self, request: Request<DeviceRequest>) -> Result<Response<StatusResponse>, Status> {
+        let mut inner = request.into_inner();
+        let device = self.get_device_by_name(&mut inner.device).await?;
+        let mut device = device.write().await;
+
+        if let Err(status) = device.try_refresh_session().await {
+            return Ok(Response::new(StatusResponse {
+                device: Some(device.rpc()),
+                reachable: false,
+                error: Some(status.message().to_string()),
+                ..StatusResponse::default()
+            }));
+        }
+
+        let (info, usage) = tokio::join!(device.get_info(), device.get_usage());
+        Ok(Response::new(StatusResponse {
+            device: Some(device.rpc()),
+            reachable: info.is_ok(),
+            error: info.as_ref().err().map(|status| status.message().to_string()),
+            info: info.ok().map(Response::into_inner),
+            usage: usage.ok().map(Response::into_inner)
+        }))
+    }
+
     /// Get all raw json information about the device
     async fn info_json(&self, request: Request<DeviceRequest>) -> Result<Response<InfoJsonResponse>, Status> {
-        let inner = request.into_inner();
-        let device = self.get_device_by_name(&inner.device).await?;
+        let mut inner = request.into_inner();
+        let device = self.get_device_by_name(&mut inner.device).await?;
         let mut device = device.write().await;
 
         device.try_refresh_session().await?;
-        device.get_info_json().await
+        if inner.fresh {
+            self.get_state_mut().await.get_fresh_info(&device).await?;
+        }
+        let response = device.get_info_json().await?.into_inner();
+        self.info_json_filter.apply(response).map(Response::new).map_err(Status::internal)
     }
 
     /// Get power and time usage of the device
     async fn usage(&self, request: Request<DeviceRequest>) -> Result<Response<UsageResponse>, Status> {
-        let inner = request.into_inner();
-        let device = self.get_device_by_name(&inner.device).await?;
+        let mut inner = request.into_inner();
+        let device = self.get_device_by_name(&mut inner.device).await?;
         let mut device = device.write().await;
 
         device.try_refresh_session().await?;
         device.get_usage().await
     }
 
+    /// Get the energy consumed by the device since the previous energy delta request for it
+    async fn energy_delta(&self, request: Request<DeviceRequest>) -> Result<Response<EnergyDeltaResponse>, Status> {
+        let mut inner = request.into_inner();
+        let device = self.get_device_by_name(&mut inner.device).await?;
+        let mut device = device.write().await;
+
+        device.try_refresh_session().await?;
+        let usage = device.get_usage().await?.into_inner();
+        let reading = usage.power_usage.and_then(|usage| usage.today)
+            .ok_or_else(|| Status::unimplemented("The device doesn't report the energy it consumed today"))?;
+        Ok(Response::new(self.energy.delta(&device.name, reading)))
+    }
+
+    /// Get the current power draw of the device
+    async fn current_power(&self, request: Request<DeviceRequest>) -> Result<Response<CurrentPowerResponse>, Status> {
+        let mut inner = request.into_inner();
+        let device = self.get_device_by_name(&mut inner.device).await?;
+        let mut device = device.write().await;
+
+        device.try_refresh_session().await?;
+        let mut response = device.get_current_power().await?;
+        if let Some(smoothing) = &self.smoothing {
+            smoothing.apply(response.get_mut());
+        }
+        Ok(response)
+    }
+
+    /// Get the latest reading of a sensor which is paired with the hub
+    async fn sensor(&self, request: Request<SensorRequest>) -> Result<Response<SensorResponse>, Status> {
+        let mut inner = request.into_inner();
+        let device = self.get_device_by_name(&mut inner.device).await?;
+        let mut device = device.write().await;
+
+        device.try_refresh_session().await?;
+        device.get_sensor(&inner.child).await
+    }
+
+    /// Change the target temperature and frost protection of a radiator valve
+    async fn set_target_temperature(&self, request: Request<TargetTemperatureRequest>) -> Result<Response<InfoResponse>, Status> {
+        let client = request.remote_addr();
+        let mut inner = request.into_inner();
+        let device = self.get_device_by_name(&mut inner.device).await?;
+        let mut device = device.write().await;
+
+        let temperature = match inner.temperature.map(u8::try_from) {
+            Some(Err(_)) => return Err(Status::invalid_argument("Target temperature is out of range")),
+            Some(Ok(temperature)) => Some(temperature),
+            None => None
+        };
+
+        device.try_refresh_session().await?;
+        device.set_target_temperature(temperature, inner.frost_protection).await?;
+        self.audit.write().await.record(&inner.device, "set_target_temperature", &inner, client);
+
+        let info = self.get_state_mut().await.get_fresh_info(&device).await?;
+        Ok(Response::new(info))
+    }
+
+    /// Get the installed firmware version of the device
+    async fn firmware_info(&self, request: Request<DeviceRequest>) -> Result<Response<FirmwareResponse>, Status> {
+        let mut inner = request.into_inner();
+        let device = self.get_device_by_name(&mut inner.device).await?;
+        let mut device = device.write().await;
+
+        device.try_refresh_session().await?;
+        device.get_firmware_info().await
+    }
+
     /// Power the device on
+    ///
+    /// The configured brightness and color temperature of the device are set together with powering it on.
+    /// Without a configured brightness the brightness from before the last fade is restored. Both are capped
+    /// by the night limit of the device
     async fn on(&self, request: Request<DeviceRequest>) -> Result<Response<PowerResponse>, Status> {
-        let inner = request.into_inner();
-        let device = self.get_device_by_name(&inner.device).await?;
+        let client = request.remote_addr();
+        let mut inner = request.into_inner();
+        let device = self.get_device_by_name(&mut inner.device).await?;
         let mut device = device.write().await;
 
         device.try_refresh_session().await?;
-        let response = device.on().await?;
-
-        let mut info = self.get_state_mut().await.get_info(&device).await?;
-        info.device_on = Some(true);
-        info.on_time = Some(0);
-        self.get_state_mut().await.update_info_optimistically(inner.device, info);
+        let response = self.switch_on(&mut device).await?;
+        self.audit.write().await.record(&inner.device, "on", &inner, client);
 
         Ok(response)
     }
 
     /// Power the device off
-    async fn off(&self, request: Request<DeviceRequest>) -> Result<Response<PowerResponse>, Status> {
-        let inner = request.into_inner();
-        let device = self.get_device_by_name(&inner.device).await?;
+    ///
+    /// Devices with a configured fade lower their brightness before they're turned off unless `instant` is set.
+    /// The brightness from before the fade is restored the next time the device is turned on
+    async fn off(&self, request: Request<OffRequest>) -> Result<Response<PowerResponse>, Status> {
+        let client = request.remote_addr();
+        let mut inner = request.into_inner();
+        let device = self.get_device_by_name(&mut inner.device).await?;
         let mut device = device.write().await;
 
         device.try_refresh_session().await?;
-        let response = device.off().await?;
+        let info = self.get_state_mut().await.get_info(&device).await?;
+        let response = self.switch_off(&mut device, info, inner.instant).await?;
+        self.audit.write().await.record(&inner.device, "off", &inner, client);
 
-        let mut info = self.get_state_mut().await.get_info(&device).await?;
-        info.device_on = Some(false);
-        info.on_time = Some(0);
-        self.get_state_mut().await.update_info_optimistically(inner.device, info);
+        Ok(response)
+    }
+
+    /// Turn the device off when it's on and on otherwise
+    ///
+    /// The power state is read from the cached state. Generic devices don't report their state reliably
+    /// which is why it's always read from them. The device is turned on and off like with `on` and `off`
+    async fn toggle(&self, request: Request<DeviceRequest>) -> Result<Response<PowerResponse>, Status> {
+        let client = request.remote_addr();
+        let mut inner = request.into_inner();
+        let device = self.get_device_by_name(&mut inner.device).await?;
+        // the device stays locked between reading and switching its power state so no other request can interfere
+        let mut device = device.write().await;
+
+        device.try_refresh_session().await?;
+        let info = match device.device_type {
+            SupportedDevice::Generic => self.get_state_mut().await.get_fresh_info(&device).await?,
+            _ => self.get_state_mut().await.get_info(&device).await?
+        };
+        let response = if info.device_on.unwrap_or_default() {
+            self.switch_off(&mut device, info, false).await?
+        } else {
+            self.switch_on(&mut device).await?
+        };
+        self.audit.write().await.record(&inner.device, "toggle", &inner, client);
 
         Ok(response)
     }
 
+    /// Turn multiple devices on or off concurrently
+    ///
+    /// Every device is turned on or off like with `on` and `off`. A device which can't be found or switched
+    /// doesn't fail the request. Instead, its result contains the error
+    async fn power_many(&self, request: Request<PowerManyRequest>) -> Result<Response<BulkResponse>, Status> {
+        let inner = request.get_ref();
+        let results = join_all(inner.devices.iter().map(|device| async {
+            let result = if inner.power {
+                self.on(forwarded(&request, DeviceRequest { device: device.clone(), fresh: false })).await
+            } else {
+                self.off(forwarded(&request, OffRequest { device: device.clone(), instant: inner.instant })).await
+            };
+            device_result(device.clone(), result)
+        })).await;
+
+        Ok(Response::new(BulkResponse { results }))
+    }
+
+    /// Turn the device off after a delay
+    ///
+    /// The power off is scheduled as a long-running operation which can be cancelled. Turning
+    /// the device on or changing any of its properties cancels the pending power off
+    async fn sleep(&self, request: Request<SleepRequest>) -> Result<Response<Empty>, Status> {
+        let client = request.remote_addr();
+        let mut inner = request.into_inner();
+        let device = self.get_device_by_name(&mut inner.device).await?;
+        let state = self.state.clone();
+        let delay = Duration::from_millis(inner.delay_ms);
+
+        self.audit.write().await.record(&inner.device, "sleep", &inner, client);
+        info!("Turning device '{}' off in {}s", inner.device, delay.as_secs());
+
+        self.operations.start(inner.device, "sleep", move |progress| async move {
+            // the progress is reported in steps of ten percent
+            for step in 1..10 {
+                tokio::time::sleep(delay / 10).await;
+                progress.report(step * 10);
+            }
+            tokio::time::sleep(delay / 10).await;
+
+            let mut device = device.write().await;
+            device.try_refresh_session().await?;
+            device.off().await?;
+            info!("Turned device '{}' off after sleep timer", device.name);
+
+            let mut info = state.write().await.get_info(&device).await?;
+            info.device_on = Some(false);
+            info.on_time = Some(0);
+            state.write().await.update_info_optimistically(device.name.clone(), info);
+            Ok(())
+        }).await;
+
+        Ok(Response::new(Empty {}))
+    }
+
+    /// Apply the updates to multiple devices concurrently
+    ///
+    /// Every update is applied like with `set`. A device which can't be found or updated doesn't fail
+    /// the request. Instead, its result contains the error
+    async fn set_many(&self, request: Request<SetManyRequest>) -> Result<Response<BulkResponse>, Status> {
+        let results = join_all(request.get_ref().requests.iter().map(|update| async {
+            let result = self.set(forwarded(&request, update.clone())).await;
+            device_result(update.device.clone(), result)
+        })).await;
+
+        Ok(Response::new(BulkResponse { results }))
+    }
+
     /// Update one or more properties of a device in a single request
     async fn set(&self, request: Request<SetRequest>) -> Result<Response<InfoResponse>, Status> {
-        let inner = request.into_inner();
-        let device = self.get_device_by_name(&inner.device).await?;
+        let client = request.remote_addr();
+        let mut inner = request.into_inner();
+        let device = self.get_device_by_name(&mut inner.device).await?;
+        let parameters = inner.clone();
         let mut device = device.write().await;
         device.try_refresh_session().await?;
 
-        let mut info = self.get_state_mut().await.get_info_silent(&device).await?;
+        // relative changes are applied to the cached state. Should the cache be cold or expired
+        // the state is fetched from the device before any relative change is resolved
+        // the state guard has to be released before the retry locks the state again
+        let first_attempt = self.get_state_mut().await.get_info_silent(&device).await;
+        let info = match first_attempt {
+            Ok(info) => Ok(info),
+            Err(status) => {
+                debug!("Unable to fetch state of device '{}': {}. Retrying once...", device.name, status.message());
+                self.get_state_mut().await.get_info_silent(&device).await
+            }
+        };
+        // without the previous state the state after the update is unknown and can't be cached optimistically
+        let (mut info, state_known) = match info {
+            Ok(info) => (info, true),
+            // absolute changes don't depend on the current state of the device
+            Err(status) if !requires_current_state(&inner) => {
+                warn!("Unable to fetch state of device '{}': {}. Applying absolute changes anyway", device.name, status.message());
+                (InfoResponse { name: device.name.clone(), ..InfoResponse::default() }, false)
+            }
+            Err(status) => return Err(status)
+        };
+        if inner.if_on && !info.device_on.unwrap_or_default() {
+            debug!("Skipping update of device '{}' since it's turned off", device.name);
+            return Ok(Response::new(info));
+        }
+        let previous = info.clone();
+
+        let mut explanation = SetExplanation::default();
 
         let mut temperature = inner.temperature
             .map(|change| {
-                let temperature = if change.absolute { change.value as u16 }
-                else {
-                    let updated = info.temperature() as i32 + change.value;
-                    if updated.is_negative() { 2500u16 }
-                    else if updated >= u16::MAX.into() { 6500u16 }
-                    else { updated as u16 }
-                };
+                let base = info.temperature();
+                let temperature = if change.absolute { change.value as i64 } else { base as i64 + change.value as i64 };
+                // the change is clamped once in a wide type so out of range changes end up exactly at the bounds
+                let temperature = temperature.clamp(2500, 6500) as u16;
+                explanation.fields.push(explain_change("temperature", &change, base, temperature as u32));
                 info.temperature = Some(temperature as u32);
                 temperature
-            })
-            .map(|value| min(max(value, 2500), 6500));
+            });
 
-        let brightness = inner.brightness
+        let mut brightness = inner.brightness
             .map(|change| {
+                let base = info.brightness();
                 let brightness = if change.absolute { change.value as u8 }
                 else {
-                    let updated = info.brightness() as i32 + change.value;
+                    let updated = base as i32 + change.value;
                     if updated.is_negative() { 1u8 }
                     else if updated >= u8::MAX.into() { 100u8 }
                     else { updated as u8 }
                 };
+                let brightness = min(max(brightness, 1), 100);
+                explanation.fields.push(explain_change("brightness", &change, base, brightness as u32));
                 info.brightness = Some(brightness as u32);
                 brightness
-            })
-            .map(|value| min(max(value, 1), 100));
+            });
 
         let mut hue_saturation = inner.hue_saturation
             .map(|hs| {
                 let saturation = hs.saturation
                     .map(|change| {
+                        let base = info.saturation();
                         let saturation = if change.absolute { change.value as u8 }
                         else {
-                            let updated = info.saturation() as i32 + change.value;
+                            let updated = base as i32 + change.value;
                             if updated.is_negative() { 1u8 }
                             else if updated >= u8::MAX.into() { 100u8 }
                             else { updated as u8 }
                         };
+                        // the devices don't accept a saturation of 0. The lowest saturation is almost white already
+                        let saturation = min(max(saturation, 1), 100);
+                        explanation.fields.push(explain_change("saturation", &change, base, saturation as u32));
                         info.saturation = Some(saturation as u32);
                         saturation
-                    })
-                    .map(|value| min(max(value, 1), 100));
+                    });
 
                 let hue = hs.hue
                     .map(|change| {
+                        let base = info.saturation();
                         let hue = if change.absolute { change.value as u16 }
                         else {
-                            let updated = info.saturation() as i32 + change.value;
+                            let updated = base as i32 + change.value;
                             if updated.is_negative() { (360 + (updated % 360)) as u16 }
                             else { (updated % 360) as u16 }
                         };
+                        // the devices only accept hues from 1 to 360 where 360 is the same red as 0
+                        let hue = if hue == 0 { 360 } else { hue };
+                        explanation.fields.push(explain_change("hue", &change, base, hue as u32));
                         info.hue = Some(hue as u32);
                         hue
                     });
@@ -238,13 +814,18 @@ impl Tapo for TapoService {
             })
             .unwrap_or_default();
 
-        let color = inner.color
-            .map(|c| rpc::Color::try_from(c).map(|c| c.tapo_color()).ok())
-            .flatten();
+        let rpc_color = inner.color.and_then(|c| rpc::Color::try_from(c).ok());
+        let color = rpc_color.map(|c| c.tapo_color());
 
         // the provided color always takes predecence over hue, saturation and
         // temperature arguments
         if let Some(color) = &color {
+            explanation.color = rpc_color.map(|c| c.as_str_name().to_string());
+            explanation.overridden = explanation.fields.iter()
+                .map(|field| field.field.clone())
+                .filter(|field| field != "brightness")
+                .collect();
+
             let (h, s, t) = color.get_color_config();
             if h > 0 {
                 temperature = None;
@@ -263,6 +844,30 @@ impl Tapo for TapoService {
 
         let power = inner.power;
 
+        // the night limit caps the brightness. Requests which turn the device on without a brightness fall
+        // back to the limit should the current brightness be higher
+        let night_limit = device.night_limit().filter(|_| device.device_type.capabilities().brightness);
+        if let Some(limit) = night_limit {
+            let turns_on = color.is_some() || hue_saturation.is_some() || temperature.is_some() || power == Some(true);
+            match brightness {
+                Some(requested) if requested > limit => {
+                    debug!("Limiting brightness of device '{}' to {limit} during the night", device.name);
+                    brightness = Some(limit);
+                    if let Some(field) = explanation.fields.iter_mut().rev().find(|field| field.field == "brightness") {
+                        field.result = limit as i32;
+                    }
+                },
+                None if turns_on && info.brightness.is_none_or(|current| current > limit as u32) => {
+                    debug!("Defaulting brightness of device '{}' to {limit} during the night", device.name);
+                    brightness = Some(limit);
+                },
+                _ => {}
+            }
+            if brightness == Some(limit) {
+                info.brightness = Some(limit as u32);
+            }
+        }
+
         if color.is_some() || hue_saturation.is_some() || temperature.is_some() || brightness.is_some() || power.is_some_and(|v| v)  {
             info.on_time = info.on_time.or(Some(0));
             info.device_on = Some(true);
@@ -271,8 +876,296 @@ impl Tapo for TapoService {
             info.device_on = Some(false);
         }
 
-        let response = device.set(info, power, brightness, temperature, hue_saturation).await?;
-        self.get_state_mut().await.update_info_optimistically(device.name.clone(), response.get_ref().clone());
+        let explanation = inner.explain.then_some(explanation);
+
+        if inner.dry_run {
+            return Ok(Response::new(InfoResponse { explanation, ..info }))
+        }
+
+        // changing the device cancels a pending sleep timer
+        self.operations.cancel(&inner.device).await;
+        // the calibration only changes the values which are sent to the device. The state keeps the requested values
+        let (calibrated_brightness, calibrated_hue_saturation) = device.calibration.apply(brightness, hue_saturation);
+        // a brightness scale above 1 mustn't lift the brightness over the night limit again
+        let calibrated_brightness = calibrated_brightness.map(|brightness| night_limit.map_or(brightness, |limit| brightness.min(limit)));
+        let mut response = match device.set(info.clone(), power, calibrated_brightness, temperature, calibrated_hue_saturation, inner.atomic).await {
+            Ok(response) => response,
+            Err(UpdateError { applied, status }) => {
+                // only the properties which were applied before the failure are sent to the subscribers
+                if !state_known {
+                    self.get_state_mut().await.invalidate(&device.name);
+                } else if applied.any() {
+                    self.get_state_mut().await.update_info_optimistically(device.name.clone(), applied.merge(previous, info));
+                }
+                return Err(status);
+            }
+        };
+        // an explicit brightness replaces the brightness from before the last fade
+        if brightness.is_some() {
+            device.faded_brightness = None;
+        }
+        self.audit.write().await.record(&parameters.device, "set", &parameters, client);
+        if state_known {
+            self.get_state_mut().await.update_info_optimistically(device.name.clone(), response.get_ref().clone());
+        } else {
+            self.get_state_mut().await.invalidate(&device.name);
+        }
+
+        // the explanation is only added after the state was cached since it's specific to this request
+        response.get_mut().explanation = explanation;
         Ok(response)
     }
-}
\ No newline at end of file
+
+    /// Cancel the long-running operation of a device
+    async fn cancel_operation(&self, request: Request<DeviceRequest>) -> Result<Response<CancelResponse>, Status> {
+        let client = request.remote_addr();
+        let mut inner = request.into_inner();
+        self.get_device_by_name(&mut inner.device).await?;
+
+        let cancelled = self.operations.cancel(&inner.device).await;
+        if cancelled {
+            self.audit.write().await.record(&inner.device, "cancel", &inner, client);
+        }
+        Ok(Response::new(CancelResponse { cancelled }))
+    }
+
+    /// Get the most recent commands which changed the device
+    async fn audit(&self, request: Request<DeviceRequest>) -> Result<Response<AuditResponse>, Status> {
+        let mut inner = request.into_inner();
+        self.get_device_by_name(&mut inner.device).await?;
+
+        let entries = self.audit.read().await.get(&inner.device);
+        Ok(Response::new(AuditResponse { entries }))
+    }
+
+    /// Get the request metrics of a device
+    async fn stats(&self, request: Request<DeviceRequest>) -> Result<Response<StatsResponse>, Status> {
+        let mut inner = request.into_inner();
+        self.get_device_by_name(&mut inner.device).await?;
+
+        let stats = self.metrics.read().await.get(&inner.device);
+        Ok(Response::new(stats))
+    }
+}
+
+/// Check whether the request depends on the current state because of a relative change or the power condition
+fn requires_current_state(request: &SetRequest) -> bool {
+    let relative = |change: &Option<IntegerValueChange>| change.as_ref().is_some_and(|change| !change.absolute);
+    let hue_saturation = request.hue_saturation.as_ref()
+        .is_some_and(|hs| relative(&hs.hue) || relative(&hs.saturation));
+    relative(&request.brightness) || relative(&request.temperature) || hue_saturation || request.if_on
+}
+
+/// Normalize a device name by ignoring its case and all separators (e.g. `Living Room-Lamp` becomes `livingroomlamp`)
+fn normalize_name(name: &str) -> String {
+    name.chars().filter(|c| c.is_alphanumeric()).flat_map(char::to_lowercase).collect()
+}
+
+/// Generate a random token for confirming a prepared reset
+fn generate_token(device: &str) -> String {
+    let mut hasher = RandomState::new().build_hasher();
+    device.hash(&mut hasher);
+    SystemTime::now().hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Create the explanation of how a single integer change was applied to a property
+fn explain_change(field: &str, change: &IntegerValueChange, base: u32, result: u32) -> FieldExplanation {
+    FieldExplanation {
+        field: field.to_string(),
+        absolute: change.absolute,
+        change: change.value,
+        base: (!change.absolute).then_some(base as i32),
+        computed: if change.absolute { change.value } else { base as i32 + change.value },
+        result: result as i32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::DeviceHandler;
+
+    const LAMP: &str = "[devices.lamp]\ntype = \"Virtual\"\naddress = \"\"";
+
+    /// Parse a server config with the given device definitions
+    fn config(devices: &str) -> ServerConfig {
+        toml::from_str(&format!("[auth]\nusername = \"\"\npassword = \"\"\n{devices}")).unwrap()
+    }
+
+    fn device_request(device: &str) -> Request<DeviceRequest> {
+        Request::new(DeviceRequest { device: device.to_string(), fresh: false })
+    }
+
+    async fn fresh_info(service: &TapoService, device: &str) -> InfoResponse {
+        service.info(Request::new(DeviceRequest { device: device.to_string(), fresh: true })).await.unwrap().into_inner()
+    }
+
+    fn absolute(value: i32) -> Option<IntegerValueChange> {
+        Some(IntegerValueChange { absolute: true, value })
+    }
+
+    #[tokio::test]
+    async fn in_process_service_controls_virtual_device() {
+        let service = TapoService::in_process(config(LAMP)).await;
+
+        let response = service.on(device_request("lamp")).await.unwrap().into_inner();
+        assert!(response.device_on);
+
+        assert_eq!(fresh_info(&service, "lamp").await.device_on, Some(true));
+    }
+
+    #[tokio::test]
+    async fn relative_brightness_on_cold_cache_starts_from_device_state() {
+        let service = TapoService::in_process(config(LAMP)).await;
+        // the device is changed directly so the state cache of the service stays cold
+        let device = service.devices["lamp"].clone();
+        assert!(device.read().await.set(InfoResponse::default(), None, Some(40), None, None, false).await.is_ok());
+
+        let request = SetRequest { device: String::from("lamp"), brightness: Some(IntegerValueChange { absolute: false, value: 10 }), ..SetRequest::default() };
+        let state = service.set(Request::new(request)).await.unwrap().into_inner();
+        assert_eq!(state.brightness, Some(50));
+        assert_eq!(fresh_info(&service, "lamp").await.brightness, Some(50));
+    }
+
+    /// Let the next info requests to the virtual device fail
+    async fn fail_infos(service: &TapoService, device: &str, count: u32) {
+        match service.devices[device].read().await.get_handler().unwrap() {
+            DeviceHandler::Virtual(handler) => handler.failing_infos.store(count, std::sync::atomic::Ordering::SeqCst),
+            _ => panic!("Device '{device}' isn't virtual")
+        }
+    }
+
+    #[tokio::test]
+    async fn absolute_set_is_applied_when_info_fetch_fails() {
+        let service = TapoService::in_process(config(LAMP)).await;
+        // the fetch of the previous state is retried once
+        fail_infos(&service, "lamp", 2).await;
+
+        let request = SetRequest { device: String::from("lamp"), brightness: absolute(20), ..SetRequest::default() };
+        let state = service.set(Request::new(request)).await.unwrap().into_inner();
+        assert_eq!(state.brightness, Some(20));
+        assert_eq!(fresh_info(&service, "lamp").await.brightness, Some(20));
+    }
+
+    #[tokio::test]
+    async fn unknown_previous_state_isnt_cached_after_absolute_set() {
+        let service = TapoService::in_process(config(LAMP)).await;
+        fail_infos(&service, "lamp", 2).await;
+        let request = SetRequest { device: String::from("lamp"), brightness: absolute(20), ..SetRequest::default() };
+        service.set(Request::new(request)).await.unwrap();
+
+        // the next read goes to the device instead of returning the partial state known from the update
+        let info = service.info(device_request("lamp")).await.unwrap().into_inner();
+        assert_eq!(info.brightness, Some(20));
+        assert_eq!(info.temperature, Some(2700));
+
+        let change = Some(IntegerValueChange { absolute: false, value: 100 });
+        let request = SetRequest { device: String::from("lamp"), temperature: change, ..SetRequest::default() };
+        let state = service.set(Request::new(request)).await.unwrap().into_inner();
+        assert_eq!(state.temperature, Some(2800));
+    }
+
+    #[tokio::test]
+    async fn relative_set_fails_when_info_fetch_fails() {
+        let service = TapoService::in_process(config(LAMP)).await;
+        fail_infos(&service, "lamp", 2).await;
+
+        let request = SetRequest { device: String::from("lamp"), brightness: Some(IntegerValueChange { absolute: false, value: -10 }), ..SetRequest::default() };
+        let status = service.set(Request::new(request)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+    }
+
+    #[tokio::test]
+    async fn out_of_range_relative_temperature_is_clamped_to_bounds() {
+        let service = TapoService::in_process(config(LAMP)).await;
+
+        for (value, expected) in [(10000, 6500), (-10000, 2500)] {
+            let change = Some(IntegerValueChange { absolute: false, value });
+            let request = SetRequest { device: String::from("lamp"), temperature: change, ..SetRequest::default() };
+            let state = service.set(Request::new(request)).await.unwrap().into_inner();
+            assert_eq!(state.temperature, Some(expected));
+            assert_eq!(fresh_info(&service, "lamp").await.temperature, Some(expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn toggle_switches_power_and_is_audited_once() {
+        let service = TapoService::in_process(config(LAMP)).await;
+
+        let response = service.toggle(device_request("lamp")).await.unwrap().into_inner();
+        assert!(response.device_on);
+        assert_eq!(fresh_info(&service, "lamp").await.device_on, Some(true));
+
+        let response = service.toggle(device_request("lamp")).await.unwrap().into_inner();
+        assert!(!response.device_on);
+        assert_eq!(fresh_info(&service, "lamp").await.device_on, Some(false));
+
+        let audit = service.audit(device_request("lamp")).await.unwrap().into_inner();
+        let commands = audit.entries.iter().map(|entry| entry.command.as_str()).collect::<Vec<_>>();
+        assert_eq!(commands, ["toggle", "toggle"]);
+    }
+
+    #[test]
+    fn normalize_name_drops_separators_and_case() {
+        assert_eq!(normalize_name("Living Room-Lamp"), "livingroomlamp");
+        assert_eq!(normalize_name("living_room_lamp"), "livingroomlamp");
+        assert_eq!(normalize_name("lamp"), "lamp");
+    }
+
+    #[tokio::test]
+    async fn loose_names_resolve_unique_and_reject_ambiguous_matches() {
+        let devices = ["living-room-lamp", "lamp-1", "lamp_1"]
+            .map(|name| format!("[devices.{name}]\ntype = \"Virtual\"\naddress = \"\""))
+            .join("\n");
+        let mut config = config(&devices);
+        config.loose_names = true;
+        let service = TapoService::in_process(config).await;
+
+        let mut name = String::from("Living Room Lamp");
+        assert!(service.get_device_by_name(&mut name).await.is_ok());
+        assert_eq!(name, "living-room-lamp");
+
+        let mut name = String::from("Lamp 1");
+        let status = service.get_device_by_name(&mut name).await.err().unwrap();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert!(status.message().contains("'lamp-1', 'lamp_1'"));
+
+        let mut name = String::from("kitchen");
+        assert_eq!(service.get_device_by_name(&mut name).await.err().unwrap().code(), tonic::Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn exact_names_are_required_without_loose_names() {
+        let service = TapoService::in_process(config(LAMP)).await;
+        let mut name = String::from("Lamp");
+        assert_eq!(service.get_device_by_name(&mut name).await.err().unwrap().code(), tonic::Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn if_on_leaves_device_which_is_off_unchanged() {
+        let service = TapoService::in_process(config(LAMP)).await;
+        service.off(Request::new(OffRequest { device: String::from("lamp"), instant: true })).await.unwrap();
+        let before = fresh_info(&service, "lamp").await;
+
+        let request = SetRequest { device: String::from("lamp"), brightness: absolute(20), if_on: true, ..SetRequest::default() };
+        let state = service.set(Request::new(request)).await.unwrap().into_inner();
+        assert_eq!(state.device_on, Some(false));
+        assert_eq!(state.brightness, before.brightness);
+
+        let after = fresh_info(&service, "lamp").await;
+        assert_eq!(after.device_on, Some(false));
+        assert_eq!(after.brightness, before.brightness);
+    }
+
+    #[tokio::test]
+    async fn if_on_updates_device_which_is_on() {
+        let service = TapoService::in_process(config(LAMP)).await;
+        service.on(device_request("lamp")).await.unwrap();
+
+        let request = SetRequest { device: String::from("lamp"), brightness: absolute(20), if_on: true, ..SetRequest::default() };
+        let state = service.set(Request::new(request)).await.unwrap().into_inner();
+        assert_eq!(state.brightness, Some(20));
+        assert_eq!(fresh_info(&service, "lamp").await.brightness, Some(20));
+    }
+}
@@ -0,0 +1,29 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use crate::tapo::server::rpc::CurrentPowerResponse;
+
+/// Exponential moving average of the current power draw of the devices
+#[derive(Clone)]
+pub struct PowerSmoothing {
+    factor: f64,
+    averages: Arc<Mutex<HashMap<String, f64>>>
+}
+
+impl PowerSmoothing {
+    /// Create a new moving average with the weight of the newest reading (0..1]
+    pub fn new(factor: f64) -> Self {
+        Self { factor, averages: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Add the reading of the response to the moving average of the device and set the smoothed power
+    ///
+    /// The first reading of a device is taken as is
+    pub fn apply(&self, response: &mut CurrentPowerResponse) {
+        let mut averages = self.averages.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let reading = response.current_power as f64;
+        let average = averages.entry(response.device.clone())
+            .and_modify(|average| *average = self.factor * reading + (1f64 - self.factor) * *average)
+            .or_insert(reading);
+        response.smoothed_power = Some(*average);
+    }
+}
@@ -1,30 +1,133 @@
 use std::collections::HashMap;
-use std::time::{Duration, SystemTime};
-use log::{error, info};
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
 use tonic::Status;
 use crate::device::{Device, DeviceHandler};
-use crate::tapo::color::any_to_rgb;
-use crate::tapo::create_event;
+use crate::tapo::device::color_light::dynamic_effect_name;
+use crate::tapo::color::{any_to_hsl, any_to_rgb};
+use crate::tapo::{create_event, TapoDeviceHandlerExt};
 use crate::tapo::server::EventSender;
 use crate::tapo::server::rpc::{EventType, InfoResponse};
 
 const INFO_VALIDITY_MILLIS: u64 = 30 * 1000; // update device info after 30 seconds
+const CLOCK_SKEW_TOLERANCE_MILLIS: u64 = 5 * 1000; // wall clock jumps above 5 seconds invalidate the cached info
 
 #[derive(Clone)]
 pub struct State {
     info: HashMap<String, DeviceInfo>,
     sender: EventSender,
+    state_file: Option<PathBuf>
 }
 
 #[derive(Clone)]
 pub struct DeviceInfo {
     response: InfoResponse,
-    created: SystemTime
+    /// Wall clock time when the info was cached which is used for persisting the info
+    created: SystemTime,
+    /// Monotonic time when the info was cached. Infos without it are stale
+    fetched: Option<Instant>
+}
+
+impl DeviceInfo {
+    fn new(response: InfoResponse) -> Self {
+        Self { response, created: SystemTime::now(), fetched: Some(Instant::now()) }
+    }
+
+    /// Get the cached info with the on-time advanced by the time which elapsed since it was cached
+    ///
+    /// The elapsed time is measured with the monotonic clock so adjustments of the system clock don't affect
+    /// the on-time. Returns `None` should the info have exceeded the cache period or should the wall clock have
+    /// jumped in the meantime (e.g. after a resume from suspend during which the monotonic clock stands still)
+    fn current(&self, device: &str) -> Option<InfoResponse> {
+        self.current_at(device, Instant::now(), SystemTime::now())
+    }
+
+    /// Get the cached info at the given monotonic and wall clock time
+    fn current_at(&self, device: &str, now: Instant, wall_now: SystemTime) -> Option<InfoResponse> {
+        let elapsed = now.saturating_duration_since(self.fetched?);
+        if elapsed >= Duration::from_millis(INFO_VALIDITY_MILLIS) {
+            return None
+        }
+
+        // a wall clock which went backwards is clamped to zero elapsed time
+        let wall_elapsed = wall_now.duration_since(self.created).unwrap_or_default();
+        let skew = wall_elapsed.abs_diff(elapsed);
+        if skew > Duration::from_millis(CLOCK_SKEW_TOLERANCE_MILLIS) {
+            warn!("Detected a clock skew of {}s for the cached info of device '{device}'. Refreshing the info...", skew.as_secs());
+            return None
+        }
+
+        let mut copy = self.response.clone();
+        copy.on_time = copy.on_time.map(|time| time + elapsed.as_secs());
+        Some(copy)
+    }
+}
+
+/// Representation of a cached device info in the state file
+#[derive(Serialize, Deserialize)]
+struct PersistedInfo {
+    response: InfoResponse,
+    /// Unix timestamp in milliseconds when the info was cached
+    created: u64
 }
 
 impl State {
-    pub fn new(sender: EventSender) -> Self {
-        State { info: HashMap::new(), sender }
+    pub fn new(sender: EventSender, state_file: Option<PathBuf>) -> Self {
+        let info = state_file.as_ref().map(Self::load).unwrap_or_default();
+        State { info, sender, state_file }
+    }
+
+    /// Load the persisted device infos from the state file
+    ///
+    /// The entries keep their original creation time. Entries which exceeded the cache
+    /// period in the meantime are therefore treated as stale and get refreshed on the next access
+    fn load(path: &PathBuf) -> HashMap<String, DeviceInfo> {
+        let content = match fs::read(path) {
+            Ok(content) => content,
+            Err(err) => {
+                debug!("Unable to read state file at {path:?}: {err}");
+                return HashMap::new()
+            }
+        };
+
+        match serde_json::from_slice::<HashMap<String, PersistedInfo>>(&content) {
+            Ok(persisted) => {
+                info!("Loaded state of {} devices from {path:?}", persisted.len());
+                persisted.into_iter()
+                    .map(|(name, info)| {
+                        let created = UNIX_EPOCH + Duration::from_millis(info.created);
+                        // the monotonic time is derived from the age of the entry. Entries from the future are stale
+                        let fetched = SystemTime::now().duration_since(created).ok().and_then(|age| Instant::now().checked_sub(age));
+                        (name, DeviceInfo { response: info.response, created, fetched })
+                    })
+                    .collect()
+            },
+            Err(err) => {
+                warn!("Ignoring invalid state file at {path:?}: {err}");
+                HashMap::new()
+            }
+        }
+    }
+
+    /// Write the cached device infos to the state file if one is configured
+    fn persist(&self) {
+        let Some(path) = &self.state_file else { return };
+        let persisted = self.info.iter()
+            .map(|(name, info)| {
+                let created = info.created.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+                (name, PersistedInfo { response: info.response.clone(), created })
+            })
+            .collect::<HashMap<_, _>>();
+
+        match serde_json::to_vec(&persisted) {
+            Ok(bytes) => if let Err(err) = fs::write(path, bytes) {
+                error!("Unable to write state file at {path:?}: {err}")
+            },
+            Err(err) => error!("Unable to serialize state: {err}")
+        }
     }
 
     /// Manually populate the cached state information for a device
@@ -39,17 +142,23 @@ impl State {
         info!("Sending new device state event 2");
         let event = create_event(EventType::DeviceStateChange, &info);
 
-        let device_info = DeviceInfo {
-            created: SystemTime::now(),
-            response: info
-        };
-        self.info.insert(device, device_info);
+        self.info.insert(device, DeviceInfo::new(info));
+        self.persist();
 
-        if let Err(err) = self.sender.send(event) {
+        if let Some(Err(err)) = event.map(|event| self.sender.send(event)) {
             error!("Error whilst sending new device state: {err}")
         }
     }
 
+    /// Mark the cached state of a device as stale so the next request fetches it from the device again
+    ///
+    /// This is used when a device was changed without knowing its previous state which makes an optimistic update impossible
+    pub fn invalidate(&mut self, device: &str) {
+        if let Some(info) = self.info.get_mut(device) {
+            info.fetched = None;
+        }
+    }
+
     /// Refresh the cached state information for a device
     ///
     /// When `send_state` is set to `true` the refreshed info is sent as an update event to
@@ -65,6 +174,8 @@ impl State {
                     on_time: info.on_time,
                     overheated: info.overheated,
                     name: device.name.clone(),
+                    rssi: Some(info.rssi as i32),
+                    signal_level: Some(info.signal_level as u32),
                     ..InfoResponse::default()
                 }
             }
@@ -74,9 +185,17 @@ impl State {
                     device_on: info.device_on,
                     on_time: info.on_time,
                     name: device.name.clone(),
+                    rssi: Some(info.rssi as i32),
+                    signal_level: Some(info.signal_level as u32),
                     ..InfoResponse::default()
                 }
             }
+            DeviceHandler::Virtual(handler) => handler.get_info(device).await?,
+            DeviceHandler::LightStrip(handler) => handler.get_info(device).await?,
+            DeviceHandler::EnergyPlug(handler) => handler.get_info(device).await?,
+            DeviceHandler::Plug(handler) => handler.get_info(device).await?,
+            DeviceHandler::Hub(handler) => handler.get_info(device).await?,
+            DeviceHandler::Valve(handler) => handler.get_info(device).await?,
             DeviceHandler::ColorLight(handler) => {
                 let info = handler.get_device_info().await.map_err(|err| Status::internal(err.to_string()))?;
                 let brightness = Some(info.brightness as u32);
@@ -90,19 +209,24 @@ impl State {
                     temperature,
                     device_on: Some(info.device_on),
                     on_time: info.on_time,
+                    dynamic_effect_name: info.dynamic_light_effect_id.as_deref().map(|id| dynamic_effect_name(&device.device_type, id)),
                     dynamic_effect_id: info.dynamic_light_effect_id,
                     overheated: info.overheated,
                     color: any_to_rgb(temperature, hue, saturation, brightness),
-                    name: device.name.clone()
+                    hsl: any_to_hsl(temperature, hue, saturation, brightness),
+                    name: device.name.clone(),
+                    explanation: None,
+                    rssi: Some(info.rssi as i32),
+                    signal_level: Some(info.signal_level as u32),
+                    ..InfoResponse::default()
                 }
             }
         };
 
         if send_state {
             info!("Sending new device state event");
-            match self.sender.send(create_event(EventType::DeviceStateChange, &info)) {
-                Ok(_) => {},
-                Err(err) => error!("Error whilst sending new device state: {err}")
+            if let Some(Err(err)) = create_event(EventType::DeviceStateChange, &info).map(|event| self.sender.send(event)) {
+                error!("Error whilst sending new device state: {err}")
             }
         }
 
@@ -114,21 +238,33 @@ impl State {
     /// The state may be cached and have a maximum age of [`INFO_VALIDITY_SECS`]. Should the state
     /// exceed the cache period it gets renewed automatically
     pub async fn get_info(&mut self, device: &Device) -> Result<InfoResponse, Status> {
-        let info = self.info.get(&device.name);
-
-        let now = SystemTime::now();
-        if let Some(info) = info {
-            if info.created + Duration::from_millis(INFO_VALIDITY_MILLIS) < now {
-                // info is still valid
-                let mut copy = info.response.clone();
-                copy.on_time = copy.on_time.map(|time| time + now.duration_since(info.created).unwrap_or_default().as_secs());
-                return Ok(copy)
-            };
-        };
+        if let Some(info) = self.info.get(&device.name).and_then(|info| info.current(&device.name)) {
+            return Ok(info)
+        }
 
         // get refreshed device info from device handler
         let response = self.refresh_info(device, true).await?;
-        self.info.insert(device.name.clone(), DeviceInfo { response: response.clone(), created: now });
+        self.info.insert(device.name.clone(), DeviceInfo::new(response.clone()));
+        self.persist();
+        Ok(response)
+    }
+
+    /// Get the last known state of a device regardless of its age
+    ///
+    /// This is used by mirrors which can't refresh the state themselves and only receive the
+    /// state changes of the upstream server
+    pub fn get_last_info(&self, device: &str) -> Option<InfoResponse> {
+        self.info.get(device).map(|info| info.response.clone())
+    }
+
+    /// Get the current state for a device bypassing the cache
+    ///
+    /// The cached state is replaced with the fetched state and sent as an update event to
+    /// all subscribed clients
+    pub async fn get_fresh_info(&mut self, device: &Device) -> Result<InfoResponse, Status> {
+        let response = self.refresh_info(device, true).await?;
+        self.info.insert(device.name.clone(), DeviceInfo::new(response.clone()));
+        self.persist();
         Ok(response)
     }
 
@@ -139,21 +275,54 @@ impl State {
     /// the state is updated optimistically later on to ensure the clients have the
     /// correct device states
     pub async fn get_info_silent(&mut self, device: &Device) -> Result<InfoResponse, Status> {
-        let info = self.info.get(&device.name);
-
-        let now = SystemTime::now();
-        if let Some(info) = info {
-            if info.created + Duration::from_millis(INFO_VALIDITY_MILLIS) < now {
-                // info is still valid
-                let mut copy = info.response.clone();
-                copy.on_time = copy.on_time.map(|time| time + now.duration_since(info.created).unwrap_or_default().as_secs());
-                return Ok(copy)
-            };
-        };
+        if let Some(info) = self.info.get(&device.name).and_then(|info| info.current(&device.name)) {
+            return Ok(info)
+        }
 
         // get refreshed device info from device handler without sending an update event
         let response = self.refresh_info(device, false).await?;
-        self.info.insert(device.name.clone(), DeviceInfo { response: response.clone(), created: now });
+        self.info.insert(device.name.clone(), DeviceInfo::new(response.clone()));
+        self.persist();
         Ok(response)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cached(on_time: u64) -> (DeviceInfo, Instant, SystemTime) {
+        let info = DeviceInfo::new(InfoResponse { on_time: Some(on_time), ..InfoResponse::default() });
+        let (fetched, created) = (info.fetched.unwrap(), info.created);
+        (info, fetched, created)
+    }
+
+    #[test]
+    fn elapsed_time_advances_on_time() {
+        let (info, fetched, created) = cached(100);
+        let elapsed = Duration::from_secs(10);
+        let current = info.current_at("lamp", fetched + elapsed, created + elapsed).unwrap();
+        assert_eq!(current.on_time, Some(110));
+    }
+
+    #[test]
+    fn backwards_wall_clock_is_clamped_to_zero() {
+        let (info, fetched, created) = cached(100);
+        let current = info.current_at("lamp", fetched + Duration::from_secs(2), created - Duration::from_secs(3600)).unwrap();
+        // the on-time only depends on the monotonic clock
+        assert_eq!(current.on_time, Some(102));
+    }
+
+    #[test]
+    fn clock_skew_above_tolerance_invalidates_info() {
+        let (info, fetched, created) = cached(100);
+        let wall_elapsed = Duration::from_secs(1) + Duration::from_millis(CLOCK_SKEW_TOLERANCE_MILLIS + 1);
+        assert!(info.current_at("lamp", fetched + Duration::from_secs(1), created + wall_elapsed).is_none());
+    }
+
+    #[test]
+    fn expired_info_is_invalid() {
+        let (info, fetched, created) = cached(100);
+        let elapsed = Duration::from_millis(INFO_VALIDITY_MILLIS);
+        assert!(info.current_at("lamp", fetched + elapsed, created + elapsed).is_none());
+    }
+}
@@ -3,9 +3,12 @@ use validator::ValidationError;
 use super::server::rpc::{HueSaturation, IntegerValueChange};
 
 /// Validate the hue range of an integer change
+///
+/// The range starts at zero like in the common hsl notation. The server maps the values to the
+/// range of the devices (hue 1 to 360 and saturation 1 to 100) before sending them
 pub fn validate_hue(change: &IntegerValueChange) -> Result<(), ValidationError> {
-    if change.absolute && !(1..=360).contains(&change.value) {
-        Err(ValidationError::new("Hue value has to be in range 1 to 360"))
+    if change.absolute && !(0..=360).contains(&change.value) {
+        Err(ValidationError::new("Hue value has to be in range 0 to 360"))
     } else {
         Ok(())
     }
@@ -22,8 +25,8 @@ pub fn validate_temperature(change: &IntegerValueChange) -> Result<(), Validatio
 
 /// Validate the saturation range of an integer change
 pub fn validate_saturation(change: &IntegerValueChange) -> Result<(), ValidationError> {
-    if change.absolute && !(1..=100).contains(&change.value) {
-        Err(ValidationError::new("Saturation value has to be in range 1 to 100"))
+    if change.absolute && !(0..=100).contains(&change.value) {
+        Err(ValidationError::new("Saturation value has to be in range 0 to 100"))
     } else {
         Ok(())
     }
@@ -0,0 +1,80 @@
+use std::time::Duration;
+use log::{debug, info, warn};
+use serde_json::{json, Value};
+use tokio::sync::broadcast::error::RecvError;
+use crate::tapo::server::EventSender;
+use crate::tapo::server::rpc::{EventResponse, EventType};
+
+const WEBHOOK_ATTEMPTS: u32 = 3; // give up on an event after three failed deliveries
+const WEBHOOK_RETRY_MILLIS: u64 = 1000; // the delay is doubled after every failed delivery
+
+/// Webhook to which the events of the configured types are posted
+pub struct Webhook {
+    pub url: String,
+    /// Event types which are posted. All events are posted when empty
+    pub events: Vec<EventType>
+}
+
+/// Post all matching events of the broadcast channel to the webhooks
+///
+/// The body of every request is a json object with the name of the event type in `type` and the event
+/// in `body`. Every delivery runs on its own task so a slow webhook doesn't delay the other events
+pub fn spawn_webhooks(webhooks: Vec<Webhook>, sender: &EventSender, timeout: Duration) {
+    let client = match reqwest::Client::builder().timeout(timeout).build() {
+        Ok(client) => client,
+        Err(err) => {
+            warn!("Unable to create http client for webhooks: {err}");
+            return
+        }
+    };
+    webhooks.iter().for_each(|webhook| info!("Posting events to webhook {}", webhook.url));
+
+    let mut receiver = sender.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Skipped {skipped} events which couldn't be posted to the webhooks fast enough");
+                    continue
+                },
+                Err(RecvError::Closed) => return
+            };
+            let Some(payload) = payload(&event) else { continue };
+
+            for webhook in &webhooks {
+                if !webhook.events.is_empty() && !webhook.events.iter().any(|r#type| i32::from(*r#type) == event.r#type) {
+                    continue
+                }
+                tokio::spawn(deliver(client.clone(), webhook.url.clone(), payload.clone()));
+            }
+        }
+    });
+}
+
+/// Create the json payload of an event
+fn payload(event: &EventResponse) -> Option<Value> {
+    let event_type = EventType::try_from(event.r#type).ok()?;
+    let body: Value = serde_json::from_slice(event.body.as_slice()).ok()?;
+    Some(json!({ "type": event_type.as_str_name(), "body": body }))
+}
+
+/// Post the payload to the url and retry with an increasing delay should it fail
+async fn deliver(client: reqwest::Client, url: String, payload: Value) {
+    let mut delay = Duration::from_millis(WEBHOOK_RETRY_MILLIS);
+    for attempt in 1..=WEBHOOK_ATTEMPTS {
+        let result = client.post(&url).json(&payload).send().await.and_then(|response| response.error_for_status());
+        match result {
+            Ok(_) => {
+                debug!("Posted event to webhook {url}");
+                return
+            },
+            Err(err) if attempt < WEBHOOK_ATTEMPTS => {
+                debug!("Unable to post event to webhook {url}: {err}. Retrying in {}ms...", delay.as_millis());
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            },
+            Err(err) => warn!("Unable to post event to webhook {url} after {WEBHOOK_ATTEMPTS} attempts: {err}")
+        }
+    }
+}